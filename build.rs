@@ -1,3 +1,18 @@
+use std::process::Command;
+
 fn main() {
     println!("cargo:rerun-if-changed=memory.x");
+
+    // Embed the git revision of the firmware, marking uncommitted changes.
+    let revision = Command::new("git")
+        .args(["describe", "--always", "--dirty", "--abbrev=12"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|revision| revision.trim().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=GIT_REVISION={}", revision);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
 }
@@ -40,3 +40,6 @@ python3 hitl/loopback.py $PREFIX
 
 # Test the livestream capabilities
 python3 hitl/streaming.py $PREFIX
+
+# Test network time synchronization against an SNTP server served by the HITL runner.
+python3 hitl/sntp.py $PREFIX
@@ -0,0 +1,61 @@
+#!/usr/bin/python3
+"""HITL testing of Stabilizer network time synchronization
+
+This serves SNTP requests using a local stand-in server. Stabilizer must be
+built with `NTP_SERVER` pointing to the host running this test. Serving on the
+NTP port usually requires elevated privileges.
+"""
+
+import asyncio
+import logging
+import argparse
+import time
+
+from stabilizer.sntp import SntpServer
+from stabilizer.telemetry import Telemetry
+
+logger = logging.getLogger(__name__)
+
+
+async def _main():
+    parser = argparse.ArgumentParser(description="Stabilizer SNTP HITL test")
+    parser.add_argument("prefix", type=str,
+                        help="The MQTT topic prefix of the target")
+    parser.add_argument("--broker", "-b", default="mqtt", type=str,
+                        help="The MQTT broker address")
+    parser.add_argument("--host", default="0.0.0.0",
+                        help="Local address to serve SNTP on")
+    parser.add_argument("--offset", type=float, default=1000.,
+                        help="Offset of the served time in seconds")
+    parser.add_argument("--timeout", type=float, default=60.,
+                        help="Maximum time to wait for synchronization")
+    parser.add_argument("--max-error", type=float, default=50e-3,
+                        help="Maximum time error in seconds for success")
+    args = parser.parse_args()
+
+    logging.basicConfig(level=logging.INFO)
+
+    _transport, server = await SntpServer.open((args.host, 123), args.offset)
+    telemetry = await Telemetry.create(args.prefix, args.broker)
+
+    logger.info("Waiting for synchronization")
+    deadline = time.time() + args.timeout
+    while True:
+        if time.time() > deadline:
+            raise RuntimeError("Synchronization timed out", server.requests)
+        status = (await telemetry.queue.get())["time"]
+        if not status["synchronized"]:
+            continue
+        # Telemetry is published with low latency on the local network.
+        error = status["utc"] / 1e3 - (time.time() + args.offset)
+        logger.info("Synchronized, error %g s, delay %d ms",
+                    error, status["delay"])
+        if abs(error) > args.max_error:
+            raise RuntimeError("Large time error", error)
+        break
+
+    print("PASS")
+
+
+if __name__ == "__main__":
+    asyncio.run(_main())
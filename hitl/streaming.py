@@ -34,7 +34,8 @@ async def _main():
 
     logger.info("Starting stream")
     await conf.command(
-        "stream_target", {"ip": local_ip, "port": args.port}, retain=False)
+        "stream_target/0", {"ip": local_ip, "port": args.port, "enabled": True},
+        retain=False)
 
     try:
         logger.info("Testing stream reception")
@@ -45,7 +46,8 @@ async def _main():
     finally:
         logger.info("Stopping stream")
         await conf.command(
-            "stream_target", {"ip": [0, 0, 0, 0], "port": 0}, retain=False)
+            "stream_target/0", {"ip": [0, 0, 0, 0], "port": 0, "enabled": False},
+            retain=False)
 
     logger.info("Draining queue")
     await asyncio.sleep(.1)
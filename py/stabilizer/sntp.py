@@ -0,0 +1,90 @@
+"""
+Minimal SNTP server for testing Stabilizer network time synchronization
+"""
+
+import argparse
+import asyncio
+import logging
+import struct
+import time
+
+logger = logging.getLogger(__name__)
+
+# The number of seconds between the NTP epoch (1900) and the Unix epoch (1970).
+NTP_UNIX_OFFSET = 2_208_988_800
+
+
+def to_ntp(timestamp):
+    """Convert a Unix timestamp in seconds to a 64-bit NTP timestamp"""
+    seconds, fraction = divmod(timestamp + NTP_UNIX_OFFSET, 1)
+    return (int(seconds) << 32) | int(fraction * (1 << 32))
+
+
+class SntpServer(asyncio.DatagramProtocol):
+    """SNTP server protocol answering client requests with the local host time.
+
+    This is a stand-in for a real NTP server and does not implement any
+    server-side synchronization. The reported time may be shifted by `offset`
+    seconds to test client-side offset discipline.
+    """
+    packet = struct.Struct(">BBbbII4sQQQQ")
+
+    @classmethod
+    async def open(cls, local_addr, offset=0.):
+        """Open a UDP socket and start serving requests"""
+        loop = asyncio.get_running_loop()
+        transport, protocol = await loop.create_datagram_endpoint(
+            lambda: cls(offset), local_addr=local_addr)
+        return transport, protocol
+
+    def __init__(self, offset):
+        self.offset = offset
+        self.transport = None
+        self.requests = 0
+
+    def connection_made(self, transport):
+        self.transport = transport
+
+    def datagram_received(self, data, addr):
+        receive = time.time() + self.offset
+        if len(data) < self.packet.size:
+            logger.warning("Short request from %s, ignoring", addr)
+            return
+        (flags, _stratum, _poll, _precision, _delay, _dispersion, _ref_id,
+         _reference, _originate, _receive, transmit) = self.packet.unpack_from(data)
+        version = (flags >> 3) & 0b111
+        mode = flags & 0b111
+        if mode != 3:
+            logger.warning("Unexpected mode %d from %s, ignoring", mode, addr)
+            return
+        self.requests += 1
+        logger.debug("Request from %s", addr)
+        response = self.packet.pack(
+            (version << 3) | 4,  # No leap second warning, server mode
+            1,  # Stratum: primary reference
+            6,  # Poll interval: 64 s
+            -20,  # Precision: ~1 µs
+            0, 0, b"LOCL",
+            to_ntp(receive), transmit, to_ntp(receive),
+            to_ntp(time.time() + self.offset))
+        self.transport.sendto(response, addr)
+
+
+async def _main():
+    parser = argparse.ArgumentParser(description="Minimal SNTP server")
+    parser.add_argument("--host", default="0.0.0.0",
+                        help="Local address to listen on")
+    parser.add_argument("--port", type=int, default=123,
+                        help="Local port to listen on")
+    parser.add_argument("--offset", type=float, default=0.,
+                        help="Offset in seconds added to the served time")
+    args = parser.parse_args()
+
+    logging.basicConfig(level=logging.DEBUG)
+
+    await SntpServer.open((args.host, args.port), args.offset)
+    await asyncio.Event().wait()
+
+
+if __name__ == "__main__":
+    asyncio.run(_main())
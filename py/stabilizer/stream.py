@@ -11,7 +11,7 @@ from dataclasses import dataclass
 
 import numpy as np
 
-from . import DAC_VOLTS_PER_LSB
+from . import DAC_VOLTS_PER_LSB, SAMPLE_PERIOD
 
 logger = logging.getLogger(__name__)
 
@@ -79,14 +79,80 @@ class AdcDac:
         ]
 
 
+class Lockin:
+    """Lockin demodulated IQ data format"""
+    format_id = 3
+    # The number of demodulated harmonics per channel
+    harmonics = 3
+    # The number of values per batch: I/Q of each demodulator, reference phase and frequency
+    values = 4 * harmonics + 2
+
+    def __init__(self, header, body):
+        self.header = header
+        self.body = body
+
+    def batch_count(self):
+        """Return the number of batches in the frame"""
+        return self.size() // (self.values * 4)
+
+    def size(self):
+        """Return the data size of the frame in bytes"""
+        return len(self.body)
+
+    def to_mu(self):
+        """Return the raw data in machine units"""
+        data = np.frombuffer(self.body, "<i4")
+        return data.reshape(-1, self.values).T
+
+    def to_si(self):
+        """Convert the raw data to SI units
+
+        The in-phase and quadrature components of each demodulator of both channels are
+        normalized to ADC full scale. Reference phase is in turns, reference frequency in Hertz.
+        """
+        data = self.to_mu()
+        iq = (data[:-2:2] + 1j*data[1:-2:2]) / (1 << 31)
+        return {
+            "iq": iq.reshape(2, self.harmonics, -1),
+            "phase": data[-2] / (1 << 32),
+            "frequency": data[-1] / (1 << 32) / SAMPLE_PERIOD,
+        }
+
+    def to_traces(self):
+        """Convert the raw data to labelled Trace instances"""
+        data = self.to_mu()
+        traces = []
+        for channel in range(2):
+            for harmonic in range(self.harmonics):
+                index = 2 * (channel * self.harmonics + harmonic)
+                traces += [
+                    Trace(data[index], scale=1 / (1 << 31),
+                          label=f'I{channel}.{harmonic}'),
+                    Trace(data[index + 1], scale=1 / (1 << 31),
+                          label=f'Q{channel}.{harmonic}'),
+                ]
+        return traces + [
+            Trace(data[-2], scale=1 / (1 << 32), label='Phase'),
+            Trace(data[-1], scale=1 / (1 << 32) / SAMPLE_PERIOD,
+                  label='Frequency'),
+        ]
+
+
 class StabilizerStream(asyncio.DatagramProtocol):
     """Stabilizer streaming receiver protocol"""
-    # The magic header half-word at the start of each packet.
-    magic = 0x057B
-    header_fmt = struct.Struct("<HBBI")
-    header = namedtuple("Header", "magic format_id batch_size sequence")
+    # The magic header half-word at the start of each packet identifies the
+    # header version and maps to the corresponding header format.
+    header_fmts = {
+        0x057B: struct.Struct("<HBBI"),
+        0x057C: struct.Struct("<HBBIQIQ"),
+    }
+    header = namedtuple(
+        "Header",
+        "magic format_id batch_size sequence timestamp system_time utc_time",
+        defaults=(None, None, None))
     parsers = {
         AdcDac.format_id: AdcDac,
+        Lockin.format_id: Lockin,
     }
 
     @classmethod
@@ -115,16 +181,19 @@ class StabilizerStream(asyncio.DatagramProtocol):
         logger.info("Connection lost")
 
     def datagram_received(self, data, _addr):
-        header = self.header._make(self.header_fmt.unpack_from(data))
-        if header.magic != self.magic:
-            logger.warning("Bad frame magic: %#04x, ignoring", header.magic)
+        magic, = struct.unpack_from("<H", data)
+        try:
+            header_fmt = self.header_fmts[magic]
+        except KeyError:
+            logger.warning("Bad frame magic: %#04x, ignoring", magic)
             return
+        header = self.header._make(header_fmt.unpack_from(data))
         try:
             parser = self.parsers[header.format_id]
         except KeyError:
             logger.warning("No parser for format %s, ignoring", header.format_id)
             return
-        frame = parser(header, data[self.header_fmt.size:])
+        frame = parser(header, data[header_fmt.size:])
         if self.queue.full():
             old = self.queue.get_nowait()
             logger.debug("Dropping frame: %#08x", old.header.sequence)
@@ -20,11 +20,50 @@
 //! application.
 //!
 //! ## Telemetry
-//! Refer to [Telemetry] for information about telemetry reported by this application.
+//! Refer to [AppTelemetry] for information about telemetry reported by this application.
 //!
 //! ## Livestreaming
 //! This application streams raw ADC and DAC data over UDP. Refer to
 //! [stabilizer::net::data_stream](../stabilizer/net/data_stream/index.html) for more information.
+//!
+//! ## Lock Detection
+//! Each channel is monitored by a lock detector reporting the lock state in telemetry and
+//! publishing state transitions as events. Refer to
+//! [stabilizer::dsp::lock_detect](../stabilizer/dsp/lock_detect/index.html) for more information.
+//!
+//! ## Filter State
+//! Coefficient updates can optionally be applied bumpless, i.e. the filter state is rewritten such
+//! that the output continues from its current value. The filter state of each channel can
+//! additionally be reset or preset to a given output by the `iir_state/<n>` command. Refer to
+//! [stabilizer::dsp::filter_state](../stabilizer/dsp/filter_state/index.html) for more
+//! information.
+//!
+//! ## Commands
+//! Commands are published to `<prefix>/command/<name>` and are executed once. In addition to the
+//! device commands (see [stabilizer::net::command](../stabilizer/net/command/index.html)), this
+//! application accepts:
+//! * `iir_state/<n>`: Apply an action to the IIR filter state of channel `<n>` := [0, 1]. See
+//!   [StateConfig#command] for the arguments.
+//!
+//! ## Slew-Rate Limits
+//! Changes of the filter offsets and the output can be slew-rate limited per channel to ramp in
+//! setpoint changes without kicking the actuator. Refer to
+//! [stabilizer::dsp::slew](../stabilizer/dsp/slew/index.html) for more information.
+//!
+//! ## Cross-Channel Mixing
+//! The filter inputs and outputs of both channels can be mixed to feed forward between channels or
+//! to decouple coupled plants. Refer to [stabilizer::dsp::mimo](../stabilizer/dsp/mimo/index.html)
+//! for more information.
+//!
+//! ## Automatic Relock
+//! Upon loss of lock, a channel can be relocked automatically by sweeping its output and
+//! re-engaging the filter once a capture condition is met. Refer to
+//! [stabilizer::dsp::relock](../stabilizer/dsp/relock/index.html) for more information.
+//!
+//! ## Capture
+//! This application supports triggered capture of ADC and DAC data into RAM and subsequent upload
+//! over MQTT. Refer to [stabilizer::net::capture](../stabilizer/net/capture/index.html) for more
+//! information.
 #![deny(warnings)]
 #![no_std]
 #![no_main]
@@ -38,19 +77,34 @@ use mutex_trait::prelude::*;
 use idsp::iir;
 
 use stabilizer::{
+    dsp::{
+        filter_state::{self, StateConfig},
+        lock_detect::{LockDetectConfig, LockDetector, LockEvent, LockState},
+        mimo::MimoConfig,
+        relock::{
+            Relock, RelockConfig, RelockEvent, RelockState, RelockStatus,
+        },
+        slew::{Slew, SlewConfig},
+    },
     hardware::{
         self,
         adc::{Adc0Input, Adc1Input, AdcCode},
         afe::Gain,
+        cpu_load::CycleAccumulator,
         dac::{Dac0Output, Dac1Output, DacCode},
         hal,
         signal_generator::{self, SignalGenerator},
-        timers::SamplingTimer,
+        timers::{SampleCounter, SamplingTimer, TimestampTimer},
+        watchdog::{self, Watchdog},
         DigitalInput0, DigitalInput1, SystemTimer, Systick, AFE0, AFE1,
     },
     net::{
-        data_stream::{FrameGenerator, StreamFormat, StreamTarget},
+        capture::{self, Capture, CaptureConfig, UploadError},
+        data_stream::{
+            FrameGenerator, StreamFormat, StreamTarget, STREAM_TARGETS,
+        },
         miniconf::Miniconf,
+        serde::{ser::SerializeStruct, Serialize, Serializer},
         telemetry::{Telemetry, TelemetryBuffer},
         NetworkState, NetworkUsers,
     },
@@ -96,6 +150,15 @@ pub struct Settings {
     /// See [iir::IIR#miniconf]
     iir_ch: [[iir::IIR<f32>; IIR_CASCADE_LENGTH]; 2],
 
+    /// Specified true if IIR coefficient updates should be bumpless.
+    ///
+    /// # Path
+    /// `bumpless`
+    ///
+    /// # Value
+    /// "true" or "false"
+    bumpless: bool,
+
     /// Specified true if DI1 should be used as a "hold" input.
     ///
     /// # Path
@@ -123,14 +186,16 @@ pub struct Settings {
     /// Any non-zero value less than 65536.
     telemetry_period: u16,
 
-    /// Specifies the target for data livestreaming.
+    /// Specifies the targets for data livestreaming.
     ///
     /// # Path
-    /// `stream_target`
+    /// `stream_target/<n>`
+    ///
+    /// * <n> specifies which target to configure. <n> := [0, 1]
     ///
     /// # Value
     /// See [StreamTarget#miniconf]
-    stream_target: StreamTarget,
+    stream_target: [StreamTarget; STREAM_TARGETS],
 
     /// Specifies the config for signal generators to add on to DAC0/DAC1 outputs.
     ///
@@ -142,6 +207,57 @@ pub struct Settings {
     /// # Value
     /// See [signal_generator::BasicConfig#miniconf]
     signal_generator: [signal_generator::BasicConfig; 2],
+
+    /// Specifies the triggered capture configuration. Every update arms the capture.
+    ///
+    /// # Path
+    /// `capture`
+    ///
+    /// # Value
+    /// See [CaptureConfig#miniconf]
+    capture: CaptureConfig,
+
+    /// Specifies the lock detection configuration of each channel.
+    ///
+    /// # Path
+    /// `lock_detect/<n>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// See [LockDetectConfig#miniconf]
+    lock_detect: [LockDetectConfig; 2],
+
+    /// Specifies the automatic relock configuration of each channel.
+    ///
+    /// # Path
+    /// `relock/<n>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// See [RelockConfig#miniconf]
+    relock: [RelockConfig; 2],
+
+    /// Specifies the slew-rate limits of each channel.
+    ///
+    /// # Path
+    /// `slew/<n>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// See [SlewConfig#miniconf]
+    slew: [SlewConfig; 2],
+
+    /// Specifies the cross-channel mixing of filter inputs and outputs.
+    ///
+    /// # Path
+    /// `mimo`
+    ///
+    /// # Value
+    /// See [MimoConfig#miniconf]
+    mimo: MimoConfig,
 }
 
 impl Default for Settings {
@@ -155,6 +271,8 @@ impl Default for Settings {
             // The IIR coefficients can be mapped to other transfer function
             // representations, for example as described in https://arxiv.org/abs/1508.06319
             iir_ch: [[iir::IIR::new(1., -SCALE, SCALE); IIR_CASCADE_LENGTH]; 2],
+            // Apply coefficient updates without rewriting the filter state.
+            bumpless: false,
             // Permit the DI1 digital input to suppress filter output updates.
             allow_hold: false,
             // Force suppress filter output updates.
@@ -164,11 +282,48 @@ impl Default for Settings {
 
             signal_generator: [signal_generator::BasicConfig::default(); 2],
 
-            stream_target: StreamTarget::default(),
+            stream_target: [StreamTarget::default(); STREAM_TARGETS],
+
+            capture: CaptureConfig::default(),
+
+            lock_detect: [LockDetectConfig::default(); 2],
+
+            relock: [RelockConfig::default(); 2],
+
+            slew: [SlewConfig::default(); 2],
+
+            mimo: MimoConfig::default(),
         }
     }
 }
 
+/// The telemetry reported by the application: the fields of [Telemetry] followed by `lock` and
+/// `relock`.
+pub struct AppTelemetry {
+    /// The device telemetry.
+    telemetry: Telemetry,
+
+    /// The lock state of each channel.
+    lock: [LockState; 2],
+
+    /// The automatic relock status of each channel.
+    relock: [RelockStatus; 2],
+}
+
+impl Serialize for AppTelemetry {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut state =
+            serializer.serialize_struct("Telemetry", Telemetry::FIELDS + 2)?;
+        self.telemetry.serialize_fields(&mut state)?;
+        state.serialize_field("lock", &self.lock)?;
+        state.serialize_field("relock", &self.relock)?;
+        state.end()
+    }
+}
+
 #[rtic::app(device = stabilizer::hardware::hal::stm32, peripherals = true, dispatchers=[DCMI, JPEG, LTDC, SDMMC])]
 mod app {
     use super::*;
@@ -178,21 +333,29 @@ mod app {
 
     #[shared]
     struct Shared {
-        network: NetworkUsers<Settings, Telemetry>,
+        network: NetworkUsers<Settings, AppTelemetry>,
 
         settings: Settings,
         telemetry: TelemetryBuffer,
         signal_generator: [SignalGenerator; 2],
+        capture: Capture,
+        lock_detect: [LockDetector; 2],
+        relock: [Relock; 2],
+        iir_state: [[iir::Vec5<f32>; IIR_CASCADE_LENGTH]; 2],
+        slew: [Slew<IIR_CASCADE_LENGTH>; 2],
     }
 
     #[local]
     struct Local {
         sampling_timer: SamplingTimer,
+        timestamp_timer: TimestampTimer,
+        sample_counter: SampleCounter,
+        watchdog: Watchdog,
         digital_inputs: (DigitalInput0, DigitalInput1),
         afes: (AFE0, AFE1),
         adcs: (Adc0Input, Adc1Input),
         dacs: (Dac0Output, Dac1Output),
-        iir_state: [[iir::Vec5<f32>; IIR_CASCADE_LENGTH]; 2],
+        outputs: [[f32; BATCH_SIZE]; 2],
         generator: FrameGenerator,
         cpu_temp_sensor: stabilizer::hardware::cpu_temp_sensor::CpuTempSensor,
     }
@@ -202,7 +365,7 @@ mod app {
         let clock = SystemTimer::new(|| monotonics::now().ticks() as u32);
 
         // Configure the microcontroller
-        let (stabilizer, _pounder) = hardware::setup::setup(
+        let (stabilizer, pounder) = hardware::setup::setup(
             c.core,
             c.device,
             clock,
@@ -211,19 +374,23 @@ mod app {
         );
 
         let mut network = NetworkUsers::new(
-            stabilizer.net.stack,
-            stabilizer.net.phy,
+            stabilizer.net,
             clock,
             env!("CARGO_BIN_NAME"),
-            stabilizer.net.mac_address,
-            option_env!("BROKER")
+            option_env!("NTP_SERVER")
                 .unwrap_or("10.34.16.10")
                 .parse()
                 .unwrap(),
+            stabilizer.reset_reason,
+            pounder.is_some(),
+            true,
         );
 
-        let generator = network
-            .configure_streaming(StreamFormat::AdcDacData, BATCH_SIZE as _);
+        let generator = network.configure_streaming(
+            StreamFormat::AdcDacData,
+            BATCH_SIZE as _,
+            SAMPLE_TICKS,
+        );
 
         let settings = Settings::default();
 
@@ -243,15 +410,26 @@ mod app {
                         .unwrap(),
                 ),
             ],
+            capture: capture::setup_capture(),
+            lock_detect: [LockDetector::default(); 2],
+            relock: Default::default(),
+            iir_state: [[[0.; 5]; IIR_CASCADE_LENGTH]; 2],
+            slew: [Slew::default(); 2],
         };
 
+        let mut timestamp_timer = stabilizer.timestamp_timer;
+        let sample_counter = SampleCounter::new(timestamp_timer.counter());
+
         let mut local = Local {
             sampling_timer: stabilizer.adc_dac_timer,
+            timestamp_timer,
+            sample_counter,
+            watchdog: stabilizer.watchdog,
             digital_inputs: stabilizer.digital_inputs,
             afes: stabilizer.afes,
             adcs: stabilizer.adcs,
             dacs: stabilizer.dacs,
-            iir_state: [[[0.; 5]; IIR_CASCADE_LENGTH]; 2],
+            outputs: [[0.; BATCH_SIZE]; 2],
             generator,
             cpu_temp_sensor: stabilizer.temperature_sensor,
         };
@@ -271,10 +449,15 @@ mod app {
         (shared, local, init::Monotonics(stabilizer.systick))
     }
 
-    #[task(priority = 1, local=[sampling_timer])]
+    #[task(priority = 1, local=[sampling_timer, timestamp_timer])]
     fn start(c: start::Context) {
-        // Start sampling ADCs and DACs.
-        c.local.sampling_timer.start();
+        cortex_m::interrupt::free(|_| {
+            // Start counting sampling timer ticks for the stream timestamps.
+            c.local.timestamp_timer.start();
+
+            // Start sampling ADCs and DACs.
+            c.local.sampling_timer.start();
+        });
     }
 
     /// Main DSP processing routine.
@@ -293,25 +476,37 @@ mod app {
     ///
     /// Because the ADC and DAC operate at the same rate, these two constraints actually implement
     /// the same time bounds, meeting one also means the other is also met.
-    #[task(binds=DMA1_STR4, local=[digital_inputs, adcs, dacs, iir_state, generator], shared=[settings, signal_generator, telemetry], priority=3)]
+    #[task(binds=DMA1_STR4, local=[sample_counter, digital_inputs, adcs, dacs, outputs, generator], shared=[settings, signal_generator, telemetry, capture, lock_detect, relock, iir_state, slew], priority=3)]
     #[link_section = ".itcm.process"]
     fn process(c: process::Context) {
+        let start = CycleAccumulator::start();
+        watchdog::check_in();
+
         let process::SharedResources {
             settings,
             telemetry,
             signal_generator,
+            capture,
+            lock_detect,
+            relock,
+            iir_state,
+            slew,
         } = c.shared;
 
         let process::LocalResources {
+            sample_counter,
             digital_inputs,
             adcs: (adc0, adc1),
             dacs: (dac0, dac1),
-            iir_state,
+            outputs,
             generator,
         } = c.local;
 
-        (settings, telemetry, signal_generator).lock(
-            |settings, telemetry, signal_generator| {
+        // Latch the sampling timer count for the stream timestamp.
+        let timestamp = sample_counter.latch();
+
+        (settings, telemetry, signal_generator, capture).lock(
+            |settings, telemetry, signal_generator, capture| {
                 let digital_inputs =
                     [digital_inputs.0.is_high(), digital_inputs.1.is_high()];
                 telemetry.digital_inputs = digital_inputs;
@@ -326,36 +521,120 @@ mod app {
                     // Preserve instruction and data ordering w.r.t. DMA flag access.
                     fence(Ordering::SeqCst);
 
-                    for channel in 0..adc_samples.len() {
-                        adc_samples[channel]
-                            .iter()
-                            .zip(dac_samples[channel].iter_mut())
-                            .zip(&mut signal_generator[channel])
-                            .map(|((ai, di), signal)| {
-                                let x = f32::from(*ai as i16);
-                                let y = settings.iir_ch[channel]
-                                    .iter()
-                                    .zip(iir_state[channel].iter_mut())
-                                    .fold(x, |yi, (ch, state)| {
-                                        ch.update(state, yi, hold)
-                                    });
-
-                                // Note(unsafe): The filter limits must ensure that the value is in range.
-                                // The truncation introduces 1/2 LSB distortion.
-                                let y: i16 = unsafe { y.to_int_unchecked() };
-
-                                let y = y.saturating_add(signal);
-
-                                // Convert to DAC code
-                                *di = DacCode::from(y).0;
-                            })
-                            .last();
-                    }
+                    (lock_detect, relock, iir_state, slew).lock(
+                        |lock_detect, relock, iir_state, slew| {
+                            for channel in 0..adc_samples.len() {
+                                let detector = &mut lock_detect[channel];
+                                let relock = &mut relock[channel];
+                                let previous = detector.state();
+                                let hold = hold || detector.hold();
+                                let iir = &slew[channel]
+                                    .ramp(&settings.iir_ch[channel]);
+
+                                for sample in 0..BATCH_SIZE {
+                                    let x = settings.mimo.input(
+                                        channel,
+                                        [
+                                            f32::from(
+                                                adc_samples[0][sample] as i16,
+                                            ),
+                                            f32::from(
+                                                adc_samples[1][sample] as i16,
+                                            ),
+                                        ],
+                                        outputs[1 - channel][sample],
+                                    );
+
+                                    let y = if relock.is_engaged() {
+                                        iir.iter()
+                                            .zip(iir_state[channel].iter_mut())
+                                            .fold(x, |yi, (ch, state)| {
+                                                ch.update(state, yi, hold)
+                                            })
+                                    } else {
+                                        // The filter is disengaged during the relock sweep.
+                                        let (y, caught) = relock.sweep(x);
+                                        if caught {
+                                            filter_state::preset(
+                                                &mut iir_state[channel],
+                                                x,
+                                                y,
+                                            );
+                                        }
+                                        y
+                                    };
+
+                                    detector.update(
+                                        x,
+                                        y,
+                                        &iir[IIR_CASCADE_LENGTH - 1],
+                                    );
+
+                                    outputs[channel][sample] = y;
+                                }
+
+                                let state = detector.state();
+                                let transition = state != previous;
+                                if transition {
+                                    if detector.take_reset() {
+                                        filter_state::reset(
+                                            &mut iir_state[channel],
+                                        );
+                                    }
+                                    lock_event::spawn(channel, state).ok();
+                                }
+
+                                if relock.update(
+                                    state,
+                                    transition,
+                                    BATCH_SIZE as u32,
+                                ) {
+                                    relock_event::spawn(
+                                        channel,
+                                        relock.status(),
+                                    )
+                                    .ok();
+                                }
+                            }
+
+                            for channel in 0..dac_samples.len() {
+                                let slew = &mut slew[channel];
+
+                                dac_samples[channel]
+                                    .iter_mut()
+                                    .zip(&mut signal_generator[channel])
+                                    .enumerate()
+                                    .map(|(sample, (di, signal))| {
+                                        let y = settings.mimo.output(
+                                            channel,
+                                            [
+                                                outputs[0][sample],
+                                                outputs[1][sample],
+                                            ],
+                                        );
+
+                                        // The conversion saturates the mixed output to the DAC
+                                        // range. The truncation introduces 1/2 LSB distortion.
+                                        let y =
+                                            (y as i16).saturating_add(signal);
+
+                                        // The limited output lies between the previous and the
+                                        // requested output and is thus in range.
+                                        let y =
+                                            slew.output(f32::from(y)) as i16;
+
+                                        // Convert to DAC code
+                                        *di = DacCode::from(y).0;
+                                    })
+                                    .last();
+                            }
+                        },
+                    );
 
                     // Stream the data.
                     const N: usize = BATCH_SIZE * core::mem::size_of::<i16>()
                         / core::mem::size_of::<MaybeUninit<u8>>();
-                    generator.add::<_, { N * 4 }>(|buf| {
+                    generator.add::<_, { N * 4 }>(timestamp, |buf| {
                         for (data, buf) in adc_samples
                             .iter()
                             .chain(dac_samples.iter())
@@ -370,6 +649,16 @@ mod app {
                             buf.copy_from_slice(data)
                         }
                     });
+
+                    // Record the batch for triggered capture.
+                    if capture.update(
+                        [&adc_samples[0][..], &adc_samples[1][..]],
+                        [&dac_samples[0][..], &dac_samples[1][..]],
+                        digital_inputs,
+                    ) {
+                        capture_upload::spawn(0).ok();
+                    }
+
                     // Update telemetry measurements.
                     telemetry.adcs = [
                         AdcCode(adc_samples[0][0]),
@@ -381,6 +670,21 @@ mod app {
                         DacCode(dac_samples[1][0]),
                     ];
 
+                    for channel in 0..adc_samples.len() {
+                        telemetry.adc_statistics[channel].update(
+                            adc_samples[channel]
+                                .iter()
+                                .map(|&x| AdcCode(x).into()),
+                        );
+                        telemetry.dac_statistics[channel].update(
+                            dac_samples[channel]
+                                .iter()
+                                .map(|&x| DacCode(x).into()),
+                        );
+                    }
+
+                    telemetry.cpu_load.record(start);
+
                     // Preserve instruction and data ordering w.r.t. DMA flag access.
                     fence(Ordering::SeqCst);
                 });
@@ -388,12 +692,31 @@ mod app {
         );
     }
 
-    #[idle(shared=[network])]
+    #[idle(shared=[network], local=[watchdog])]
     fn idle(mut c: idle::Context) -> ! {
         loop {
+            c.local.watchdog.feed();
+
             match c.shared.network.lock(|net| net.update()) {
-                NetworkState::SettingsChanged(_path) => {
-                    settings_update::spawn().unwrap()
+                NetworkState::SettingsChanged(path) => {
+                    if path.starts_with("capture") {
+                        arm_capture::spawn().unwrap()
+                    }
+                    settings_update::spawn().unwrap();
+                }
+                NetworkState::Command(command) => {
+                    match command.channel("iir_state", 2) {
+                        Some(channel) => match command.parse() {
+                            Ok(config) => {
+                                apply_iir_state::spawn(channel, config).unwrap()
+                            }
+                            Err(err) => log::warn!(
+                                "Invalid filter state command: {:?}",
+                                err
+                            ),
+                        },
+                        None => log::warn!("Unknown command {}", command.name),
+                    }
                 }
                 NetworkState::Updated => {}
                 NetworkState::NoChange => cortex_m::asm::wfi(),
@@ -401,10 +724,33 @@ mod app {
         }
     }
 
-    #[task(priority = 1, local=[afes], shared=[network, settings, signal_generator])]
+    #[task(priority = 1, local=[afes], shared=[network, settings, signal_generator, lock_detect, relock, iir_state, slew])]
     fn settings_update(mut c: settings_update::Context) {
         let settings = c.shared.network.lock(|net| *net.miniconf.settings());
-        c.shared.settings.lock(|current| *current = settings);
+        (c.shared.settings, c.shared.iir_state, c.shared.slew).lock(
+            |current, iir_state, slew| {
+                for (channel, slew) in slew.iter_mut().enumerate() {
+                    slew.configure(
+                        &settings.slew[channel],
+                        SAMPLE_PERIOD,
+                        BATCH_SIZE,
+                    );
+
+                    // Transfer to the offsets currently used by the ramps.
+                    let iir = &settings.iir_ch[channel];
+                    if settings.bumpless
+                        && filter_state::changed(&current.iir_ch[channel], iir)
+                    {
+                        filter_state::transfer(
+                            &slew.current(iir),
+                            &mut iir_state[channel],
+                        );
+                    }
+                }
+
+                *current = settings;
+            },
+        );
 
         c.local.afes.0.set_gain(settings.afe[0]);
         c.local.afes.1.set_gain(settings.afe[1]);
@@ -425,14 +771,165 @@ mod app {
             }
         }
 
-        let target = settings.stream_target.into();
-        c.shared.network.lock(|net| net.direct_stream(target));
+        // Update the lock detectors
+        c.shared.lock_detect.lock(|detectors| {
+            for (i, detector) in detectors.iter_mut().enumerate() {
+                detector.configure(
+                    &settings.lock_detect[i],
+                    settings.afe[i],
+                    SAMPLE_PERIOD,
+                );
+            }
+        });
+
+        // Update the automatic relock
+        for (i, config) in settings.relock.iter().enumerate() {
+            if let Err(err) = c.shared.relock.lock(|relock| {
+                relock[i].configure(
+                    config,
+                    settings.afe[i],
+                    settings.lock_detect[i].lock_time,
+                    SAMPLE_PERIOD,
+                )
+            }) {
+                log::error!(
+                    "Failed to configure relock on channel {}: {:?}",
+                    i,
+                    err
+                );
+            }
+        }
+
+        c.shared
+            .network
+            .lock(|net| net.direct_stream(&settings.stream_target));
     }
 
-    #[task(priority = 1, shared=[network, settings, telemetry], local=[cpu_temp_sensor])]
+    #[task(priority = 1, shared=[network, iir_state])]
+    fn apply_iir_state(
+        mut c: apply_iir_state::Context,
+        channel: usize,
+        config: StateConfig,
+    ) {
+        let iir = c
+            .shared
+            .network
+            .lock(|net| net.miniconf.settings().iir_ch[channel]);
+
+        c.shared
+            .iir_state
+            .lock(|state| config.apply(&iir, &mut state[channel]));
+        log::info!("Channel {} filter state: {:?}", channel, config.action);
+    }
+
+    #[task(priority = 1, capacity = 4, shared=[network])]
+    fn lock_event(
+        mut c: lock_event::Context,
+        channel: usize,
+        state: LockState,
+    ) {
+        log::info!("Channel {} lock state: {:?}", channel, state);
+        c.shared.network.lock(|net| {
+            let time = net.time_status().utc;
+            net.telemetry.publish_event(&LockEvent {
+                channel,
+                state,
+                time,
+            })
+        });
+    }
+
+    #[task(priority = 1, capacity = 4, shared=[network])]
+    fn relock_event(
+        mut c: relock_event::Context,
+        channel: usize,
+        status: RelockStatus,
+    ) {
+        match status.state {
+            RelockState::Failed => log::warn!(
+                "Channel {} relock failed after {} attempts",
+                channel,
+                status.attempts
+            ),
+            state => {
+                log::info!("Channel {} relock state: {:?}", channel, state)
+            }
+        }
+        c.shared.network.lock(|net| {
+            let time = net.time_status().utc;
+            net.telemetry.publish_event(&RelockEvent {
+                channel,
+                status,
+                time,
+            })
+        });
+    }
+
+    #[task(priority = 1, shared=[network, capture])]
+    fn arm_capture(mut c: arm_capture::Context) {
+        let (config, afe) = c.shared.network.lock(|net| {
+            let settings = net.miniconf.settings();
+            (settings.capture, settings.afe)
+        });
+
+        match c.shared.capture.lock(|capture| capture.arm(&config, afe)) {
+            Ok(()) => log::info!("Capture armed: {:?}", config.trigger),
+            Err(err) => log::error!("Failed to arm capture: {:?}", err),
+        }
+    }
+
+    #[task(priority = 1, shared=[network, capture])]
+    fn capture_upload(mut c: capture_upload::Context, offset: usize) {
+        let mut offset = offset;
+        let mut samples = [[0; 4]; capture::CHUNK_LENGTH];
+        loop {
+            let (len, pre_trigger) = c.shared.capture.lock(|capture| {
+                (capture.read(offset, &mut samples), capture.pre_trigger())
+            });
+
+            // Release the capture once the last chunk is acknowledged.
+            let result = c.shared.network.lock(|net| {
+                // Note(unwrap): The capture client is constructed for this application.
+                let client = net.capture.as_mut().unwrap();
+                if len == 0 {
+                    client.flush()
+                } else {
+                    client.upload(offset, pre_trigger, &samples[..len])
+                }
+            });
+
+            // Publish chunks until the previous chunk is awaiting acknowledgement and retry later.
+            match result {
+                Ok(()) if len == 0 => {
+                    c.shared.capture.lock(|capture| capture.release());
+                    log::info!("Capture uploaded");
+                    return;
+                }
+                Ok(()) => offset += len,
+                Err(UploadError::Resend(lost)) => offset = lost,
+                Err(UploadError::Busy) => break,
+            }
+        }
+
+        capture_upload::Monotonic::spawn_after(1.millis(), offset).unwrap();
+    }
+
+    #[task(priority = 1, shared=[network, settings, telemetry, lock_detect, relock], local=[cpu_temp_sensor])]
     fn telemetry(mut c: telemetry::Context) {
-        let telemetry: TelemetryBuffer =
-            c.shared.telemetry.lock(|telemetry| *telemetry);
+        let telemetry: TelemetryBuffer = c.shared.telemetry.lock(|telemetry| {
+            let buffer = *telemetry;
+            telemetry.reset_statistics();
+            buffer
+        });
+
+        let lock = c
+            .shared
+            .lock_detect
+            .lock(|detectors| [detectors[0].state(), detectors[1].state()]);
+        let relock = c
+            .shared
+            .relock
+            .lock(|relock| [relock[0].status(), relock[1].status()]);
 
         let (gains, telemetry_period) = c
             .shared
@@ -440,11 +937,21 @@ mod app {
             .lock(|settings| (settings.afe, settings.telemetry_period));
 
         c.shared.network.lock(|net| {
-            net.telemetry.publish(&telemetry.finalize(
-                gains[0],
-                gains[1],
-                c.local.cpu_temp_sensor.get_temperature().unwrap(),
-            ))
+            // Locked channels inhibit resets into or out of the fallback network configuration.
+            net.set_controlling(lock.contains(&LockState::Locked));
+
+            let status = net.system_status();
+            net.telemetry.publish(&AppTelemetry {
+                telemetry: telemetry.finalize(
+                    gains[0],
+                    gains[1],
+                    c.local.cpu_temp_sensor.get_temperature().unwrap(),
+                    BATCH_SIZE as f32 * SAMPLE_PERIOD,
+                    status,
+                ),
+                lock,
+                relock,
+            })
         });
 
         // Schedule the telemetry task in the future.
@@ -7,59 +7,67 @@
 //! * Up to 800 kHz sampling
 //! * Up to 400 kHz modulation frequency
 //! * Supports internal and external reference sources:
-//!     1. Internal: Generate reference internally and output on one of the channel outputs
+//!     1. Internal: Generate reference internally with configurable frequency, amplitude and
+//!        waveform and output it on one of the channel outputs
 //!     2. External: Reciprocal PLL, reference input applied to DI0.
-//! * Adjustable PLL and locking time constants
-//! * Adjustable phase offset and harmonic index
+//! * Simultaneous demodulation of both ADC inputs with a shared reference
+//! * Adjustable PLL and per-channel locking time constants
+//! * Simultaneous demodulation at up to three harmonics per channel, each with an adjustable phase
+//!   offset
 //! * Run-time configurable output modes (in-phase, quadrature, magnitude, log2 power, phase, frequency)
-//! * Input/output data streamng via UDP
+//!   of either demodulated channel on each DAC
+//! * Per-output gain, offset and IIR filter (e.g. a PI servo) for lock-in based locking
+//! * Demodulated data streaming via UDP
 //!
 //! ## Settings
 //! Refer to the [Settings] structure for documentation of run-time configurable settings for this
 //! application.
 //!
 //! ## Telemetry
-//! Refer to [Telemetry] for information about telemetry reported by this application.
+//! Refer to [AppTelemetry] for information about telemetry reported by this application.
 //!
 //! ## Livestreaming
-//! This application streams raw ADC and DAC data over UDP. Refer to
+//! This application streams the demodulated in-phase and quadrature components as well as the
+//! reference phase and frequency of each batch over UDP. See [StreamFormat::Lockin] and
 //! [stabilizer::net::data_stream](../stabilizer/net/data_stream/index.html) for more information.
 #![deny(warnings)]
 #![no_std]
 #![no_main]
 
-use core::{
-    convert::TryFrom,
-    mem::MaybeUninit,
-    sync::atomic::{fence, Ordering},
-};
+use core::sync::atomic::{fence, Ordering};
 
 use fugit::ExtU64;
 use mutex_trait::prelude::*;
 
-use idsp::{Accu, Complex, ComplexExt, Lockin, RPLL};
+use idsp::{iir, Accu, Complex, ComplexExt, Lockin, RPLL};
 
 use stabilizer::{
     hardware::{
         self,
         adc::{Adc0Input, Adc1Input, AdcCode},
         afe::Gain,
+        cpu_load::CycleAccumulator,
         dac::{Dac0Output, Dac1Output, DacCode},
         hal,
         input_stamper::InputStamper,
-        signal_generator,
-        timers::SamplingTimer,
+        signal_generator::{self, SignalGenerator},
+        timers::{SampleCounter, SamplingTimer, TimestampTimer},
+        watchdog::{self, Watchdog},
         DigitalInput0, DigitalInput1, SystemTimer, Systick, AFE0, AFE1,
     },
     net::{
-        data_stream::{FrameGenerator, StreamFormat, StreamTarget},
+        data_stream::{
+            FrameGenerator, StreamFormat, StreamTarget, STREAM_TARGETS,
+        },
         miniconf::Miniconf,
-        serde::{Deserialize, Serialize},
+        serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer},
         telemetry::{Telemetry, TelemetryBuffer},
         NetworkState, NetworkUsers,
     },
 };
 
+const SCALE: f32 = i16::MAX as _;
+
 // The logarithm of the number of samples in each batch process. This corresponds with 2^3 samples
 // per batch = 8 samples
 const BATCH_SIZE_LOG2: u32 = 3;
@@ -70,6 +78,13 @@ const BATCH_SIZE: usize = 1 << BATCH_SIZE_LOG2;
 // period of 1.28 uS or 781.25 KHz.
 const SAMPLE_TICKS_LOG2: u32 = 7;
 const SAMPLE_TICKS: u32 = 1 << SAMPLE_TICKS_LOG2;
+const SAMPLE_PERIOD: f32 =
+    SAMPLE_TICKS as f32 * hardware::design_parameters::TIMER_PERIOD;
+const BATCH_PERIOD: f32 = (BATCH_SIZE as u32 * SAMPLE_TICKS) as f32
+    * hardware::design_parameters::TIMER_PERIOD;
+
+// The maximum number of harmonics demodulated per channel.
+const HARMONICS: usize = 3;
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, Miniconf)]
 enum Conf {
@@ -85,13 +100,13 @@ enum Conf {
     InPhase,
     /// Output the quadrature component of the lockin signal.
     Quadrature,
-    /// Output the lockin internal modulation frequency as a sinusoid
+    /// Output the internal reference modulation signal
     Modulation,
 }
 
 #[derive(Copy, Clone, Debug, Miniconf, Serialize, Deserialize, PartialEq)]
 enum LockinMode {
-    /// Utilize an internally generated reference for demodulation
+    /// Utilize an internally generated reference for demodulation. See `reference`.
     Internal,
     /// Utilize an external modulation signal supplied to DI0
     External,
@@ -119,6 +134,19 @@ pub struct Settings {
     /// One of the variants of [LockinMode] enclosed in double quotes.
     lockin_mode: LockinMode,
 
+    /// Specifies the internal reference modulation signal.
+    ///
+    /// # Path
+    /// `reference`
+    ///
+    /// # Value
+    /// See [signal_generator::BasicConfig#miniconf]
+    ///
+    /// # Note
+    /// The modulation is output on DACs configured to [Conf::Modulation]. In
+    /// [LockinMode::Internal], the signal fundamental is used as the demodulation reference.
+    reference: signal_generator::BasicConfig,
+
     /// Specifis the PLL time constant.
     ///
     /// # Path
@@ -133,30 +161,39 @@ pub struct Settings {
     /// Specifies the lockin time constant.
     ///
     /// # Path
-    /// `lockin_tc`
+    /// `lockin_tc/<n>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
     ///
     /// # Value
     /// The lockin low-pass time constant as an unsigned byte (0-255).
-    lockin_tc: u32,
+    lockin_tc: [u32; 2],
 
-    /// Specifies which harmonic to use for the lockin.
+    /// Specifies which harmonics to use for the lockin.
     ///
     /// # Path
-    /// `lockin_harmonic`
+    /// `lockin_harmonic/<n>/<m>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
+    /// * <m> specifies which demodulator to configure. <m> := [0, 1, 2]
     ///
     /// # Value
-    /// Harmonic index of the LO. -1 to _de_modulate the fundamental (complex conjugate)
-    lockin_harmonic: i32,
+    /// Harmonic index of the LO. -1 to _de_modulate the fundamental (complex conjugate). 0 disables
+    /// the demodulator.
+    lockin_harmonic: [[i32; HARMONICS]; 2],
 
     /// Specifies the LO phase offset.
     ///
     /// # Path
-    /// `lockin_phase`
+    /// `lockin_phase/<n>/<m>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
+    /// * <m> specifies which demodulator to configure. <m> := [0, 1, 2]
     ///
     /// # Value
     /// Demodulation LO phase offset. Units are in terms of i32, where [i32::MIN] is equivalent to
     /// -pi and [i32::MAX] is equivalent to +pi.
-    lockin_phase: i32,
+    lockin_phase: [[i32; HARMONICS]; 2],
 
     /// Specifies DAC output mode.
     ///
@@ -169,6 +206,65 @@ pub struct Settings {
     /// One of the variants of [Conf] enclosed in double quotes.
     output_conf: [Conf; 2],
 
+    /// Specifies which demodulated channel is output on each DAC.
+    ///
+    /// # Path
+    /// `output_channel/<n>`
+    ///
+    /// * <n> specifies which DAC to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// The index of the demodulated ADC input. [0, 1]
+    output_channel: [usize; 2],
+
+    /// Specifies which demodulator of the channel is output on each DAC.
+    ///
+    /// # Path
+    /// `output_harmonic/<n>`
+    ///
+    /// * <n> specifies which DAC to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// The index of the demodulator in `lockin_harmonic`. [0, 1, 2]
+    output_harmonic: [usize; 2],
+
+    /// Specifies the gain applied to each output before filtering.
+    ///
+    /// # Path
+    /// `output_gain/<n>`
+    ///
+    /// * <n> specifies which DAC to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// The dimensionless gain applied to the output in DAC codes.
+    output_gain: [f32; 2],
+
+    /// Specifies the offset added to each output before filtering.
+    ///
+    /// # Path
+    /// `output_offset/<n>`
+    ///
+    /// * <n> specifies which DAC to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// The offset in volts.
+    output_offset: [f32; 2],
+
+    /// Specifies the IIR filter applied to each output, e.g. to servo on the lockin output.
+    ///
+    /// # Path
+    /// `output_iir/<n>`
+    ///
+    /// * <n> specifies which DAC to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// See [iir::IIR#miniconf]
+    ///
+    /// # Note
+    /// The filter is updated once per batch. It is not applied to [Conf::Modulation]. The default
+    /// filter passes the output through.
+    output_iir: [iir::IIR<f32>; 2],
+
     /// Specifies the telemetry output period in seconds.
     ///
     /// # Path
@@ -178,14 +274,16 @@ pub struct Settings {
     /// Any non-zero value less than 65536.
     telemetry_period: u16,
 
-    /// Specifies the target for data livestreaming.
+    /// Specifies the targets for data livestreaming.
     ///
     /// # Path
-    /// `stream_target`
+    /// `stream_target/<n>`
+    ///
+    /// * <n> specifies which target to configure. <n> := [0, 1]
     ///
     /// # Value
     /// See [StreamTarget#miniconf]
-    stream_target: StreamTarget,
+    stream_target: [StreamTarget; STREAM_TARGETS],
 }
 
 impl Default for Settings {
@@ -195,21 +293,87 @@ impl Default for Settings {
 
             lockin_mode: LockinMode::External,
 
+            // 1V cosine at the batch rate.
+            reference: signal_generator::BasicConfig {
+                frequency: 1. / BATCH_PERIOD,
+                amplitude: 1.,
+                ..Default::default()
+            },
+
             pll_tc: [21, 21], // frequency and phase settling time (log2 counter cycles)
 
-            lockin_tc: 6,        // lockin lowpass time constant
-            lockin_harmonic: -1, // Harmonic index of the LO: -1 to _de_modulate the fundamental (complex conjugate)
-            lockin_phase: 0,     // Demodulation LO phase offset
+            lockin_tc: [6; 2], // lockin lowpass time constant
+            lockin_harmonic: [[-1, 0, 0]; 2], // Harmonic index of the LO: -1 to _de_modulate the fundamental (complex conjugate)
+            lockin_phase: [[0; HARMONICS]; 2], // Demodulation LO phase offset
 
             output_conf: [Conf::InPhase, Conf::Quadrature],
+            // Output the demodulated ADC0 signal on both DACs.
+            output_channel: [0; 2],
+            output_harmonic: [0; 2],
+            // Pass the outputs through unchanged.
+            output_gain: [1.; 2],
+            output_offset: [0.; 2],
+            output_iir: [iir::IIR::new(1., -SCALE, SCALE); 2],
             // The default telemetry period in seconds.
             telemetry_period: 10,
 
-            stream_target: StreamTarget::default(),
+            stream_target: [StreamTarget::default(); STREAM_TARGETS],
+        }
+    }
+}
+
+/// A demodulated signal at a harmonic of the lockin reference.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct Demodulation<T> {
+    /// The harmonic index of the reference.
+    harmonic: i32,
+    /// The in-phase component.
+    in_phase: T,
+    /// The quadrature component.
+    quadrature: T,
+}
+
+impl Demodulation<i32> {
+    /// Convert the demodulated signal to volts at the input.
+    ///
+    /// # Args
+    /// * `afe` - The AFE configuration of the demodulated channel.
+    fn finalize(&self, afe: Gain) -> Demodulation<f32> {
+        // Demodulated signals are normalized to the MSB-aligned ADC code.
+        let scale =
+            AdcCode::VOLT_PER_LSB / (1u32 << 16) as f32 / afe.as_multiplier();
+        Demodulation {
+            harmonic: self.harmonic,
+            in_phase: self.in_phase as f32 * scale,
+            quadrature: self.quadrature as f32 * scale,
         }
     }
 }
 
+/// The telemetry reported by the application: the fields of [Telemetry] followed by
+/// `demodulation`.
+pub struct AppTelemetry {
+    /// The device telemetry.
+    telemetry: Telemetry,
+
+    /// The demodulated signals of each channel in volts at the input, `None` for disabled
+    /// demodulators.
+    demodulation: [[Option<Demodulation<f32>>; HARMONICS]; 2],
+}
+
+impl Serialize for AppTelemetry {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut state =
+            serializer.serialize_struct("Telemetry", Telemetry::FIELDS + 1)?;
+        self.telemetry.serialize_fields(&mut state)?;
+        state.serialize_field("demodulation", &self.demodulation)?;
+        state.end()
+    }
+}
+
 #[rtic::app(device = stabilizer::hardware::hal::stm32, peripherals = true, dispatchers=[DCMI, JPEG, SDMMC])]
 mod app {
     use super::*;
@@ -219,22 +383,27 @@ mod app {
 
     #[shared]
     struct Shared {
-        network: NetworkUsers<Settings, Telemetry>,
+        network: NetworkUsers<Settings, AppTelemetry>,
         settings: Settings,
         telemetry: TelemetryBuffer,
+        signal_generator: SignalGenerator,
+        demodulation: [[Option<Demodulation<i32>>; HARMONICS]; 2],
     }
 
     #[local]
     struct Local {
         sampling_timer: SamplingTimer,
+        timestamp_timer: TimestampTimer,
+        sample_counter: SampleCounter,
+        watchdog: Watchdog,
         digital_inputs: (DigitalInput0, DigitalInput1),
         timestamper: InputStamper,
         afes: (AFE0, AFE1),
         adcs: (Adc0Input, Adc1Input),
         dacs: (Dac0Output, Dac1Output),
         pll: RPLL,
-        lockin: Lockin<4>,
-        signal_generator: signal_generator::SignalGenerator,
+        lockin: [[Lockin<4>; HARMONICS]; 2],
+        output_state: [iir::Vec5<f32>; 2],
         generator: FrameGenerator,
         cpu_temp_sensor: stabilizer::hardware::cpu_temp_sensor::CpuTempSensor,
     }
@@ -244,7 +413,7 @@ mod app {
         let clock = SystemTimer::new(|| monotonics::now().ticks() as u32);
 
         // Configure the microcontroller
-        let (mut stabilizer, _pounder) = hardware::setup::setup(
+        let (stabilizer, pounder) = hardware::setup::setup(
             c.core,
             c.device,
             clock,
@@ -253,37 +422,47 @@ mod app {
         );
 
         let mut network = NetworkUsers::new(
-            stabilizer.net.stack,
-            stabilizer.net.phy,
+            stabilizer.net,
             clock,
             env!("CARGO_BIN_NAME"),
-            stabilizer.net.mac_address,
-            option_env!("BROKER")
+            option_env!("NTP_SERVER")
                 .unwrap_or("10.34.16.10")
                 .parse()
                 .unwrap(),
+            stabilizer.reset_reason,
+            pounder.is_some(),
+            false,
+        );
+
+        let generator = network.configure_streaming(
+            StreamFormat::Lockin,
+            BATCH_SIZE as _,
+            SAMPLE_TICKS,
         );
 
-        let generator = network
-            .configure_streaming(StreamFormat::AdcDacData, BATCH_SIZE as _);
+        let settings = Settings::default();
 
         let shared = Shared {
             network,
             telemetry: TelemetryBuffer::default(),
-            settings: Settings::default(),
+            settings,
+            signal_generator: SignalGenerator::new(
+                settings
+                    .reference
+                    .try_into_config(SAMPLE_PERIOD, DacCode::FULL_SCALE)
+                    .unwrap(),
+            ),
+            demodulation: [[None; HARMONICS]; 2],
         };
 
-        let signal_config = signal_generator::Config {
-            // Same frequency as batch size.
-            phase_increment: [1 << (32 - BATCH_SIZE_LOG2); 2],
-            // 1V Amplitude
-            amplitude: DacCode::try_from(1.0).unwrap().into(),
-            signal: signal_generator::Signal::Cosine,
-            phase_offset: 0,
-        };
+        let mut timestamp_timer = stabilizer.timestamp_timer;
+        let sample_counter = SampleCounter::new(timestamp_timer.counter());
 
         let mut local = Local {
             sampling_timer: stabilizer.adc_dac_timer,
+            timestamp_timer,
+            sample_counter,
+            watchdog: stabilizer.watchdog,
             digital_inputs: stabilizer.digital_inputs,
             afes: stabilizer.afes,
             adcs: stabilizer.adcs,
@@ -291,10 +470,8 @@ mod app {
             timestamper: stabilizer.timestamper,
 
             pll: RPLL::new(SAMPLE_TICKS_LOG2 + BATCH_SIZE_LOG2),
-            lockin: Lockin::default(),
-            signal_generator: signal_generator::SignalGenerator::new(
-                signal_config,
-            ),
+            lockin: Default::default(),
+            output_state: [[0.; 5]; 2],
 
             generator,
             cpu_temp_sensor: stabilizer.temperature_sensor,
@@ -312,49 +489,73 @@ mod app {
         ethernet_link::spawn().unwrap();
         start::spawn_after(100.millis()).unwrap();
 
-        // Start recording digital input timestamps.
-        stabilizer.timestamp_timer.start();
-
         // Enable the timestamper.
         local.timestamper.start();
 
         (shared, local, init::Monotonics(stabilizer.systick))
     }
 
-    #[task(priority = 1, local=[sampling_timer])]
+    #[task(priority = 1, local=[sampling_timer, timestamp_timer])]
     fn start(c: start::Context) {
-        // Start sampling ADCs and DACs.
-        c.local.sampling_timer.start();
+        cortex_m::interrupt::free(|_| {
+            // Start counting sampling timer ticks for the stream timestamps.
+            c.local.timestamp_timer.start();
+
+            // Start sampling ADCs and DACs.
+            c.local.sampling_timer.start();
+        });
     }
 
     /// Main DSP processing routine.
     ///
     /// See `dual-iir` for general notes on processing time and timing.
     ///
-    /// This is an implementation of a externally (DI0) referenced PLL lockin on the ADC0 signal.
-    /// It outputs either I/Q or power/phase on DAC0/DAC1. Data is normalized to full scale.
+    /// This is an implementation of a externally (DI0) referenced PLL lockin on the ADC0 and ADC1
+    /// signals. It outputs either I/Q or power/phase of either channel on DAC0/DAC1. Data is
+    /// normalized to full scale.
     /// PLL bandwidth, filter bandwidth, slope, and x/y or power/phase post-filters are available.
-    #[task(binds=DMA1_STR4, shared=[settings, telemetry], local=[adcs, dacs, lockin, timestamper, pll, generator, signal_generator], priority=3)]
+    #[task(binds=DMA1_STR4, shared=[settings, telemetry, signal_generator, demodulation], local=[sample_counter, adcs, dacs, lockin, output_state, timestamper, pll, generator], priority=3)]
     #[link_section = ".itcm.process"]
     fn process(c: process::Context) {
+        let start = CycleAccumulator::start();
+        watchdog::check_in();
+
         let process::SharedResources {
             settings,
             telemetry,
+            signal_generator,
+            demodulation,
         } = c.shared;
 
         let process::LocalResources {
+            sample_counter,
             timestamper,
             adcs: (adc0, adc1),
             dacs: (dac0, dac1),
             pll,
             lockin,
-            signal_generator,
+            output_state,
             generator,
         } = c.local;
 
-        (settings, telemetry).lock(|settings, telemetry| {
-            let (reference_phase, reference_frequency) =
-                match settings.lockin_mode {
+        // Latch the sampling timer count for the stream timestamp.
+        let timestamp = sample_counter.latch();
+
+        (settings, telemetry, signal_generator, demodulation).lock(
+            |settings, telemetry, signal_generator, demodulation| {
+                // Generate the modulation of the batch. The generator advances independent of the
+                // output configuration to keep the internal reference coherent.
+                let modulation_phase = signal_generator.phase();
+                let mut modulation = [0i16; BATCH_SIZE];
+                for (sample, value) in
+                    modulation.iter_mut().zip(&mut *signal_generator)
+                {
+                    *sample = value;
+                }
+
+                let (reference_phase, reference_frequency) = match settings
+                    .lockin_mode
+                {
                     LockinMode::External => {
                         let timestamp =
                             timestamper.latest_timestamp().unwrap_or(None); // Ignore data from timer capture overflows.
@@ -366,41 +567,76 @@ mod app {
                         (pll_phase, (pll_frequency >> BATCH_SIZE_LOG2) as i32)
                     }
                     LockinMode::Internal => {
-                        // Reference phase and frequency are known.
-                        (1i32 << 30, 1i32 << (32 - BATCH_SIZE_LOG2))
+                        // Reference phase and frequency follow the modulation. The demodulation
+                        // phase retains a quarter turn offset to the modulation phase.
+                        (
+                            modulation_phase.wrapping_add(1i32 << 30),
+                            signal_generator.frequency(),
+                        )
                     }
                 };
 
-            let sample_frequency =
-                reference_frequency.wrapping_mul(settings.lockin_harmonic);
-            let sample_phase = settings.lockin_phase.wrapping_add(
-                reference_phase.wrapping_mul(settings.lockin_harmonic),
-            );
-
-            (adc0, adc1, dac0, dac1).lock(|adc0, adc1, dac0, dac1| {
-                let adc_samples = [adc0, adc1];
-                let mut dac_samples = [dac0, dac1];
-
-                // Preserve instruction and data ordering w.r.t. DMA flag access.
-                fence(Ordering::SeqCst);
-
-                let output: Complex<i32> = adc_samples[0]
-                    .iter()
-                    // Zip in the LO phase.
-                    .zip(Accu::new(sample_phase, sample_frequency))
-                    // Convert to signed, MSB align the ADC sample, update the Lockin (demodulate, filter)
-                    .map(|(&sample, phase)| {
-                        let s = (sample as i16 as i32) << 16;
-                        lockin.update(s, phase, settings.lockin_tc)
-                    })
-                    // Decimate
-                    .last()
-                    .unwrap()
-                    * 2; // Full scale assuming the 2f component is gone.
-
-                // Convert to DAC data.
-                for (channel, samples) in dac_samples.iter_mut().enumerate() {
-                    for sample in samples.iter_mut() {
+                (adc0, adc1, dac0, dac1).lock(|adc0, adc1, dac0, dac1| {
+                    let adc_samples = [adc0, adc1];
+                    let mut dac_samples = [dac0, dac1];
+
+                    // Preserve instruction and data ordering w.r.t. DMA flag access.
+                    fence(Ordering::SeqCst);
+
+                    let mut output = [[Complex::new(0, 0); HARMONICS]; 2];
+                    for channel in 0..adc_samples.len() {
+                        for demodulator in 0..HARMONICS {
+                            let harmonic =
+                                settings.lockin_harmonic[channel][demodulator];
+
+                            // A harmonic index of zero disables the demodulator.
+                            if harmonic == 0 {
+                                demodulation[channel][demodulator] = None;
+                                continue;
+                            }
+
+                            let sample_frequency =
+                                reference_frequency.wrapping_mul(harmonic);
+                            let sample_phase = settings.lockin_phase[channel]
+                                [demodulator]
+                                .wrapping_add(
+                                    reference_phase.wrapping_mul(harmonic),
+                                );
+
+                            let lockin = &mut lockin[channel][demodulator];
+                            let iq = adc_samples[channel]
+                                .iter()
+                                // Zip in the LO phase.
+                                .zip(Accu::new(sample_phase, sample_frequency))
+                                // Convert to signed, MSB align the ADC sample, update the Lockin (demodulate, filter)
+                                .map(|(&sample, phase)| {
+                                    let s = (sample as i16 as i32) << 16;
+                                    lockin.update(
+                                        s,
+                                        phase,
+                                        settings.lockin_tc[channel],
+                                    )
+                                })
+                                // Decimate
+                                .last()
+                                .unwrap()
+                                * 2; // Full scale assuming the 2f component is gone.
+
+                            output[channel][demodulator] = iq;
+                            demodulation[channel][demodulator] =
+                                Some(Demodulation {
+                                    harmonic,
+                                    in_phase: iq.re,
+                                    quadrature: iq.im,
+                                });
+                        }
+                    }
+
+                    // Convert to DAC data.
+                    for (channel, samples) in dac_samples.iter_mut().enumerate()
+                    {
+                        let output = output[settings.output_channel[channel]]
+                            [settings.output_harmonic[channel]];
                         let value = match settings.output_conf[channel] {
                             Conf::Magnitude => output.abs_sqr() as i32 >> 16,
                             Conf::Phase => output.arg() >> 16,
@@ -414,75 +650,167 @@ mod app {
                             Conf::Quadrature => output.im >> 16,
 
                             Conf::Modulation => {
-                                signal_generator.next().unwrap() as i32
+                                for (sample, modulation) in
+                                    samples.iter_mut().zip(modulation.iter())
+                                {
+                                    *sample = DacCode::from(*modulation).0;
+                                }
+                                continue;
                             }
                         };
 
-                        *sample = DacCode::from(value as i16).0;
-                    }
-                }
+                        // Scale, offset and filter the decimated output once per batch.
+                        let x = value as f32 * settings.output_gain[channel]
+                            + settings.output_offset[channel]
+                                * DacCode::LSB_PER_VOLT;
+                        let y = settings.output_iir[channel].update(
+                            &mut output_state[channel],
+                            x,
+                            false,
+                        );
 
-                // Stream the data.
-                const N: usize = BATCH_SIZE * core::mem::size_of::<i16>()
-                    / core::mem::size_of::<MaybeUninit<u8>>();
-                generator.add::<_, { N * 4 }>(|buf| {
-                    for (data, buf) in adc_samples
-                        .iter()
-                        .chain(dac_samples.iter())
-                        .zip(buf.chunks_exact_mut(N))
-                    {
-                        let data = unsafe {
-                            core::slice::from_raw_parts(
-                                data.as_ptr() as *const MaybeUninit<u8>,
-                                N,
-                            )
-                        };
-                        buf.copy_from_slice(data)
+                        // The conversion saturates the output to the DAC range.
+                        samples.fill(DacCode::from(y as i16).0);
                     }
-                });
 
-                // Update telemetry measurements.
-                telemetry.adcs =
-                    [AdcCode(adc_samples[0][0]), AdcCode(adc_samples[1][0])];
+                    // Stream the demodulated data.
+                    const N: usize = core::mem::size_of::<i32>();
+                    generator.add::<_, { N * (4 * HARMONICS + 2) }>(
+                        timestamp,
+                        |buf| {
+                            for (data, buf) in output
+                                .iter()
+                                .flatten()
+                                .flat_map(|iq| [iq.re, iq.im])
+                                .chain([reference_phase, reference_frequency])
+                                .zip(buf.chunks_exact_mut(N))
+                            {
+                                for (byte, buf) in
+                                    data.to_le_bytes().iter().zip(buf)
+                                {
+                                    buf.write(*byte);
+                                }
+                            }
+                        },
+                    );
+
+                    // Update telemetry measurements.
+                    telemetry.adcs = [
+                        AdcCode(adc_samples[0][0]),
+                        AdcCode(adc_samples[1][0]),
+                    ];
+
+                    telemetry.dacs = [
+                        DacCode(dac_samples[0][0]),
+                        DacCode(dac_samples[1][0]),
+                    ];
+
+                    for channel in 0..adc_samples.len() {
+                        telemetry.adc_statistics[channel].update(
+                            adc_samples[channel]
+                                .iter()
+                                .map(|&x| AdcCode(x).into()),
+                        );
+                        telemetry.dac_statistics[channel].update(
+                            dac_samples[channel]
+                                .iter()
+                                .map(|&x| DacCode(x).into()),
+                        );
+                    }
 
-                telemetry.dacs =
-                    [DacCode(dac_samples[0][0]), DacCode(dac_samples[1][0])];
+                    telemetry.cpu_load.record(start);
 
-                // Preserve instruction and data ordering w.r.t. DMA flag access.
-                fence(Ordering::SeqCst);
-            });
-        });
+                    // Preserve instruction and data ordering w.r.t. DMA flag access.
+                    fence(Ordering::SeqCst);
+                });
+            },
+        );
     }
 
-    #[idle(shared=[network])]
+    #[idle(shared=[network], local=[watchdog])]
     fn idle(mut c: idle::Context) -> ! {
         loop {
+            c.local.watchdog.feed();
+
             match c.shared.network.lock(|net| net.update()) {
                 NetworkState::SettingsChanged(_path) => {
                     settings_update::spawn().unwrap()
                 }
+                NetworkState::Command(command) => {
+                    log::warn!("Unknown command {}", command.name)
+                }
                 NetworkState::Updated => {}
                 NetworkState::NoChange => cortex_m::asm::wfi(),
             }
         }
     }
 
-    #[task(priority = 1, local=[afes], shared=[network, settings])]
+    #[task(priority = 1, local=[afes], shared=[network, settings, signal_generator])]
     fn settings_update(mut c: settings_update::Context) {
-        let settings = c.shared.network.lock(|net| *net.miniconf.settings());
-        c.shared.settings.lock(|current| *current = settings);
+        let mut settings =
+            c.shared.network.lock(|net| *net.miniconf.settings());
+
+        for (dac, channel) in settings.output_channel.iter_mut().enumerate() {
+            if *channel >= 2 {
+                log::error!("Invalid output channel {} on DAC{}", channel, dac);
+                *channel = 0;
+            }
+        }
+
+        for (dac, demodulator) in
+            settings.output_harmonic.iter_mut().enumerate()
+        {
+            if *demodulator >= HARMONICS {
+                log::error!(
+                    "Invalid output harmonic {} on DAC{}",
+                    demodulator,
+                    dac
+                );
+                *demodulator = 0;
+            }
+        }
+
+        let previous = c
+            .shared
+            .settings
+            .lock(|current| core::mem::replace(current, settings));
 
         c.local.afes.0.set_gain(settings.afe[0]);
         c.local.afes.1.set_gain(settings.afe[1]);
 
-        let target = settings.stream_target.into();
-        c.shared.network.lock(|net| net.direct_stream(target));
+        // Update the internal reference. The phase is cleared to define the phase relation of the
+        // modulation and the demodulation reference.
+        if settings.reference != previous.reference {
+            match settings
+                .reference
+                .try_into_config(SAMPLE_PERIOD, DacCode::FULL_SCALE)
+            {
+                Ok(config) => c.shared.signal_generator.lock(|generator| {
+                    generator.update_waveform(config);
+                    generator.clear_phase_accumulator();
+                }),
+                Err(err) => {
+                    log::error!(
+                        "Failed to update internal reference: {:?}",
+                        err
+                    )
+                }
+            }
+        }
+
+        c.shared
+            .network
+            .lock(|net| net.direct_stream(&settings.stream_target));
     }
 
-    #[task(priority = 1, local=[digital_inputs, cpu_temp_sensor], shared=[network, settings, telemetry])]
+    #[task(priority = 1, local=[digital_inputs, cpu_temp_sensor], shared=[network, settings, telemetry, demodulation])]
     fn telemetry(mut c: telemetry::Context) {
         let mut telemetry: TelemetryBuffer =
-            c.shared.telemetry.lock(|telemetry| *telemetry);
+            c.shared.telemetry.lock(|telemetry| {
+                let buffer = *telemetry;
+                telemetry.reset_statistics();
+                buffer
+            });
 
         telemetry.digital_inputs = [
             c.local.digital_inputs.0.is_high(),
@@ -494,12 +822,29 @@ mod app {
             .settings
             .lock(|settings| (settings.afe, settings.telemetry_period));
 
+        let mut demodulation = [[None; HARMONICS]; 2];
+        c.shared.demodulation.lock(|latest| {
+            for ((out, channel), afe) in
+                demodulation.iter_mut().zip(latest.iter()).zip(gains)
+            {
+                for (out, latest) in out.iter_mut().zip(channel.iter()) {
+                    *out = latest.map(|d| d.finalize(afe));
+                }
+            }
+        });
+
         c.shared.network.lock(|net| {
-            net.telemetry.publish(&telemetry.finalize(
-                gains[0],
-                gains[1],
-                c.local.cpu_temp_sensor.get_temperature().unwrap(),
-            ))
+            let status = net.system_status();
+            net.telemetry.publish(&AppTelemetry {
+                telemetry: telemetry.finalize(
+                    gains[0],
+                    gains[1],
+                    c.local.cpu_temp_sensor.get_temperature().unwrap(),
+                    BATCH_PERIOD,
+                    status,
+                ),
+                demodulation,
+            })
         });
 
         // Schedule the telemetry task in the future.
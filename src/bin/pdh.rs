@@ -0,0 +1,940 @@
+//! # Pound-Drever-Hall
+//!
+//! The PDH application locks two channels to optical (or RF) resonances using the
+//! Pound-Drever-Hall technique with Pounder. Each channel generates the phase modulation tone on a
+//! Pounder DDS output and demodulates the reflected signal with the Pounder mixer of the
+//! respective input. The demodulated error signal is sampled by Stabilizer, filtered through
+//! configurable biquads and generated on the respective DAC output.
+//!
+//! ## Features
+//! * Two independent channels
+//! * DDS generated modulation with configurable frequency, amplitude and attenuation
+//! * Configurable demodulation phase
+//! * Demodulation phase calibration by phase scan
+//! * Run-time filter configuration
+//! * Lock detection
+//! * Input/Output data streaming
+//!
+//! ## Channels
+//! Channel `n` generates the modulation on Pounder output `OUT<n>` and the demodulation reference
+//! on the mixer of Pounder input `IN<n>`. The demodulated error signal of `IN<n>` is sampled by
+//! `ADC<n>` and the filter output is generated on `DAC<n>`.
+//!
+//! ## Demodulation Phase
+//! The modulation and the demodulation reference are generated by two channels of the DDS with
+//! the same frequency. Their relative phase is arbitrary after a change of the modulation
+//! frequency and the demodulation phase has to be recalibrated. A phase scan sweeps the output
+//! across the resonance and selects the demodulation phase with the largest error signal. Refer to
+//! [stabilizer::dsp::phase_scan](../stabilizer/dsp/phase_scan/index.html) for more information.
+//!
+//! ## Settings
+//! Refer to the [Settings] structure for documentation of run-time configurable settings for this
+//! application.
+//!
+//! ## Telemetry
+//! Refer to [AppTelemetry] for information about telemetry reported by this application.
+//!
+//! ## Livestreaming
+//! This application streams raw ADC and DAC data over UDP. Refer to
+//! [stabilizer::net::data_stream](../stabilizer/net/data_stream/index.html) for more information.
+//!
+//! ## Lock Detection
+//! Each channel is monitored by a lock detector reporting the lock state in telemetry and
+//! publishing state transitions as events. Refer to
+//! [stabilizer::dsp::lock_detect](../stabilizer/dsp/lock_detect/index.html) for more information.
+//!
+//! ## Commands
+//! Commands are published to `<prefix>/command/<name>` and are executed once. In addition to the
+//! device commands (see [stabilizer::net::command](../stabilizer/net/command/index.html)), this
+//! application accepts:
+//! * `phase_scan/<n>`: Start a demodulation phase scan of channel `<n>` := [0, 1]. See
+//!   [PhaseScanConfig#command] for the arguments.
+#![deny(warnings)]
+#![no_std]
+#![no_main]
+
+use core::mem::MaybeUninit;
+use core::sync::atomic::{fence, Ordering};
+
+use fugit::ExtU64;
+use mutex_trait::prelude::*;
+
+use idsp::iir;
+
+use stabilizer::{
+    dsp::{
+        filter_state,
+        lock_detect::{LockDetectConfig, LockDetector, LockEvent, LockState},
+        phase_scan::{PhaseCalibration, PhaseScan, PhaseScanConfig, ScanStep},
+    },
+    hardware::{
+        self,
+        adc::{Adc0Input, Adc1Input, AdcCode},
+        afe::Gain,
+        cpu_load::CycleAccumulator,
+        dac::{Dac0Output, Dac1Output, DacCode},
+        design_parameters::DDS_SYSTEM_CLK,
+        hal,
+        pounder::{
+            self, attenuators::AttenuatorInterface, dds_output::DdsOutput,
+        },
+        timers::{SampleCounter, SamplingTimer, TimestampTimer},
+        watchdog::{self, Watchdog},
+        DigitalInput0, DigitalInput1, SystemTimer, Systick, AFE0, AFE1,
+    },
+    net::{
+        data_stream::{
+            FrameGenerator, StreamFormat, StreamTarget, STREAM_TARGETS,
+        },
+        miniconf::Miniconf,
+        serde::{ser::SerializeStruct, Serialize, Serializer},
+        telemetry::{Telemetry, TelemetryBuffer},
+        NetworkState, NetworkUsers,
+    },
+};
+
+const SCALE: f32 = i16::MAX as _;
+
+// The number of cascaded IIR biquads per channel. Select 1 or 2!
+const IIR_CASCADE_LENGTH: usize = 2;
+
+// The number of samples in each batch process
+const BATCH_SIZE: usize = 8;
+
+// The logarithm of the number of 100MHz timer ticks between each sample. With a value of 2^7 =
+// 128, there is 1.28uS per sample, corresponding to a sampling frequency of 781.25 KHz.
+const SAMPLE_TICKS_LOG2: u8 = 7;
+const SAMPLE_TICKS: u32 = 1 << SAMPLE_TICKS_LOG2;
+const SAMPLE_PERIOD: f32 =
+    SAMPLE_TICKS as f32 * hardware::design_parameters::TIMER_PERIOD;
+
+// The Pounder outputs generating the modulation of each channel.
+const MODULATION: [pounder::Channel; 2] =
+    [pounder::Channel::Out0, pounder::Channel::Out1];
+
+// The Pounder inputs demodulating the error signal of each channel.
+const DEMODULATION: [pounder::Channel; 2] =
+    [pounder::Channel::In0, pounder::Channel::In1];
+
+#[derive(Clone, Copy, Debug, Miniconf)]
+pub struct Settings {
+    /// Configure the Analog Front End (AFE) gain.
+    ///
+    /// # Path
+    /// `afe/<n>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// Any of the variants of [Gain] enclosed in double quotes.
+    afe: [Gain; 2],
+
+    /// Specifies the modulation frequency in Hertz. The demodulation reference is generated with
+    /// the same frequency. Changing the frequency requires recalibrating the demodulation phase.
+    ///
+    /// # Path
+    /// `modulation_frequency/<n>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// Any positive value less than half the DDS system clock frequency.
+    modulation_frequency: [f32; 2],
+
+    /// Specifies the normalized DDS amplitude of the modulation.
+    ///
+    /// # Path
+    /// `modulation_amplitude/<n>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// Any value between 0 and 1 (full scale).
+    modulation_amplitude: [f32; 2],
+
+    /// Specifies the attenuation of the modulation output in dB.
+    ///
+    /// # Path
+    /// `modulation_attenuation/<n>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// Any value between 0 and 31.5 with a resolution of 0.5.
+    modulation_attenuation: [f32; 2],
+
+    /// Specifies the attenuation of the Pounder input in dB.
+    ///
+    /// # Path
+    /// `input_attenuation/<n>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// Any value between 0 and 31.5 with a resolution of 0.5.
+    input_attenuation: [f32; 2],
+
+    /// Specifies the demodulation phase in turns. A completed phase scan overrides the phase until
+    /// this setting is changed.
+    ///
+    /// # Path
+    /// `demodulation_phase/<n>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// Any value. Only the fractional part is used.
+    demodulation_phase: [f32; 2],
+
+    /// Configure the IIR filter parameters.
+    ///
+    /// # Path
+    /// `iir_ch/<n>/<m>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
+    /// * <m> specifies which cascade to configure. <m> := [0, 1], depending on [IIR_CASCADE_LENGTH]
+    ///
+    /// # Value
+    /// See [iir::IIR#miniconf]
+    iir_ch: [[iir::IIR<f32>; IIR_CASCADE_LENGTH]; 2],
+
+    /// Specified true if DI1 should be used as a "hold" input.
+    ///
+    /// # Path
+    /// `allow_hold`
+    ///
+    /// # Value
+    /// "true" or "false"
+    allow_hold: bool,
+
+    /// Specified true if "hold" should be forced regardless of DI1 state and hold allowance.
+    ///
+    /// # Path
+    /// `force_hold`
+    ///
+    /// # Value
+    /// "true" or "false"
+    force_hold: bool,
+
+    /// Specifies the lock detection configuration of each channel.
+    ///
+    /// # Path
+    /// `lock_detect/<n>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// See [LockDetectConfig#miniconf]
+    lock_detect: [LockDetectConfig; 2],
+
+    /// Specifies the telemetry output period in seconds.
+    ///
+    /// # Path
+    /// `telemetry_period`
+    ///
+    /// # Value
+    /// Any non-zero value less than 65536.
+    telemetry_period: u16,
+
+    /// Specifies the targets for data livestreaming.
+    ///
+    /// # Path
+    /// `stream_target/<n>`
+    ///
+    /// * <n> specifies which target to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// See [StreamTarget#miniconf]
+    stream_target: [StreamTarget; STREAM_TARGETS],
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            // Analog frontend programmable gain amplifier gains (G1, G2, G5, G10)
+            afe: [Gain::G1, Gain::G1],
+            // The modulation is generated at 20 MHz with full DDS amplitude.
+            modulation_frequency: [20e6; 2],
+            modulation_amplitude: [1.0; 2],
+            // Fully attenuate the modulation until configured.
+            modulation_attenuation: [31.5; 2],
+            input_attenuation: [0.0; 2],
+            demodulation_phase: [0.0; 2],
+            // IIR filter tap gains are an array `[b0, b1, b2, a1, a2]` such that the
+            // new output is computed as `y0 = a1*y1 + a2*y2 + b0*x0 + b1*x1 + b2*x2`.
+            // The array is `iir_state[channel-index][cascade-index][coeff-index]`.
+            iir_ch: [[iir::IIR::new(1., -SCALE, SCALE); IIR_CASCADE_LENGTH]; 2],
+            // Permit the DI1 digital input to suppress filter output updates.
+            allow_hold: false,
+            // Force suppress filter output updates.
+            force_hold: false,
+            lock_detect: [LockDetectConfig::default(); 2],
+            // The default telemetry period in seconds.
+            telemetry_period: 10,
+
+            stream_target: [StreamTarget::default(); STREAM_TARGETS],
+        }
+    }
+}
+
+/// The DDS channels generating the modulation and the demodulation reference of both channels.
+pub struct Dds {
+    output: DdsOutput,
+    phase: [f32; 2],
+}
+
+impl Dds {
+    /// Configure the modulation and the demodulation reference of a channel.
+    ///
+    /// # Note
+    /// Both DDS channels are updated with the same IO update. Writing an unchanged frequency
+    /// therefore leaves their relative phase unchanged.
+    ///
+    /// # Args
+    /// * `channel` - The channel to configure.
+    /// * `frequency` - The modulation frequency in Hertz.
+    /// * `amplitude` - The normalized modulation amplitude.
+    pub fn configure(
+        &mut self,
+        channel: usize,
+        frequency: f32,
+        amplitude: f32,
+    ) -> Result<(), pounder::Error> {
+        let system_clock = DDS_SYSTEM_CLK.to_Hz() as f32;
+        if !(0.0..system_clock / 2.).contains(&frequency)
+            || !(0.0..=1.0).contains(&amplitude)
+        {
+            return Err(pounder::Error::Bounds);
+        }
+
+        // The function for channel frequency is `f_out = FTW * f_s / 2^32`, where FTW is the
+        // frequency tuning word and f_s is the system clock rate.
+        let ftw = (frequency / system_clock * (1u64 << 32) as f32) as u32;
+
+        // The amplitude multiplier is enabled (bit 12 of the ACR) unless full-scale is used.
+        let amplitude = (amplitude * (1 << 10) as f32) as u32;
+        let acr = if amplitude < (1 << 10) {
+            (1 << 12) | amplitude
+        } else {
+            0
+        };
+
+        let modulation: ad9959::Channel = MODULATION[channel].into();
+        let demodulation: ad9959::Channel = DEMODULATION[channel].into();
+
+        // Profiles are limited to 16 bytes. Split the update into two profiles, the first of which
+        // is drained from the QSPI FIFO before the second is written. Both take effect with the
+        // IO update of the second profile.
+        self.output
+            .builder()
+            .update_channels(modulation | demodulation, Some(ftw), None, None)
+            .update_channels(
+                demodulation,
+                None,
+                Some(pow(self.phase[channel])),
+                None,
+            )
+            .write_deferred();
+        self.output
+            .builder()
+            .update_channels(modulation, None, None, Some(acr))
+            .write();
+
+        Ok(())
+    }
+
+    /// Set the demodulation phase of a channel.
+    ///
+    /// # Args
+    /// * `channel` - The channel to configure.
+    /// * `phase` - The demodulation phase in turns.
+    pub fn set_phase(&mut self, channel: usize, phase: f32) {
+        self.phase[channel] = phase;
+        self.output
+            .builder()
+            .update_channels(
+                DEMODULATION[channel].into(),
+                None,
+                Some(pow(phase)),
+                None,
+            )
+            .write();
+    }
+}
+
+/// Convert a phase in turns to a DDS phase offset word.
+fn pow(phase: f32) -> u16 {
+    (phase * (1 << 14) as f32) as i32 as u16 & 0x3FFF
+}
+
+/// The telemetry reported by the application: the fields of [Telemetry] followed by `lock`.
+pub struct AppTelemetry {
+    /// The device telemetry.
+    telemetry: Telemetry,
+
+    /// The lock state of each channel.
+    lock: [LockState; 2],
+}
+
+impl Serialize for AppTelemetry {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut state =
+            serializer.serialize_struct("Telemetry", Telemetry::FIELDS + 1)?;
+        self.telemetry.serialize_fields(&mut state)?;
+        state.serialize_field("lock", &self.lock)?;
+        state.end()
+    }
+}
+
+#[rtic::app(device = stabilizer::hardware::hal::stm32, peripherals = true, dispatchers=[DCMI, JPEG, LTDC, SDMMC])]
+mod app {
+    use super::*;
+
+    #[monotonic(binds = SysTick, default = true, priority = 2)]
+    type Monotonic = Systick;
+
+    #[shared]
+    struct Shared {
+        network: NetworkUsers<Settings, AppTelemetry>,
+
+        settings: Settings,
+        telemetry: TelemetryBuffer,
+        lock_detect: [LockDetector; 2],
+        phase_scan: [PhaseScan; 2],
+        dds: Dds,
+    }
+
+    #[local]
+    struct Local {
+        sampling_timer: SamplingTimer,
+        timestamp_timer: TimestampTimer,
+        sample_counter: SampleCounter,
+        watchdog: Watchdog,
+        digital_inputs: (DigitalInput0, DigitalInput1),
+        afes: (AFE0, AFE1),
+        adcs: (Adc0Input, Adc1Input),
+        dacs: (Dac0Output, Dac1Output),
+        pounder: pounder::PounderDevices,
+        iir_state: [[iir::Vec5<f32>; IIR_CASCADE_LENGTH]; 2],
+        generator: FrameGenerator,
+        cpu_temp_sensor: stabilizer::hardware::cpu_temp_sensor::CpuTempSensor,
+    }
+
+    #[init]
+    fn init(c: init::Context) -> (Shared, Local, init::Monotonics) {
+        let clock = SystemTimer::new(|| monotonics::now().ticks() as u32);
+
+        // Configure the microcontroller
+        let (stabilizer, pounder) = hardware::setup::setup(
+            c.core,
+            c.device,
+            clock,
+            BATCH_SIZE,
+            SAMPLE_TICKS,
+        );
+
+        let pounder = pounder.expect("The PDH application requires Pounder");
+
+        let mut network = NetworkUsers::new(
+            stabilizer.net,
+            clock,
+            env!("CARGO_BIN_NAME"),
+            option_env!("NTP_SERVER")
+                .unwrap_or("10.34.16.10")
+                .parse()
+                .unwrap(),
+            stabilizer.reset_reason,
+            true,
+            false,
+        );
+
+        let generator = network.configure_streaming(
+            StreamFormat::AdcDacData,
+            BATCH_SIZE as _,
+            SAMPLE_TICKS,
+        );
+
+        let settings = Settings::default();
+
+        let shared = Shared {
+            network,
+            settings,
+            telemetry: TelemetryBuffer::default(),
+            lock_detect: [LockDetector::default(); 2],
+            phase_scan: Default::default(),
+            dds: Dds {
+                output: pounder.dds_output,
+                phase: settings.demodulation_phase,
+            },
+        };
+
+        let mut timestamp_timer = stabilizer.timestamp_timer;
+        let sample_counter = SampleCounter::new(timestamp_timer.counter());
+
+        let mut local = Local {
+            sampling_timer: stabilizer.adc_dac_timer,
+            timestamp_timer,
+            sample_counter,
+            watchdog: stabilizer.watchdog,
+            digital_inputs: stabilizer.digital_inputs,
+            afes: stabilizer.afes,
+            adcs: stabilizer.adcs,
+            dacs: stabilizer.dacs,
+            pounder: pounder.pounder,
+            iir_state: [[[0.; 5]; IIR_CASCADE_LENGTH]; 2],
+            generator,
+            cpu_temp_sensor: stabilizer.temperature_sensor,
+        };
+
+        // Enable ADC/DAC events
+        local.adcs.0.start();
+        local.adcs.1.start();
+        local.dacs.0.start();
+        local.dacs.1.start();
+
+        // Spawn a settings update for default settings.
+        settings_update::spawn().unwrap();
+        telemetry::spawn().unwrap();
+        ethernet_link::spawn().unwrap();
+        start::spawn_after(100.millis()).unwrap();
+
+        (shared, local, init::Monotonics(stabilizer.systick))
+    }
+
+    #[task(priority = 1, local=[sampling_timer, timestamp_timer])]
+    fn start(c: start::Context) {
+        cortex_m::interrupt::free(|_| {
+            // Start counting sampling timer ticks for the stream timestamps.
+            c.local.timestamp_timer.start();
+
+            // Start sampling ADCs and DACs.
+            c.local.sampling_timer.start();
+        });
+    }
+
+    /// Main DSP processing routine.
+    ///
+    /// # Note
+    /// Processing time for the DSP application code is bounded by the following constraints:
+    ///
+    /// DSP application code starts after the ADC has generated a batch of samples and must be
+    /// completed by the time the next batch of ADC samples has been acquired (plus the FIFO buffer
+    /// time). If this constraint is not met, firmware will panic due to an ADC input overrun.
+    ///
+    /// The DSP application code must also fill out the next DAC output buffer in time such that the
+    /// DAC can switch to it when it has completed the current buffer. If this constraint is not met
+    /// it's possible that old DAC codes will be generated on the output and the output samples will
+    /// be delayed by 1 batch.
+    ///
+    /// Because the ADC and DAC operate at the same rate, these two constraints actually implement
+    /// the same time bounds, meeting one also means the other is also met.
+    #[task(binds=DMA1_STR4, local=[sample_counter, digital_inputs, adcs, dacs, iir_state, generator], shared=[settings, telemetry, lock_detect, phase_scan, dds], priority=3)]
+    #[link_section = ".itcm.process"]
+    fn process(c: process::Context) {
+        let start = CycleAccumulator::start();
+        watchdog::check_in();
+
+        let process::SharedResources {
+            settings,
+            telemetry,
+            lock_detect,
+            phase_scan,
+            mut dds,
+        } = c.shared;
+
+        let process::LocalResources {
+            sample_counter,
+            digital_inputs,
+            adcs: (adc0, adc1),
+            dacs: (dac0, dac1),
+            iir_state,
+            generator,
+        } = c.local;
+
+        // Latch the sampling timer count for the stream timestamp.
+        let timestamp = sample_counter.latch();
+
+        (settings, telemetry, lock_detect, phase_scan).lock(
+            |settings, telemetry, lock_detect, phase_scan| {
+                let digital_inputs =
+                    [digital_inputs.0.is_high(), digital_inputs.1.is_high()];
+                telemetry.digital_inputs = digital_inputs;
+
+                let hold = settings.force_hold
+                    || (digital_inputs[1] && settings.allow_hold);
+
+                (adc0, adc1, dac0, dac1).lock(|adc0, adc1, dac0, dac1| {
+                    let adc_samples = [adc0, adc1];
+                    let dac_samples = [dac0, dac1];
+
+                    // Preserve instruction and data ordering w.r.t. DMA flag access.
+                    fence(Ordering::SeqCst);
+
+                    for channel in 0..adc_samples.len() {
+                        let scan = &mut phase_scan[channel];
+
+                        // The filter is disengaged during the phase scan.
+                        if scan.is_active() {
+                            for di in dac_samples[channel].iter_mut() {
+                                *di = DacCode::from(scan.sweep()).0;
+                            }
+
+                            match scan.update(
+                                adc_samples[channel].iter().map(|&x| x as i16),
+                            ) {
+                                Some(ScanStep::Phase(phase)) => dds
+                                    .lock(|dds| dds.set_phase(channel, phase)),
+                                Some(ScanStep::Done { phase, amplitude }) => {
+                                    dds.lock(|dds| {
+                                        dds.set_phase(channel, phase)
+                                    });
+                                    // Continue from the sweep center.
+                                    filter_state::preset(
+                                        &mut iir_state[channel],
+                                        0.,
+                                        scan.center(),
+                                    );
+                                    phase_calibrated::spawn(
+                                        channel, phase, amplitude,
+                                    )
+                                    .ok();
+                                }
+                                None => {}
+                            }
+                            continue;
+                        }
+
+                        let detector = &mut lock_detect[channel];
+                        let previous = detector.state();
+                        let hold = hold || detector.hold();
+                        let iir = &settings.iir_ch[channel];
+
+                        adc_samples[channel]
+                            .iter()
+                            .zip(dac_samples[channel].iter_mut())
+                            .map(|(ai, di)| {
+                                let x = f32::from(*ai as i16);
+                                let y = iir
+                                    .iter()
+                                    .zip(iir_state[channel].iter_mut())
+                                    .fold(x, |yi, (ch, state)| {
+                                        ch.update(state, yi, hold)
+                                    });
+
+                                detector.update(
+                                    x,
+                                    y,
+                                    &iir[IIR_CASCADE_LENGTH - 1],
+                                );
+
+                                // Note(unsafe): The filter limits must ensure that the value is in
+                                // range. The truncation introduces 1/2 LSB distortion.
+                                let y: i16 = unsafe { y.to_int_unchecked() };
+                                // Convert to DAC code
+                                *di = DacCode::from(y).0;
+                            })
+                            .last();
+
+                        let state = detector.state();
+                        if state != previous {
+                            if detector.take_reset() {
+                                filter_state::reset(&mut iir_state[channel]);
+                            }
+                            lock_event::spawn(channel, state).ok();
+                        }
+                    }
+
+                    // Stream the data.
+                    const N: usize = BATCH_SIZE * core::mem::size_of::<i16>()
+                        / core::mem::size_of::<MaybeUninit<u8>>();
+                    generator.add::<_, { N * 4 }>(timestamp, |buf| {
+                        for (data, buf) in adc_samples
+                            .iter()
+                            .chain(dac_samples.iter())
+                            .zip(buf.chunks_exact_mut(N))
+                        {
+                            let data = unsafe {
+                                core::slice::from_raw_parts(
+                                    data.as_ptr() as *const MaybeUninit<u8>,
+                                    N,
+                                )
+                            };
+                            buf.copy_from_slice(data)
+                        }
+                    });
+
+                    // Update telemetry measurements.
+                    telemetry.adcs = [
+                        AdcCode(adc_samples[0][0]),
+                        AdcCode(adc_samples[1][0]),
+                    ];
+
+                    telemetry.dacs = [
+                        DacCode(dac_samples[0][0]),
+                        DacCode(dac_samples[1][0]),
+                    ];
+
+                    for channel in 0..adc_samples.len() {
+                        telemetry.adc_statistics[channel].update(
+                            adc_samples[channel]
+                                .iter()
+                                .map(|&x| AdcCode(x).into()),
+                        );
+                        telemetry.dac_statistics[channel].update(
+                            dac_samples[channel]
+                                .iter()
+                                .map(|&x| DacCode(x).into()),
+                        );
+                    }
+
+                    telemetry.cpu_load.record(start);
+
+                    // Preserve instruction and data ordering w.r.t. DMA flag access.
+                    fence(Ordering::SeqCst);
+                });
+            },
+        );
+    }
+
+    #[idle(shared=[network], local=[watchdog])]
+    fn idle(mut c: idle::Context) -> ! {
+        loop {
+            c.local.watchdog.feed();
+
+            match c.shared.network.lock(|net| net.update()) {
+                NetworkState::SettingsChanged(_path) => {
+                    settings_update::spawn().unwrap();
+                }
+                NetworkState::Command(command) => {
+                    match command.channel("phase_scan", 2) {
+                        Some(channel) => match command.parse() {
+                            Ok(config) => {
+                                start_phase_scan::spawn(channel, config)
+                                    .unwrap()
+                            }
+                            Err(err) => log::warn!(
+                                "Invalid phase scan command: {:?}",
+                                err
+                            ),
+                        },
+                        None => log::warn!("Unknown command {}", command.name),
+                    }
+                }
+                NetworkState::Updated => {}
+                NetworkState::NoChange => cortex_m::asm::wfi(),
+            }
+        }
+    }
+
+    #[task(priority = 1, local=[afes, pounder], shared=[network, settings, lock_detect, dds])]
+    fn settings_update(mut c: settings_update::Context) {
+        let settings = c.shared.network.lock(|net| *net.miniconf.settings());
+        let previous = c
+            .shared
+            .settings
+            .lock(|current| core::mem::replace(current, settings));
+
+        c.local.afes.0.set_gain(settings.afe[0]);
+        c.local.afes.1.set_gain(settings.afe[1]);
+
+        for channel in 0..2 {
+            for (pounder_channel, attenuation) in [
+                (
+                    MODULATION[channel],
+                    settings.modulation_attenuation[channel],
+                ),
+                (DEMODULATION[channel], settings.input_attenuation[channel]),
+            ] {
+                if let Err(err) = c
+                    .local
+                    .pounder
+                    .set_attenuation(pounder_channel, attenuation)
+                {
+                    log::error!(
+                        "Failed to set attenuation of {:?}: {:?}",
+                        pounder_channel,
+                        err
+                    );
+                }
+            }
+
+            let result = c.shared.dds.lock(|dds| {
+                // Keep a calibrated phase until the demodulation phase is changed.
+                if settings.demodulation_phase[channel]
+                    != previous.demodulation_phase[channel]
+                {
+                    dds.phase[channel] = settings.demodulation_phase[channel];
+                }
+
+                dds.configure(
+                    channel,
+                    settings.modulation_frequency[channel],
+                    settings.modulation_amplitude[channel],
+                )
+            });
+
+            if let Err(err) = result {
+                log::error!(
+                    "Failed to configure the DDS of channel {}: {:?}",
+                    channel,
+                    err
+                );
+            }
+        }
+
+        // Update the lock detectors
+        c.shared.lock_detect.lock(|detectors| {
+            for (i, detector) in detectors.iter_mut().enumerate() {
+                detector.configure(
+                    &settings.lock_detect[i],
+                    settings.afe[i],
+                    SAMPLE_PERIOD,
+                );
+            }
+        });
+
+        c.shared
+            .network
+            .lock(|net| net.direct_stream(&settings.stream_target));
+    }
+
+    #[task(priority = 1, shared=[phase_scan, dds])]
+    fn start_phase_scan(
+        mut c: start_phase_scan::Context,
+        channel: usize,
+        config: PhaseScanConfig,
+    ) {
+        match c.shared.phase_scan.lock(|scan| {
+            scan[channel].start(&config, SAMPLE_PERIOD, BATCH_SIZE)
+        }) {
+            Ok(phase) => {
+                c.shared.dds.lock(|dds| dds.set_phase(channel, phase));
+                log::info!("Channel {} phase scan started", channel);
+            }
+            Err(err) => log::error!(
+                "Failed to start phase scan on channel {}: {:?}",
+                channel,
+                err
+            ),
+        }
+    }
+
+    #[task(priority = 1, capacity = 2, shared=[network, settings])]
+    fn phase_calibrated(
+        mut c: phase_calibrated::Context,
+        channel: usize,
+        phase: f32,
+        amplitude: i32,
+    ) {
+        let afe = c.shared.settings.lock(|settings| settings.afe[channel]);
+        let amplitude =
+            amplitude as f32 * AdcCode::VOLT_PER_LSB / afe.as_multiplier();
+
+        log::info!(
+            "Channel {} demodulation phase: {} turns ({} V peak-to-peak)",
+            channel,
+            phase,
+            amplitude
+        );
+        c.shared.network.lock(|net| {
+            let time = net.time_status().utc;
+            net.telemetry.publish_event(&PhaseCalibration {
+                channel,
+                phase,
+                amplitude,
+                time,
+            })
+        });
+    }
+
+    #[task(priority = 1, capacity = 4, shared=[network])]
+    fn lock_event(
+        mut c: lock_event::Context,
+        channel: usize,
+        state: LockState,
+    ) {
+        log::info!("Channel {} lock state: {:?}", channel, state);
+        c.shared.network.lock(|net| {
+            let time = net.time_status().utc;
+            net.telemetry.publish_event(&LockEvent {
+                channel,
+                state,
+                time,
+            })
+        });
+    }
+
+    #[task(priority = 1, shared=[network, settings, telemetry, lock_detect], local=[cpu_temp_sensor])]
+    fn telemetry(mut c: telemetry::Context) {
+        let telemetry: TelemetryBuffer = c.shared.telemetry.lock(|telemetry| {
+            let buffer = *telemetry;
+            telemetry.reset_statistics();
+            buffer
+        });
+
+        let lock = c
+            .shared
+            .lock_detect
+            .lock(|detectors| [detectors[0].state(), detectors[1].state()]);
+
+        let (gains, telemetry_period) = c
+            .shared
+            .settings
+            .lock(|settings| (settings.afe, settings.telemetry_period));
+
+        c.shared.network.lock(|net| {
+            // Locked channels inhibit resets into or out of the fallback network configuration.
+            net.set_controlling(lock.contains(&LockState::Locked));
+
+            let status = net.system_status();
+            net.telemetry.publish(&AppTelemetry {
+                telemetry: telemetry.finalize(
+                    gains[0],
+                    gains[1],
+                    c.local.cpu_temp_sensor.get_temperature().unwrap(),
+                    BATCH_SIZE as f32 * SAMPLE_PERIOD,
+                    status,
+                ),
+                lock,
+            })
+        });
+
+        // Schedule the telemetry task in the future.
+        telemetry::Monotonic::spawn_after((telemetry_period as u64).secs())
+            .unwrap();
+    }
+
+    #[task(priority = 1, shared=[network])]
+    fn ethernet_link(mut c: ethernet_link::Context) {
+        c.shared.network.lock(|net| net.processor.handle_link());
+        ethernet_link::Monotonic::spawn_after(1.secs()).unwrap();
+    }
+
+    #[task(binds = ETH, priority = 1)]
+    fn eth(_: eth::Context) {
+        unsafe { hal::ethernet::interrupt_handler() }
+    }
+
+    #[task(binds = SPI2, priority = 4)]
+    fn spi2(_: spi2::Context) {
+        panic!("ADC0 SPI error");
+    }
+
+    #[task(binds = SPI3, priority = 4)]
+    fn spi3(_: spi3::Context) {
+        panic!("ADC1 SPI error");
+    }
+
+    #[task(binds = SPI4, priority = 4)]
+    fn spi4(_: spi4::Context) {
+        panic!("DAC0 SPI error");
+    }
+
+    #[task(binds = SPI5, priority = 4)]
+    fn spi5(_: spi5::Context) {
+        panic!("DAC1 SPI error");
+    }
+}
@@ -0,0 +1,602 @@
+//! # Thermostat
+//!
+//! The Thermostat application stabilizes two temperatures, e.g. of laser diodes or nonlinear
+//! crystals. The voltage of a temperature sensor readout on each input is converted to
+//! temperature, and the output drives a heater or thermoelectric cooler (typically through a
+//! current driver) to keep the temperature at the setpoint.
+//!
+//! ## Features
+//! * Two independent channels
+//! * NTC thermistor (Steinhart-Hart), platinum RTD (PT100, PT1000) and linear sensor models
+//! * Divider and bridge readout of resistive sensors
+//! * PID controller with setpoint in Kelvin
+//! * Slew-rate limited setpoint changes
+//! * Output current/voltage limits with anti-windup
+//! * Sensor fault detection
+//! * Input/Output data streaming
+//!
+//! ## Operation
+//! The inputs are sampled at a low rate and averaged over the controller period, which is
+//! [CONTROL_PERIOD]. The temperature is computed from the averaged input voltage. The controller
+//! output is held on the DAC output between updates.
+//!
+//! Refer to [stabilizer::dsp::thermostat](../stabilizer/dsp/thermostat/index.html) for more
+//! information about the sensor models and the controller.
+//!
+//! ## Settings
+//! Refer to the [Settings] structure for documentation of run-time configurable settings for this
+//! application.
+//!
+//! ## Telemetry
+//! Refer to [AppTelemetry] for information about telemetry reported by this application.
+//!
+//! ## Livestreaming
+//! This application streams raw ADC and DAC data over UDP. Refer to
+//! [stabilizer::net::data_stream](../stabilizer/net/data_stream/index.html) for more information.
+#![deny(warnings)]
+#![no_std]
+#![no_main]
+
+use core::mem::MaybeUninit;
+use core::sync::atomic::{fence, Ordering};
+
+use fugit::ExtU64;
+use mutex_trait::prelude::*;
+
+use stabilizer::{
+    dsp::thermostat::{
+        OutputConfig, PidConfig, SensorConfig, Thermostat, ThermostatStatus,
+    },
+    hardware::{
+        self,
+        adc::{Adc0Input, Adc1Input, AdcCode},
+        afe::Gain,
+        cpu_load::CycleAccumulator,
+        dac::{Dac0Output, Dac1Output, DacCode},
+        hal,
+        timers::{SampleCounter, SamplingTimer, TimestampTimer},
+        watchdog::{self, Watchdog},
+        SystemTimer, Systick, AFE0, AFE1,
+    },
+    net::{
+        data_stream::{
+            FrameGenerator, StreamFormat, StreamTarget, STREAM_TARGETS,
+        },
+        miniconf::Miniconf,
+        serde::{ser::SerializeStruct, Serialize, Serializer},
+        telemetry::{Telemetry, TelemetryBuffer},
+        NetworkState, NetworkUsers,
+    },
+};
+
+// The number of samples in each batch process
+const BATCH_SIZE: usize = 8;
+
+// The logarithm of the number of 100MHz timer ticks between each sample. With a value of 2^12 =
+// 4096, there is 40.96uS per sample, corresponding to a sampling frequency of 24.41 KHz.
+const SAMPLE_TICKS_LOG2: u8 = 12;
+const SAMPLE_TICKS: u32 = 1 << SAMPLE_TICKS_LOG2;
+const SAMPLE_PERIOD: f32 =
+    SAMPLE_TICKS as f32 * hardware::design_parameters::TIMER_PERIOD;
+
+// The number of batches averaged for each controller update.
+const DECIMATION: usize = 16;
+
+/// The controller update period in seconds, approximately 5.24 ms.
+pub const CONTROL_PERIOD: f32 =
+    (DECIMATION * BATCH_SIZE) as f32 * SAMPLE_PERIOD;
+
+#[derive(Clone, Copy, Debug, Miniconf)]
+pub struct Settings {
+    /// Configure the Analog Front End (AFE) gain.
+    ///
+    /// # Path
+    /// `afe/<n>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// Any of the variants of [Gain] enclosed in double quotes.
+    afe: [Gain; 2],
+
+    /// Configure the temperature sensor of each input.
+    ///
+    /// # Path
+    /// `sensor/<n>/<field>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
+    /// * <field> specifies any field of [SensorConfig].
+    ///
+    /// # Value
+    /// See [SensorConfig#miniconf]
+    sensor: [SensorConfig; 2],
+
+    /// Specified true if the controller drives the output. While disabled, the output is zero
+    /// (limited to the output range).
+    ///
+    /// # Path
+    /// `enable/<n>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// "true" or "false"
+    enable: [bool; 2],
+
+    /// Specifies the temperature setpoint in Kelvin.
+    ///
+    /// # Path
+    /// `setpoint/<n>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// Any positive value.
+    setpoint: [f32; 2],
+
+    /// Specifies the maximum rate of change of the setpoint in Kelvin per second.
+    ///
+    /// # Path
+    /// `setpoint_slew/<n>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// Any non-negative value. Zero applies setpoint changes instantly.
+    setpoint_slew: [f32; 2],
+
+    /// Configure the PID controller gains.
+    ///
+    /// # Path
+    /// `pid/<n>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// See [PidConfig#miniconf]
+    pid: [PidConfig; 2],
+
+    /// Configure the output scaling and limits.
+    ///
+    /// # Path
+    /// `output/<n>`
+    ///
+    /// * <n> specifies which channel to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// See [OutputConfig#miniconf]
+    output: [OutputConfig; 2],
+
+    /// Specifies the telemetry output period in seconds.
+    ///
+    /// # Path
+    /// `telemetry_period`
+    ///
+    /// # Value
+    /// Any non-zero value less than 65536.
+    telemetry_period: u16,
+
+    /// Specifies the targets for data livestreaming.
+    ///
+    /// # Path
+    /// `stream_target/<n>`
+    ///
+    /// * <n> specifies which target to configure. <n> := [0, 1]
+    ///
+    /// # Value
+    /// See [StreamTarget#miniconf]
+    stream_target: [StreamTarget; STREAM_TARGETS],
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            // Analog frontend programmable gain amplifier gains (G1, G2, G5, G10)
+            afe: [Gain::G1, Gain::G1],
+            sensor: [SensorConfig::default(); 2],
+            // The controllers are disabled until configured.
+            enable: [false; 2],
+            // 25 °C
+            setpoint: [298.15; 2],
+            setpoint_slew: [0.; 2],
+            pid: [PidConfig::default(); 2],
+            output: [OutputConfig::default(); 2],
+            // The default telemetry period in seconds.
+            telemetry_period: 10,
+
+            stream_target: [StreamTarget::default(); STREAM_TARGETS],
+        }
+    }
+}
+
+/// The telemetry reported by the application: the fields of [Telemetry] followed by
+/// `thermostat`.
+pub struct AppTelemetry {
+    /// The device telemetry.
+    telemetry: Telemetry,
+
+    /// The temperature controller status of each channel.
+    thermostat: [ThermostatStatus; 2],
+}
+
+impl Serialize for AppTelemetry {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut state =
+            serializer.serialize_struct("Telemetry", Telemetry::FIELDS + 1)?;
+        self.telemetry.serialize_fields(&mut state)?;
+        state.serialize_field("thermostat", &self.thermostat)?;
+        state.end()
+    }
+}
+
+#[rtic::app(device = stabilizer::hardware::hal::stm32, peripherals = true, dispatchers=[DCMI, JPEG, LTDC, SDMMC])]
+mod app {
+    use super::*;
+
+    #[monotonic(binds = SysTick, default = true, priority = 2)]
+    type Monotonic = Systick;
+
+    #[shared]
+    struct Shared {
+        network: NetworkUsers<Settings, AppTelemetry>,
+
+        settings: Settings,
+        telemetry: TelemetryBuffer,
+        thermostat: [Thermostat; 2],
+    }
+
+    #[local]
+    struct Local {
+        sampling_timer: SamplingTimer,
+        timestamp_timer: TimestampTimer,
+        sample_counter: SampleCounter,
+        watchdog: Watchdog,
+        afes: (AFE0, AFE1),
+        adcs: (Adc0Input, Adc1Input),
+        dacs: (Dac0Output, Dac1Output),
+        accumulator: [i32; 2],
+        batches: usize,
+        outputs: [DacCode; 2],
+        generator: FrameGenerator,
+        cpu_temp_sensor: stabilizer::hardware::cpu_temp_sensor::CpuTempSensor,
+    }
+
+    #[init]
+    fn init(c: init::Context) -> (Shared, Local, init::Monotonics) {
+        let clock = SystemTimer::new(|| monotonics::now().ticks() as u32);
+
+        // Configure the microcontroller
+        let (stabilizer, pounder) = hardware::setup::setup(
+            c.core,
+            c.device,
+            clock,
+            BATCH_SIZE,
+            SAMPLE_TICKS,
+        );
+
+        let mut network = NetworkUsers::new(
+            stabilizer.net,
+            clock,
+            env!("CARGO_BIN_NAME"),
+            option_env!("NTP_SERVER")
+                .unwrap_or("10.34.16.10")
+                .parse()
+                .unwrap(),
+            stabilizer.reset_reason,
+            pounder.is_some(),
+            false,
+        );
+
+        let generator = network.configure_streaming(
+            StreamFormat::AdcDacData,
+            BATCH_SIZE as _,
+            SAMPLE_TICKS,
+        );
+
+        let shared = Shared {
+            network,
+            settings: Settings::default(),
+            telemetry: TelemetryBuffer::default(),
+            thermostat: [Thermostat::default(); 2],
+        };
+
+        let mut timestamp_timer = stabilizer.timestamp_timer;
+        let sample_counter = SampleCounter::new(timestamp_timer.counter());
+
+        let mut local = Local {
+            sampling_timer: stabilizer.adc_dac_timer,
+            timestamp_timer,
+            sample_counter,
+            watchdog: stabilizer.watchdog,
+            afes: stabilizer.afes,
+            adcs: stabilizer.adcs,
+            dacs: stabilizer.dacs,
+            accumulator: [0; 2],
+            batches: 0,
+            outputs: [DacCode(0); 2],
+            generator,
+            cpu_temp_sensor: stabilizer.temperature_sensor,
+        };
+
+        // Enable ADC/DAC events
+        local.adcs.0.start();
+        local.adcs.1.start();
+        local.dacs.0.start();
+        local.dacs.1.start();
+
+        // Spawn a settings update for default settings.
+        settings_update::spawn().unwrap();
+        telemetry::spawn().unwrap();
+        ethernet_link::spawn().unwrap();
+        start::spawn_after(100.millis()).unwrap();
+
+        (shared, local, init::Monotonics(stabilizer.systick))
+    }
+
+    #[task(priority = 1, local=[sampling_timer, timestamp_timer])]
+    fn start(c: start::Context) {
+        cortex_m::interrupt::free(|_| {
+            // Start counting sampling timer ticks for the stream timestamps.
+            c.local.timestamp_timer.start();
+
+            // Start sampling ADCs and DACs.
+            c.local.sampling_timer.start();
+        });
+    }
+
+    /// Main DSP processing routine.
+    ///
+    /// # Note
+    /// The input samples are accumulated on every batch. Once every [DECIMATION] batches, the
+    /// averaged inputs are converted to temperature and the controllers are updated.
+    ///
+    /// DSP application code must be completed by the time the next batch of ADC samples has been
+    /// acquired and must fill out the next DAC output buffer in time. If this constraint is not
+    /// met, firmware will panic due to an ADC input overrun.
+    #[task(binds=DMA1_STR4, local=[sample_counter, adcs, dacs, accumulator, batches, outputs, generator], shared=[settings, telemetry, thermostat], priority=3)]
+    #[link_section = ".itcm.process"]
+    fn process(c: process::Context) {
+        let start = CycleAccumulator::start();
+        watchdog::check_in();
+
+        let process::SharedResources {
+            settings,
+            telemetry,
+            thermostat,
+        } = c.shared;
+
+        let process::LocalResources {
+            sample_counter,
+            adcs: (adc0, adc1),
+            dacs: (dac0, dac1),
+            accumulator,
+            batches,
+            outputs,
+            generator,
+        } = c.local;
+
+        // Latch the sampling timer count for the stream timestamp.
+        let timestamp = sample_counter.latch();
+
+        (settings, telemetry, thermostat).lock(
+            |settings, telemetry, thermostat| {
+                (adc0, adc1, dac0, dac1).lock(|adc0, adc1, dac0, dac1| {
+                    let adc_samples = [adc0, adc1];
+                    let dac_samples = [dac0, dac1];
+
+                    // Preserve instruction and data ordering w.r.t. DMA flag access.
+                    fence(Ordering::SeqCst);
+
+                    for (accu, adc) in
+                        accumulator.iter_mut().zip(adc_samples.iter())
+                    {
+                        *accu +=
+                            adc.iter().map(|&x| x as i16 as i32).sum::<i32>();
+                    }
+
+                    *batches += 1;
+                    if *batches == DECIMATION {
+                        for channel in 0..adc_samples.len() {
+                            let code = accumulator[channel] as f32
+                                / (DECIMATION * BATCH_SIZE) as f32;
+                            let voltage = code * AdcCode::VOLT_PER_LSB
+                                / settings.afe[channel].as_multiplier();
+
+                            let thermostat = &mut thermostat[channel];
+                            thermostat.update(
+                                settings.sensor[channel].temperature(voltage),
+                            );
+
+                            // The conversion saturates the output to the DAC range.
+                            outputs[channel] = DacCode::from(
+                                (thermostat.voltage() * DacCode::LSB_PER_VOLT)
+                                    as i16,
+                            );
+                        }
+
+                        *accumulator = [0; 2];
+                        *batches = 0;
+                    }
+
+                    for (dac, output) in
+                        dac_samples.iter_mut().zip(outputs.iter())
+                    {
+                        dac.fill(output.0);
+                    }
+
+                    // Stream the data.
+                    const N: usize = BATCH_SIZE * core::mem::size_of::<i16>()
+                        / core::mem::size_of::<MaybeUninit<u8>>();
+                    generator.add::<_, { N * 4 }>(timestamp, |buf| {
+                        for (data, buf) in adc_samples
+                            .iter()
+                            .chain(dac_samples.iter())
+                            .zip(buf.chunks_exact_mut(N))
+                        {
+                            let data = unsafe {
+                                core::slice::from_raw_parts(
+                                    data.as_ptr() as *const MaybeUninit<u8>,
+                                    N,
+                                )
+                            };
+                            buf.copy_from_slice(data)
+                        }
+                    });
+
+                    // Update telemetry measurements.
+                    telemetry.adcs = [
+                        AdcCode(adc_samples[0][0]),
+                        AdcCode(adc_samples[1][0]),
+                    ];
+
+                    telemetry.dacs = [
+                        DacCode(dac_samples[0][0]),
+                        DacCode(dac_samples[1][0]),
+                    ];
+
+                    for channel in 0..adc_samples.len() {
+                        telemetry.adc_statistics[channel].update(
+                            adc_samples[channel]
+                                .iter()
+                                .map(|&x| AdcCode(x).into()),
+                        );
+                        telemetry.dac_statistics[channel].update(
+                            dac_samples[channel]
+                                .iter()
+                                .map(|&x| DacCode(x).into()),
+                        );
+                    }
+
+                    telemetry.cpu_load.record(start);
+
+                    // Preserve instruction and data ordering w.r.t. DMA flag access.
+                    fence(Ordering::SeqCst);
+                });
+            },
+        );
+    }
+
+    #[idle(shared=[network], local=[watchdog])]
+    fn idle(mut c: idle::Context) -> ! {
+        loop {
+            c.local.watchdog.feed();
+
+            match c.shared.network.lock(|net| net.update()) {
+                NetworkState::SettingsChanged(_path) => {
+                    settings_update::spawn().unwrap()
+                }
+                NetworkState::Command(command) => {
+                    log::warn!("Unknown command {}", command.name)
+                }
+                NetworkState::Updated => {}
+                NetworkState::NoChange => cortex_m::asm::wfi(),
+            }
+        }
+    }
+
+    #[task(priority = 1, local=[afes], shared=[network, settings, thermostat])]
+    fn settings_update(mut c: settings_update::Context) {
+        let settings = c.shared.network.lock(|net| *net.miniconf.settings());
+        c.shared.settings.lock(|current| *current = settings);
+
+        c.local.afes.0.set_gain(settings.afe[0]);
+        c.local.afes.1.set_gain(settings.afe[1]);
+
+        // Update the controllers
+        for channel in 0..2 {
+            if let Err(err) = c.shared.thermostat.lock(|thermostat| {
+                thermostat[channel].configure(
+                    settings.enable[channel],
+                    settings.setpoint[channel],
+                    settings.setpoint_slew[channel],
+                    &settings.pid[channel],
+                    &settings.output[channel],
+                    CONTROL_PERIOD,
+                )
+            }) {
+                log::error!(
+                    "Failed to configure the controller of channel {}: {:?}",
+                    channel,
+                    err
+                );
+            }
+        }
+
+        c.shared
+            .network
+            .lock(|net| net.direct_stream(&settings.stream_target));
+    }
+
+    #[task(priority = 1, shared=[network, settings, telemetry, thermostat], local=[cpu_temp_sensor])]
+    fn telemetry(mut c: telemetry::Context) {
+        let telemetry: TelemetryBuffer = c.shared.telemetry.lock(|telemetry| {
+            let buffer = *telemetry;
+            telemetry.reset_statistics();
+            buffer
+        });
+
+        let thermostat = c.shared.thermostat.lock(|thermostat| {
+            [thermostat[0].status(), thermostat[1].status()]
+        });
+
+        let (gains, telemetry_period, enable) =
+            c.shared.settings.lock(|settings| {
+                (settings.afe, settings.telemetry_period, settings.enable)
+            });
+
+        c.shared.network.lock(|net| {
+            // Enabled controllers inhibit resets into or out of the fallback network configuration.
+            net.set_controlling(enable.contains(&true));
+
+            let status = net.system_status();
+            net.telemetry.publish(&AppTelemetry {
+                telemetry: telemetry.finalize(
+                    gains[0],
+                    gains[1],
+                    c.local.cpu_temp_sensor.get_temperature().unwrap(),
+                    BATCH_SIZE as f32 * SAMPLE_PERIOD,
+                    status,
+                ),
+                thermostat,
+            })
+        });
+
+        // Schedule the telemetry task in the future.
+        telemetry::Monotonic::spawn_after((telemetry_period as u64).secs())
+            .unwrap();
+    }
+
+    #[task(priority = 1, shared=[network])]
+    fn ethernet_link(mut c: ethernet_link::Context) {
+        c.shared.network.lock(|net| net.processor.handle_link());
+        ethernet_link::Monotonic::spawn_after(1.secs()).unwrap();
+    }
+
+    #[task(binds = ETH, priority = 1)]
+    fn eth(_: eth::Context) {
+        unsafe { hal::ethernet::interrupt_handler() }
+    }
+
+    #[task(binds = SPI2, priority = 4)]
+    fn spi2(_: spi2::Context) {
+        panic!("ADC0 SPI error");
+    }
+
+    #[task(binds = SPI3, priority = 4)]
+    fn spi3(_: spi3::Context) {
+        panic!("ADC1 SPI error");
+    }
+
+    #[task(binds = SPI4, priority = 4)]
+    fn spi4(_: spi4::Context) {
+        panic!("DAC0 SPI error");
+    }
+
+    #[task(binds = SPI5, priority = 4)]
+    fn spi5(_: spi5::Context) {
+        panic!("DAC1 SPI error");
+    }
+}
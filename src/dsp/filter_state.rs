@@ -0,0 +1,153 @@
+///! IIR filter state manipulation
+///!
+///! # Design
+///! The state of each [iir::IIR] biquad is kept in direct form I as the history of its inputs and
+///! outputs. After an update, the state of a biquad is `[x0, x1, y0, y1, y2]`, where `x0` and `y0`
+///! are the latest input and output.
+///!
+///! Changing the coefficients of a running filter generally causes a jump of the output, as the
+///! output history was accumulated with the previous coefficients. A bumpless transfer rewrites the
+///! output history of each biquad such that, for an unchanged input, the next output computed
+///! with the new coefficients equals the latest output. The filter then evolves from the current
+///! output according to the new coefficients.
+///!
+///! Additionally, the filter state can be reset or preset to a given output by a command, e.g. to
+///! discharge a wound-up integrator or to start a lock from a known output. As a retained setting
+///! would be applied again upon every connection to the broker, the action is not a setting.
+use idsp::iir;
+use serde::{Deserialize, Serialize};
+
+use crate::hardware::dac::DacCode;
+
+// The minimum feedback `a1 + a2` of a biquad for a bumpless transfer. Smaller feedback would
+// amplify the output history without bound.
+const MIN_FEEDBACK: f32 = 1e-6;
+
+/// An action on the filter state of a channel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StateAction {
+    /// Leave the filter state unchanged.
+    None,
+    /// Clear the filter state.
+    Reset,
+    /// Preset the filter state such that the filter continues from the configured output.
+    Preset,
+}
+
+/// Configuration of a filter state action.
+///
+/// # Command
+/// `{"action": <action>, "output": <output>}`
+///
+/// * `<action>` is any of the [StateAction] variants enclosed in double quotes.
+/// * `<output>` is the output voltage to preset the filter to. It is limited to the output range
+///   of the filter.
+///
+/// Every command applies the action once.
+///
+/// ## Example
+/// `{"action": "Preset", "output": 1.5}`
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StateConfig {
+    pub action: StateAction,
+    pub output: f32,
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        Self {
+            action: StateAction::None,
+            output: 0.,
+        }
+    }
+}
+
+impl StateConfig {
+    /// Apply the configured action to the state of a filter cascade.
+    ///
+    /// # Args
+    /// * `iir` - The filter cascade.
+    /// * `state` - The state of the filter cascade.
+    pub fn apply(&self, iir: &[iir::IIR<f32>], state: &mut [iir::Vec5<f32>]) {
+        match self.action {
+            StateAction::None => {}
+            StateAction::Reset => reset(state),
+            StateAction::Preset => {
+                if let (Some(iir), Some(last)) = (iir.last(), state.last()) {
+                    let y = (self.output * DacCode::LSB_PER_VOLT)
+                        .max(iir.y_min)
+                        .min(iir.y_max);
+                    let x = last[0];
+                    preset(state, x, y);
+                }
+            }
+        }
+    }
+}
+
+/// Clear the state of a filter cascade.
+///
+/// # Args
+/// * `state` - The state of the filter cascade.
+pub fn reset(state: &mut [iir::Vec5<f32>]) {
+    for stage in state.iter_mut() {
+        *stage = [0.; 5];
+    }
+}
+
+/// Preset the filter state for bumpless transfer.
+///
+/// # Note
+/// The output stage is preset such that the next output continues from the current output. Any
+/// preceding cascade stages are cleared.
+///
+/// # Args
+/// * `state` - The state of the filter cascade.
+/// * `x` - The current filter input.
+/// * `y` - The current output.
+pub fn preset(state: &mut [iir::Vec5<f32>], x: f32, y: f32) {
+    if let Some((last, stages)) = state.split_last_mut() {
+        reset(stages);
+        *last = [x, x, y, y, y];
+    }
+}
+
+/// Check whether the coefficients or the offset of two filter cascades differ.
+///
+/// # Note
+/// Changes of the output limits do not affect the filter state and are ignored.
+pub fn changed(old: &[iir::IIR<f32>], new: &[iir::IIR<f32>]) -> bool {
+    old.iter()
+        .zip(new.iter())
+        .any(|(old, new)| old.ba != new.ba || old.y_offset != new.y_offset)
+}
+
+/// Rewrite the state of a filter cascade for a bumpless transfer to new coefficients.
+///
+/// # Note
+/// Biquads without feedback (`|a1 + a2|` below 1e-6) have no output history to absorb the change
+/// and are left unchanged. The rewritten output history is limited to the output range of the
+/// biquad (`y_min`/`y_max`), so the transfer is not bumpless if the required history lies outside
+/// of it. While the filter is held, the held output follows the rewritten history.
+///
+/// # Args
+/// * `iir` - The new filter cascade.
+/// * `state` - The state of the filter cascade accumulated with the previous coefficients.
+pub fn transfer(iir: &[iir::IIR<f32>], state: &mut [iir::Vec5<f32>]) {
+    for (iir, state) in iir.iter().zip(state.iter_mut()) {
+        let [b0, b1, b2, a1, a2] = iir.ba;
+        let [x0, x1, y0, _, _] = *state;
+
+        let feedback = a1 + a2;
+        if feedback.abs() < MIN_FEEDBACK {
+            continue;
+        }
+
+        // Solve `y0 = (b0 + b1) * x0 + b2 * x1 + (a1 + a2) * y + y_offset` for a uniform output
+        // history `y`, i.e. the next output for an unchanged input `x0`.
+        let y = ((y0 - (b0 + b1) * x0 - b2 * x1 - iir.y_offset) / feedback)
+            .max(iir.y_min)
+            .min(iir.y_max);
+        state[2..].fill(y);
+    }
+}
@@ -0,0 +1,233 @@
+///! Lock detection for feedback loops
+///!
+///! # Design
+///! The lock detector monitors the error signal (the filter input) and the output of a feedback
+///! loop filter on every sample. The loop is considered:
+///!
+///! * **Railed** if the filter output stays within a margin of its limits (`y_min`/`y_max`) for
+///!   the unlock time.
+///! * **Locked** if the magnitude of the error signal stays below a threshold, without the output
+///!   being railed, for the lock time.
+///! * **Unlocked** if the magnitude of the error signal stays above the threshold for the unlock
+///!   time.
+///!
+///! Otherwise, the previous state is retained. This debounces the state against noise and short
+///! excursions. Upon losing lock, the detector can optionally request the filter to be held or its
+///! state to be reset for reacquisition.
+use idsp::iir;
+use miniconf::MiniconfAtomic;
+use serde::{Deserialize, Serialize};
+
+use crate::hardware::{adc::AdcCode, afe::Gain, dac::DacCode};
+
+/// The state of a feedback loop.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LockState {
+    /// The error signal exceeds the threshold.
+    Unlocked,
+    /// The error signal is within the threshold.
+    Locked,
+    /// The filter output is at its limits.
+    Railed,
+}
+
+/// The action taken upon losing lock.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LockAction {
+    /// Only report the state.
+    None,
+    /// Hold the filter output after a transition from [LockState::Locked] until the lock detection
+    /// configuration is updated.
+    Hold,
+    /// Reset the filter state (e.g. a wound-up integrator) to reacquire lock after a transition
+    /// from [LockState::Locked] to [LockState::Unlocked] and upon any transition to
+    /// [LockState::Railed].
+    Reset,
+}
+
+/// Configuration of a lock detector.
+///
+/// # Miniconf
+/// `{"threshold": <threshold>, "margin": <margin>, "lock_time": <lock>, "unlock_time": <unlock>,
+/// "action": <action>}`
+///
+/// * `<threshold>` is the error signal threshold in volts at the input (including the AFE gain).
+/// * `<margin>` is the margin to the output limits in volts below which the output is considered
+///   railed.
+/// * `<lock>` is the time in seconds the error signal has to stay within the threshold to
+///   consider the loop locked.
+/// * `<unlock>` is the time in seconds the error signal has to exceed the threshold (or the output
+///   has to be railed) to consider the loop unlocked (or railed).
+/// * `<action>` is any of the [LockAction] variants enclosed in double quotes.
+///
+/// Every update of the configuration resets the detector to [LockState::Unlocked] and releases a
+/// hold.
+///
+/// ## Example
+/// `{"threshold": 0.1, "margin": 0.01, "lock_time": 0.1, "unlock_time": 0.001, "action": "Hold"}`
+#[derive(
+    Copy, Clone, Debug, PartialEq, MiniconfAtomic, Serialize, Deserialize,
+)]
+pub struct LockDetectConfig {
+    pub threshold: f32,
+    pub margin: f32,
+    pub lock_time: f32,
+    pub unlock_time: f32,
+    pub action: LockAction,
+}
+
+impl Default for LockDetectConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 0.1,
+            margin: 0.01,
+            lock_time: 0.1,
+            unlock_time: 0.001,
+            action: LockAction::None,
+        }
+    }
+}
+
+/// A lock state transition event.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct LockEvent {
+    /// The channel of the feedback loop.
+    pub channel: usize,
+    /// The new lock state.
+    pub state: LockState,
+    /// The UTC time of the event report in milliseconds since the Unix epoch, if known.
+    pub time: Option<u64>,
+}
+
+/// A lock detector for a single feedback loop.
+#[derive(Copy, Clone, Debug)]
+pub struct LockDetector {
+    config: LockDetectConfig,
+    threshold: f32,
+    margin: f32,
+    lock_samples: u32,
+    unlock_samples: u32,
+    within: u32,
+    outside: u32,
+    railed: u32,
+    state: LockState,
+    lost: bool,
+    reset: bool,
+}
+
+impl Default for LockDetector {
+    fn default() -> Self {
+        Self {
+            config: LockDetectConfig::default(),
+            threshold: 0.,
+            margin: 0.,
+            lock_samples: u32::MAX,
+            unlock_samples: u32::MAX,
+            within: 0,
+            outside: 0,
+            railed: 0,
+            state: LockState::Unlocked,
+            lost: false,
+            reset: false,
+        }
+    }
+}
+
+impl LockDetector {
+    /// Configure the lock detector.
+    ///
+    /// # Note
+    /// The detector state is only reset if the configuration changed. AFE gain changes only update
+    /// the threshold.
+    ///
+    /// # Args
+    /// * `config` - The lock detection configuration.
+    /// * `afe` - The AFE gain of the input channel used to convert the threshold.
+    /// * `sample_period` - The time in seconds between samples.
+    pub fn configure(
+        &mut self,
+        config: &LockDetectConfig,
+        afe: Gain,
+        sample_period: f32,
+    ) {
+        self.threshold =
+            config.threshold * afe.as_multiplier() * AdcCode::LSB_PER_VOLT;
+        self.margin = config.margin * DacCode::LSB_PER_VOLT;
+        self.lock_samples = (config.lock_time / sample_period) as u32;
+        self.unlock_samples = (config.unlock_time / sample_period) as u32;
+
+        if *config != self.config {
+            self.config = *config;
+            self.within = 0;
+            self.outside = 0;
+            self.railed = 0;
+            self.state = LockState::Unlocked;
+            self.lost = false;
+            self.reset = false;
+        }
+    }
+
+    /// Get the current lock state.
+    pub fn state(&self) -> LockState {
+        self.state
+    }
+
+    /// Check whether the filter output should be held.
+    pub fn hold(&self) -> bool {
+        self.lost && self.config.action == LockAction::Hold
+    }
+
+    /// Update the lock detector with a sample.
+    ///
+    /// # Args
+    /// * `x` - The error signal (the filter input) in ADC codes.
+    /// * `y` - The filter output in DAC codes.
+    /// * `iir` - The output filter stage defining the output limits.
+    pub fn update(&mut self, x: f32, y: f32, iir: &iir::IIR<f32>) {
+        let railed =
+            y <= iir.y_min + self.margin || y >= iir.y_max - self.margin;
+        let within = -self.threshold <= x && x <= self.threshold;
+
+        self.railed = if railed {
+            self.railed.saturating_add(1)
+        } else {
+            0
+        };
+        self.within = if within && !railed {
+            self.within.saturating_add(1)
+        } else {
+            0
+        };
+        self.outside = if within {
+            0
+        } else {
+            self.outside.saturating_add(1)
+        };
+
+        let state = if self.railed > self.unlock_samples {
+            LockState::Railed
+        } else if self.within > self.lock_samples {
+            LockState::Locked
+        } else if self.outside > self.unlock_samples {
+            LockState::Unlocked
+        } else {
+            self.state
+        };
+
+        if state == self.state {
+            return;
+        }
+
+        let lost = self.state == LockState::Locked;
+        self.lost |= lost;
+        self.reset = self.config.action == LockAction::Reset
+            && (lost || state == LockState::Railed);
+
+        self.state = state;
+    }
+
+    /// Check whether the filter state should be reset, clearing the request.
+    pub fn take_reset(&mut self) -> bool {
+        core::mem::take(&mut self.reset)
+    }
+}
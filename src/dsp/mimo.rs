@@ -0,0 +1,83 @@
+///! Cross-channel mixing of a two-channel filter
+///!
+///! # Design
+///! By default, the two channels of a dual filter are independent. To allow feed-forward between
+///! actuators (e.g. offloading a fast piezo to a slow actuator) and decoupling of coupled plants,
+///! the channels are combined by three configurable mixing stages:
+///!
+///! * The filter input of each channel is a linear combination of both ADC inputs.
+///! * The output of the respective other filter is added to the filter input with a cross gain.
+///! * The output of each channel is a linear combination of both filter outputs.
+///!
+///! All weights are dimensionless and apply to ADC and DAC codes.
+///!
+///! # Note
+///! The filters are processed one channel after the other. Channel 1 is therefore fed the output of
+///! channel 0 of the same sample, whereas channel 0 is fed the output of channel 1 of the same
+///! sample in the previous batch.
+use miniconf::MiniconfAtomic;
+use serde::{Deserialize, Serialize};
+
+/// Configuration of the cross-channel mixing.
+///
+/// # Miniconf
+/// `{"input": [[<i00>, <i01>], [<i10>, <i11>]], "cross": [<c0>, <c1>], "output": [[<o00>, <o01>],
+/// [<o10>, <o11>]]}`
+///
+/// * `<inm>` is the weight of ADC input `m` in the filter input of channel `n`.
+/// * `<cn>` is the weight of the output of the respective other filter in the filter input of
+///   channel `n`.
+/// * `<onm>` is the weight of the output of filter `m` in the output of channel `n`.
+///
+/// The default configuration keeps the channels independent.
+///
+/// ## Example
+/// Feed the output of channel 0 to the input of channel 1 (instead of ADC1) to offload it:
+/// `{"input": [[1.0, 0.0], [0.0, 0.0]], "cross": [0.0, 1.0], "output": [[1.0, 0.0], [0.0, 1.0]]}`
+#[derive(
+    Copy, Clone, Debug, PartialEq, MiniconfAtomic, Serialize, Deserialize,
+)]
+pub struct MimoConfig {
+    pub input: [[f32; 2]; 2],
+    pub cross: [f32; 2],
+    pub output: [[f32; 2]; 2],
+}
+
+impl Default for MimoConfig {
+    fn default() -> Self {
+        Self {
+            input: [[1., 0.], [0., 1.]],
+            cross: [0., 0.],
+            output: [[1., 0.], [0., 1.]],
+        }
+    }
+}
+
+impl MimoConfig {
+    /// Compute the filter input of a channel.
+    ///
+    /// # Args
+    /// * `channel` - The channel of the filter.
+    /// * `x` - The ADC inputs of both channels in ADC codes.
+    /// * `y` - The output of the other filter in DAC codes.
+    ///
+    /// # Returns
+    /// The filter input.
+    pub fn input(&self, channel: usize, x: [f32; 2], y: f32) -> f32 {
+        let [w0, w1] = self.input[channel];
+        w0 * x[0] + w1 * x[1] + self.cross[channel] * y
+    }
+
+    /// Compute the output of a channel.
+    ///
+    /// # Args
+    /// * `channel` - The output channel.
+    /// * `y` - The filter outputs of both channels in DAC codes.
+    ///
+    /// # Returns
+    /// The output in DAC codes.
+    pub fn output(&self, channel: usize, y: [f32; 2]) -> f32 {
+        let [w0, w1] = self.output[channel];
+        w0 * y[0] + w1 * y[1]
+    }
+}
@@ -0,0 +1,13 @@
+///! Stabilizer signal processing utilities
+///!
+///! # Design
+///! This module contains signal processing building blocks that are shared between applications
+///! but are specific to Stabilizer, e.g. because they operate on Stabilizer settings or codes.
+///! Generic signal processing primitives are provided by the `idsp` crate.
+pub mod filter_state;
+pub mod lock_detect;
+pub mod mimo;
+pub mod phase_scan;
+pub mod relock;
+pub mod slew;
+pub mod thermostat;
@@ -0,0 +1,259 @@
+///! Demodulation phase calibration by phase scan
+///!
+///! # Design
+///! The error signal of a heterodyne detection scheme (e.g. Pound-Drever-Hall) depends on the phase
+///! of the demodulation reference relative to the modulation. The optimum demodulation phase
+///! maximizes the error signal amplitude and its slope at the lock point.
+///!
+///! During a phase scan, the loop filter is disengaged and the output is swept across the resonance
+///! with a triangular ramp generated by a [SignalGenerator] around a configured center. The
+///! demodulation phase is stepped over half a turn in [STEPS] steps. For each step, the
+///! peak-to-peak amplitude of the error signal is recorded over the dwell time, which should cover
+///! at least one sweep period. The phase with the largest amplitude is selected.
+///!
+///! # Note
+///! The error signal amplitude is periodic with half a turn of the demodulation phase. Adding half
+///! a turn to the selected phase inverts the sign of the error signal, which can equivalently be
+///! accounted for by the sign of the loop filter gain.
+///!
+///! A scan is started by a command rather than a setting. A retained setting would be applied again
+///! upon every connection to the broker and disengage the loop filter of a locked channel.
+use serde::{Deserialize, Serialize};
+
+use crate::hardware::{
+    dac::DacCode,
+    signal_generator::{self, BasicConfig, Signal, SignalGenerator},
+};
+
+/// The number of demodulation phase steps over half a turn.
+pub const STEPS: usize = 32;
+
+/// Configuration of a phase scan.
+///
+/// # Command
+/// `{"center": <center>, "amplitude": <amplitude>, "frequency": <frequency>, "dwell": <dwell>}`
+///
+/// * `<center>` is the sweep center output voltage.
+/// * `<amplitude>` is the sweep amplitude in volts, i.e. half of the swept range.
+/// * `<frequency>` is the sweep frequency in Hertz.
+/// * `<dwell>` is the time in seconds the error signal is recorded for each phase step.
+///
+/// ## Example
+/// `{"center": 0.0, "amplitude": 1.0, "frequency": 20.0, "dwell": 0.1}`
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PhaseScanConfig {
+    pub center: f32,
+    pub amplitude: f32,
+    pub frequency: f32,
+    pub dwell: f32,
+}
+
+impl Default for PhaseScanConfig {
+    fn default() -> Self {
+        Self {
+            center: 0.,
+            amplitude: 1.,
+            frequency: 20.,
+            dwell: 0.1,
+        }
+    }
+}
+
+/// Represents the errors that can occur when starting a phase scan.
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    /// The sweep could not be configured.
+    Sweep(signal_generator::Error),
+    /// The sweep range exceeds the output range.
+    InvalidRange,
+}
+
+/// The result of a phase scan step.
+#[derive(Copy, Clone, Debug)]
+pub enum ScanStep {
+    /// The demodulation phase in turns to record the next step with.
+    Phase(f32),
+    /// The scan completed.
+    Done {
+        /// The optimum demodulation phase in turns.
+        phase: f32,
+        /// The peak-to-peak error signal amplitude at the optimum phase in ADC codes.
+        amplitude: i32,
+    },
+}
+
+/// A phase calibration result event.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct PhaseCalibration {
+    /// The calibrated channel.
+    pub channel: usize,
+    /// The optimum demodulation phase in turns.
+    pub phase: f32,
+    /// The peak-to-peak error signal amplitude at the optimum phase in volts at the input
+    /// (including the AFE gain).
+    pub amplitude: f32,
+    /// The UTC time of the event report in milliseconds since the Unix epoch, if known.
+    pub time: Option<u64>,
+}
+
+/// The phase scan state machine of a single channel.
+#[derive(Debug)]
+pub struct PhaseScan {
+    sweep: SignalGenerator,
+    center: f32,
+    active: bool,
+    settling: bool,
+    step: usize,
+    dwell_batches: u32,
+    remaining: u32,
+    min: i16,
+    max: i16,
+    best: (usize, i32),
+}
+
+impl Default for PhaseScan {
+    fn default() -> Self {
+        Self {
+            sweep: SignalGenerator::default(),
+            center: 0.,
+            active: false,
+            settling: false,
+            step: 0,
+            dwell_batches: 1,
+            remaining: 0,
+            min: i16::MAX,
+            max: i16::MIN,
+            best: (0, i32::MIN),
+        }
+    }
+}
+
+impl PhaseScan {
+    /// Start a phase scan.
+    ///
+    /// # Args
+    /// * `config` - The phase scan configuration.
+    /// * `sample_period` - The time in seconds between samples.
+    /// * `batch_size` - The number of samples per batch.
+    ///
+    /// # Returns
+    /// The demodulation phase in turns to record the first step with.
+    pub fn start(
+        &mut self,
+        config: &PhaseScanConfig,
+        sample_period: f32,
+        batch_size: usize,
+    ) -> Result<f32, Error> {
+        if (config.center - config.amplitude) < -DacCode::FULL_SCALE
+            || (config.center + config.amplitude) > DacCode::FULL_SCALE
+        {
+            return Err(Error::InvalidRange);
+        }
+
+        // Start the sweep at its center.
+        let sweep = BasicConfig {
+            signal: Signal::Triangle,
+            frequency: config.frequency,
+            symmetry: 0.5,
+            amplitude: config.amplitude,
+            phase: 0.25,
+        }
+        .try_into_config(sample_period, DacCode::FULL_SCALE)
+        .map_err(Error::Sweep)?;
+
+        self.sweep.update_waveform(sweep);
+        self.sweep.clear_phase_accumulator();
+        self.center = config.center * DacCode::LSB_PER_VOLT;
+        self.dwell_batches =
+            ((config.dwell / (sample_period * batch_size as f32)) as u32)
+                .max(1);
+        self.best = (0, i32::MIN);
+        self.step = 0;
+        self.next_step();
+        self.active = true;
+
+        Ok(phase(self.step))
+    }
+
+    /// Check whether a scan is in progress and determines the output.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Get the sweep center in DAC codes.
+    pub fn center(&self) -> f32 {
+        self.center
+    }
+
+    /// Generate the next output sample while scanning.
+    ///
+    /// # Returns
+    /// The output in DAC codes.
+    pub fn sweep(&mut self) -> i16 {
+        // Note(unwrap): The signal generator is an infinite iterator.
+        (self.center + self.sweep.next().unwrap() as f32)
+            .max(i16::MIN as f32)
+            .min(i16::MAX as f32) as i16
+    }
+
+    fn next_step(&mut self) {
+        self.min = i16::MAX;
+        self.max = i16::MIN;
+        self.remaining = self.dwell_batches;
+        self.settling = true;
+    }
+
+    /// Record the error signal once per batch.
+    ///
+    /// # Args
+    /// * `samples` - The error signal samples of the batch in ADC codes.
+    ///
+    /// # Returns
+    /// The next demodulation phase to set or the scan result when a step completed.
+    pub fn update(
+        &mut self,
+        samples: impl Iterator<Item = i16>,
+    ) -> Option<ScanStep> {
+        if !self.active {
+            return None;
+        }
+
+        // The phase update takes effect during the first batch of each step, which is discarded.
+        if self.settling {
+            self.settling = false;
+            return None;
+        }
+
+        for x in samples {
+            self.min = self.min.min(x);
+            self.max = self.max.max(x);
+        }
+
+        self.remaining -= 1;
+        if self.remaining > 0 {
+            return None;
+        }
+
+        let amplitude = self.max as i32 - self.min as i32;
+        if amplitude > self.best.1 {
+            self.best = (self.step, amplitude);
+        }
+
+        self.step += 1;
+        if self.step < STEPS {
+            self.next_step();
+            Some(ScanStep::Phase(phase(self.step)))
+        } else {
+            self.active = false;
+            Some(ScanStep::Done {
+                phase: phase(self.best.0),
+                amplitude: self.best.1,
+            })
+        }
+    }
+}
+
+/// Get the demodulation phase in turns of a scan step.
+fn phase(step: usize) -> f32 {
+    step as f32 * 0.5 / STEPS as f32
+}
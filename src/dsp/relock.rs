@@ -0,0 +1,334 @@
+///! Automatic relocking of feedback loops by sweep-and-catch
+///!
+///! # Design
+///! When the lock detector reports a loss of lock (a transition to
+///! [LockState::Unlocked](super::lock_detect::LockState::Unlocked) or
+///! [LockState::Railed](super::lock_detect::LockState::Railed)), the relock state machine
+///! disengages the loop filter and sweeps the output with a triangular ramp generated by a
+///! [SignalGenerator] around a configured center.
+///!
+///! During the sweep, the error signal is watched for a capture condition: either the error
+///! magnitude falling below a threshold or the error crossing a level with a given slope. Upon
+///! capture, the filter is re-engaged with bumpless transfer, i.e. its state is preset (see
+///! [super::filter_state::preset]) such that the output continues from the current sweep value.
+///!
+///! After re-engaging, the loop has to be reported as locked within the settle time. Otherwise, a
+///! new sweep is started. As the lock detector only reports lock once the error signal stayed
+///! within its threshold for the lock time, the settle time has to exceed the lock time. After the
+///! maximum number of consecutive failed attempts, relocking is given up and the output is held at
+///! the sweep center until the configuration is updated.
+///!
+///! State transitions are indicated to the caller, which reports them outside of the DSP routine.
+use miniconf::MiniconfAtomic;
+use serde::{Deserialize, Serialize};
+
+use super::lock_detect::LockState;
+use crate::hardware::{
+    adc::AdcCode,
+    afe::Gain,
+    dac::DacCode,
+    signal_generator::{self, BasicConfig, Signal, SignalGenerator},
+};
+
+/// The condition on the error signal to catch the lock during a sweep.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Catch {
+    /// Catch when the error magnitude is below the level.
+    Threshold,
+    /// Catch when the error crosses the level upwards.
+    Rising,
+    /// Catch when the error crosses the level downwards.
+    Falling,
+}
+
+/// Configuration of the automatic relock.
+///
+/// # Miniconf
+/// `{"enable": <enable>, "center": <center>, "amplitude": <amplitude>, "frequency": <frequency>,
+/// "catch": <catch>, "level": <level>, "settle_time": <settle>, "max_attempts": <attempts>}`
+///
+/// * `<enable>` enables automatic relocking upon loss of lock.
+/// * `<center>` is the sweep center output voltage.
+/// * `<amplitude>` is the sweep amplitude in volts, i.e. half of the swept range.
+/// * `<frequency>` is the sweep frequency in Hertz.
+/// * `<catch>` is any of the [Catch] variants enclosed in double quotes.
+/// * `<level>` is the error signal level of the catch condition in volts at the input (including
+///   the AFE gain).
+/// * `<settle>` is the time in seconds after re-engaging in which the loop has to be locked. It
+///   has to exceed the `lock_time` of the lock detector.
+/// * `<attempts>` is the maximum number of consecutive attempts before giving up. Zero retries
+///   indefinitely.
+///
+/// Every update of the configuration resets the relock state and attempt counters.
+///
+/// ## Example
+/// `{"enable": true, "center": 0.0, "amplitude": 1.0, "frequency": 10.0, "catch": "Rising",
+/// "level": 0.0, "settle_time": 1.0, "max_attempts": 10}`
+#[derive(
+    Copy, Clone, Debug, PartialEq, MiniconfAtomic, Serialize, Deserialize,
+)]
+pub struct RelockConfig {
+    pub enable: bool,
+    pub center: f32,
+    pub amplitude: f32,
+    pub frequency: f32,
+    pub catch: Catch,
+    pub level: f32,
+    pub settle_time: f32,
+    pub max_attempts: u32,
+}
+
+impl Default for RelockConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            center: 0.,
+            amplitude: 1.,
+            frequency: 10.,
+            catch: Catch::Rising,
+            level: 0.,
+            settle_time: 1.,
+            max_attempts: 0,
+        }
+    }
+}
+
+/// Represents the errors that can occur when configuring the relock.
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    /// The sweep could not be configured.
+    Sweep(signal_generator::Error),
+    /// The sweep range exceeds the output range.
+    InvalidRange,
+    /// The settle time does not exceed the lock time of the lock detector.
+    InvalidSettleTime,
+}
+
+/// The state of the relock state machine.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum RelockState {
+    /// The loop filter is engaged.
+    Engaged,
+    /// The output is swept to catch the lock.
+    Sweeping,
+    /// The loop filter has been re-engaged and the loop is settling.
+    Settling,
+    /// Relocking has been given up after the maximum number of attempts.
+    Failed,
+}
+
+/// The relock status reported in telemetry.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct RelockStatus {
+    /// The current relock state.
+    pub state: RelockState,
+    /// The number of consecutive failed attempts.
+    pub attempts: u32,
+    /// The total number of successful relocks.
+    pub relocks: u32,
+}
+
+/// A relock state transition event.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct RelockEvent {
+    /// The channel of the feedback loop.
+    pub channel: usize,
+    /// The new relock status.
+    pub status: RelockStatus,
+    /// The UTC time of the event report in milliseconds since the Unix epoch, if known.
+    pub time: Option<u64>,
+}
+
+/// The automatic relock state machine of a single feedback loop.
+#[derive(Debug)]
+pub struct Relock {
+    config: RelockConfig,
+    sweep: SignalGenerator,
+    center: f32,
+    level: f32,
+    settle_samples: u32,
+    remaining: u32,
+    last_x: f32,
+    state: RelockState,
+    attempts: u32,
+    relocks: u32,
+}
+
+impl Default for Relock {
+    fn default() -> Self {
+        Self {
+            config: RelockConfig::default(),
+            sweep: SignalGenerator::default(),
+            center: 0.,
+            level: 0.,
+            settle_samples: 0,
+            remaining: 0,
+            last_x: 0.,
+            state: RelockState::Engaged,
+            attempts: 0,
+            relocks: 0,
+        }
+    }
+}
+
+impl Relock {
+    /// Configure the relock.
+    ///
+    /// # Note
+    /// The relock state is only reset if the configuration changed.
+    ///
+    /// # Args
+    /// * `config` - The relock configuration.
+    /// * `afe` - The AFE gain of the input channel used to convert the catch level.
+    /// * `lock_time` - The time in seconds the lock detector requires to report lock.
+    /// * `sample_period` - The time in seconds between samples.
+    pub fn configure(
+        &mut self,
+        config: &RelockConfig,
+        afe: Gain,
+        lock_time: f32,
+        sample_period: f32,
+    ) -> Result<(), Error> {
+        if (config.center - config.amplitude) < -DacCode::FULL_SCALE
+            || (config.center + config.amplitude) > DacCode::FULL_SCALE
+        {
+            return Err(Error::InvalidRange);
+        }
+
+        // The loop could never be reported as locked before the settle time expires otherwise.
+        let settle_samples = (config.settle_time / sample_period) as u32;
+        if settle_samples <= (lock_time / sample_period) as u32 {
+            return Err(Error::InvalidSettleTime);
+        }
+
+        // Start the sweep at its center.
+        let sweep = BasicConfig {
+            signal: Signal::Triangle,
+            frequency: config.frequency,
+            symmetry: 0.5,
+            amplitude: config.amplitude,
+            phase: 0.25,
+        }
+        .try_into_config(sample_period, DacCode::FULL_SCALE)
+        .map_err(Error::Sweep)?;
+
+        self.sweep.update_waveform(sweep);
+        self.center = config.center * DacCode::LSB_PER_VOLT;
+        self.level = config.level * afe.as_multiplier() * AdcCode::LSB_PER_VOLT;
+        self.settle_samples = settle_samples;
+
+        if *config != self.config {
+            self.config = *config;
+            self.state = RelockState::Engaged;
+            self.attempts = 0;
+            self.relocks = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Check whether the loop filter is engaged and determines the output.
+    pub fn is_engaged(&self) -> bool {
+        matches!(self.state, RelockState::Engaged | RelockState::Settling)
+    }
+
+    /// Get the current relock status.
+    pub fn status(&self) -> RelockStatus {
+        RelockStatus {
+            state: self.state,
+            attempts: self.attempts,
+            relocks: self.relocks,
+        }
+    }
+
+    fn start(&mut self) {
+        if self.config.max_attempts != 0
+            && self.attempts >= self.config.max_attempts
+        {
+            self.state = RelockState::Failed;
+            return;
+        }
+
+        self.attempts += 1;
+        self.sweep.clear_phase_accumulator();
+        // No crossing can be detected on the first sample of the sweep.
+        self.last_x = f32::NAN;
+        self.state = RelockState::Sweeping;
+    }
+
+    /// Generate the output while the loop filter is disengaged.
+    ///
+    /// # Args
+    /// * `x` - The error signal (the filter input) in ADC codes.
+    ///
+    /// # Returns
+    /// (y, caught) where `y` is the output in DAC codes and `caught` indicates that the capture
+    /// condition was met and the loop filter must be re-engaged with bumpless transfer from `y`.
+    pub fn sweep(&mut self, x: f32) -> (f32, bool) {
+        if self.state != RelockState::Sweeping {
+            return (self.center, false);
+        }
+
+        // Note(unwrap): The signal generator is an infinite iterator.
+        let y = (self.center + self.sweep.next().unwrap() as f32)
+            .max(i16::MIN as f32)
+            .min(i16::MAX as f32);
+
+        let caught = match self.config.catch {
+            Catch::Threshold => -self.level <= x && x <= self.level,
+            Catch::Rising => self.last_x < self.level && x >= self.level,
+            Catch::Falling => self.last_x > self.level && x <= self.level,
+        };
+        self.last_x = x;
+
+        if caught {
+            self.remaining = self.settle_samples;
+            self.state = RelockState::Settling;
+        }
+
+        (y, caught)
+    }
+
+    /// Update the relock state machine once per batch.
+    ///
+    /// # Args
+    /// * `state` - The current lock state of the loop.
+    /// * `transition` - Indicates that the lock state changed during the batch.
+    /// * `samples` - The number of samples in the batch.
+    ///
+    /// # Returns
+    /// True if the relock state changed other than by catching the lock during the sweep.
+    pub fn update(
+        &mut self,
+        state: LockState,
+        transition: bool,
+        samples: u32,
+    ) -> bool {
+        if !self.config.enable {
+            return false;
+        }
+
+        let previous = self.state;
+        match self.state {
+            RelockState::Engaged => {
+                if transition && state != LockState::Locked {
+                    self.start();
+                }
+            }
+            RelockState::Settling => {
+                if state == LockState::Locked {
+                    self.attempts = 0;
+                    self.relocks += 1;
+                    self.state = RelockState::Engaged;
+                } else if self.remaining <= samples {
+                    self.start();
+                } else {
+                    self.remaining -= samples;
+                }
+            }
+            RelockState::Sweeping | RelockState::Failed => {}
+        }
+
+        self.state != previous
+    }
+}
@@ -0,0 +1,155 @@
+///! Slew-rate limiting of setpoints and outputs
+///!
+///! # Design
+///! Changes of the filter offsets (`y_offset`, i.e. the setpoint or a static output) requested over
+///! MQTT are applied instantly by default. Large steps can kick actuators like piezos and lose a
+///! lock. A slew-rate limiter ramps the value actually used on the DSP side towards the requested
+///! value with a configured maximum rate.
+///!
+///! The filter offsets are ramped once per batch. The final output is limited on every sample,
+///! including any added signal generator output.
+use idsp::iir;
+use miniconf::MiniconfAtomic;
+use serde::{Deserialize, Serialize};
+
+use crate::hardware::dac::DacCode;
+
+/// Configuration of the slew-rate limits of a channel.
+///
+/// # Miniconf
+/// `{"offset": <offset>, "output": <output>}`
+///
+/// * `<offset>` is the maximum rate of change of the filter offsets in volts per second.
+/// * `<output>` is the maximum rate of change of the output in volts per second.
+///
+/// A rate of zero disables the respective limit.
+///
+/// ## Example
+/// `{"offset": 10.0, "output": 0.0}`
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    PartialEq,
+    MiniconfAtomic,
+    Serialize,
+    Deserialize,
+)]
+pub struct SlewConfig {
+    pub offset: f32,
+    pub output: f32,
+}
+
+/// A slew-rate limiter of a single value.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SlewLimiter {
+    step: f32,
+    value: f32,
+}
+
+impl SlewLimiter {
+    /// Configure the slew rate.
+    ///
+    /// # Args
+    /// * `rate` - The maximum rate of change in volts per second. Zero disables the limit.
+    /// * `period` - The time in seconds between updates.
+    pub fn configure(&mut self, rate: f32, period: f32) {
+        self.step = rate * DacCode::LSB_PER_VOLT * period;
+    }
+
+    /// Get the current value.
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+
+    /// Advance the value towards the target.
+    ///
+    /// # Args
+    /// * `target` - The requested value in DAC codes.
+    ///
+    /// # Returns
+    /// The slew-rate limited value.
+    pub fn update(&mut self, target: f32) -> f32 {
+        self.value = if self.step > 0. {
+            self.value + (target - self.value).max(-self.step).min(self.step)
+        } else {
+            target
+        };
+        self.value
+    }
+}
+
+/// The slew-rate limiters of a channel with a cascade of `N` filters.
+#[derive(Copy, Clone, Debug)]
+pub struct Slew<const N: usize> {
+    offset: [SlewLimiter; N],
+    output: SlewLimiter,
+}
+
+impl<const N: usize> Default for Slew<N> {
+    fn default() -> Self {
+        Self {
+            offset: [SlewLimiter::default(); N],
+            output: SlewLimiter::default(),
+        }
+    }
+}
+
+impl<const N: usize> Slew<N> {
+    /// Configure the slew-rate limits.
+    ///
+    /// # Args
+    /// * `config` - The slew-rate limit configuration.
+    /// * `sample_period` - The time in seconds between samples.
+    /// * `batch_size` - The number of samples per batch.
+    pub fn configure(
+        &mut self,
+        config: &SlewConfig,
+        sample_period: f32,
+        batch_size: usize,
+    ) {
+        for offset in self.offset.iter_mut() {
+            offset.configure(config.offset, sample_period * batch_size as f32);
+        }
+        self.output.configure(config.output, sample_period);
+    }
+
+    /// Get the filter cascade with the current offsets without advancing the ramps.
+    ///
+    /// # Args
+    /// * `iir` - The requested filter cascade.
+    pub fn current(&self, iir: &[iir::IIR<f32>; N]) -> [iir::IIR<f32>; N] {
+        let mut iir = *iir;
+        for (iir, offset) in iir.iter_mut().zip(self.offset.iter()) {
+            iir.y_offset = offset.value();
+        }
+        iir
+    }
+
+    /// Advance the offset ramps once per batch.
+    ///
+    /// # Args
+    /// * `iir` - The requested filter cascade.
+    ///
+    /// # Returns
+    /// The filter cascade with slew-rate limited offsets to process the batch with.
+    pub fn ramp(&mut self, iir: &[iir::IIR<f32>; N]) -> [iir::IIR<f32>; N] {
+        let mut iir = *iir;
+        for (iir, offset) in iir.iter_mut().zip(self.offset.iter_mut()) {
+            iir.y_offset = offset.update(iir.y_offset);
+        }
+        iir
+    }
+
+    /// Limit the output on every sample.
+    ///
+    /// # Args
+    /// * `y` - The requested output in DAC codes.
+    ///
+    /// # Returns
+    /// The slew-rate limited output in DAC codes.
+    pub fn output(&mut self, y: f32) -> f32 {
+        self.output.update(y)
+    }
+}
@@ -0,0 +1,347 @@
+///! Temperature measurement and control
+///!
+///! # Design
+///! The input voltage of a temperature sensor readout is converted to a temperature in Kelvin
+///! according to a configurable sensor model:
+///!
+///! * **Linear**: The temperature is a linear function of the input voltage, e.g. for IC sensors
+///!   or transmitters.
+///! * **Ntc**: An NTC thermistor. The resistance is converted to temperature using the
+///!   Steinhart-Hart equation `1/T = A + B ln(R) + C ln(R)^3`.
+///! * **Rtd**: A platinum resistance thermometer (e.g. PT100 or PT1000). The resistance is
+///!   converted to temperature using the Callendar-Van Dusen equation with the IEC 60751
+///!   coefficients.
+///!
+///! The resistance of resistive sensors is read out either as a voltage divider, where the sensor
+///! is connected in series with a reference resistor to the excitation voltage and the input
+///! measures the voltage across the sensor, or as a bridge, where the divider is compared against a
+///! second divider of two equal resistors.
+///!
+///! The temperature is controlled by a PID controller with the error `setpoint - temperature`.
+///! Setpoint changes are slew-rate limited. The derivative acts on the temperature only to avoid a
+///! kick on setpoint changes. The integrator is limited to the output range to prevent windup.
+///!
+///! # Note
+///! The quadratic Callendar-Van Dusen equation is also used below 0 °C. The error due to the
+///! neglected cubic term is below 0.03 K above -50 °C.
+use miniconf::{Miniconf, MiniconfAtomic};
+use serde::{Deserialize, Serialize};
+
+/// The Callendar-Van Dusen coefficient `A` of IEC 60751 platinum resistance thermometers.
+const RTD_A: f32 = 3.9083e-3;
+
+/// The Callendar-Van Dusen coefficient `B` of IEC 60751 platinum resistance thermometers.
+const RTD_B: f32 = -5.775e-7;
+
+/// The temperature of 0 °C in Kelvin.
+const ZERO_CELSIUS: f32 = 273.15;
+
+/// Temperature sensor models.
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Miniconf,
+)]
+pub enum SensorModel {
+    Linear,
+    Ntc,
+    Rtd,
+}
+
+/// Configuration of a temperature sensor.
+///
+/// # Miniconf
+/// `{"model": <model>, "excitation": 2.5, "resistor": 10000.0, "bridge": false, "coefficients":
+/// [1.129e-3, 2.341e-4, 8.767e-8], "r0": 100.0, "slope": 100.0, "offset": 273.15}`
+///
+/// Where `<model>` may be any of [SensorModel] variants. The remaining fields only apply to the
+/// respective models.
+#[derive(Copy, Clone, Debug, PartialEq, Miniconf, Deserialize)]
+pub struct SensorConfig {
+    /// The sensor model. See [SensorModel] variants.
+    pub model: SensorModel,
+
+    /// The excitation voltage of the divider or bridge of resistive sensors in volts.
+    pub excitation: f32,
+
+    /// The reference resistor in series with resistive sensors in Ohms.
+    pub resistor: f32,
+
+    /// Specifies true if resistive sensors are read out as a bridge instead of a divider.
+    pub bridge: bool,
+
+    /// The Steinhart-Hart coefficients `[A, B, C]` of NTC thermistors.
+    pub coefficients: [f32; 3],
+
+    /// The resistance of RTDs at 0 °C in Ohms, e.g. 100 for PT100 and 1000 for PT1000.
+    pub r0: f32,
+
+    /// The temperature coefficient of linear sensors in Kelvin per volt.
+    pub slope: f32,
+
+    /// The temperature of linear sensors at zero input voltage in Kelvin.
+    pub offset: f32,
+}
+
+impl Default for SensorConfig {
+    fn default() -> Self {
+        Self {
+            model: SensorModel::Ntc,
+            excitation: 2.5,
+            resistor: 10e3,
+            bridge: false,
+            // A generic 10 kOhm NTC thermistor.
+            coefficients: [1.129148e-3, 2.34125e-4, 8.76741e-8],
+            r0: 100.,
+            slope: 100.,
+            offset: ZERO_CELSIUS,
+        }
+    }
+}
+
+impl SensorConfig {
+    /// Convert the sensor readout voltage to temperature.
+    ///
+    /// # Args
+    /// * `voltage` - The input voltage in volts.
+    ///
+    /// # Returns
+    /// The temperature in Kelvin or `None` if the voltage is outside of the valid range of the
+    /// readout, e.g. due to an open or shorted sensor.
+    pub fn temperature(&self, voltage: f32) -> Option<f32> {
+        let temperature = match self.model {
+            SensorModel::Linear => self.slope * voltage + self.offset,
+            SensorModel::Ntc => {
+                let [a, b, c] = self.coefficients;
+                let ln_r = libm::logf(self.resistance(voltage)?);
+                1. / (a + b * ln_r + c * ln_r * ln_r * ln_r)
+            }
+            SensorModel::Rtd => {
+                // Solve `R = R0 (1 + A t + B t^2)` for the temperature `t` in °C.
+                let ratio = self.resistance(voltage)? / self.r0;
+                let discriminant = RTD_A * RTD_A - 4. * RTD_B * (1. - ratio);
+                if discriminant < 0. {
+                    return None;
+                }
+                ZERO_CELSIUS
+                    + (libm::sqrtf(discriminant) - RTD_A) / (2. * RTD_B)
+            }
+        };
+
+        (temperature.is_finite() && temperature > 0.).then_some(temperature)
+    }
+
+    /// Compute the resistance of resistive sensors.
+    fn resistance(&self, voltage: f32) -> Option<f32> {
+        // The fraction of the excitation voltage across the sensor.
+        let fraction =
+            voltage / self.excitation + if self.bridge { 0.5 } else { 0. };
+
+        (fraction > 0. && fraction < 1.)
+            .then(|| self.resistor * fraction / (1. - fraction))
+    }
+}
+
+/// Configuration of a PID controller.
+///
+/// # Miniconf
+/// `{"kp": <kp>, "ki": <ki>, "kd": <kd>}`
+///
+/// * `<kp>` is the proportional gain in output units per Kelvin.
+/// * `<ki>` is the integral gain in output units per Kelvin and second.
+/// * `<kd>` is the derivative gain in output units times seconds per Kelvin.
+///
+/// ## Example
+/// `{"kp": 0.5, "ki": 0.05, "kd": 0.0}`
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    PartialEq,
+    MiniconfAtomic,
+    Serialize,
+    Deserialize,
+)]
+pub struct PidConfig {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+}
+
+/// Configuration of the controller output.
+///
+/// # Miniconf
+/// `{"gain": <gain>, "min": <min>, "max": <max>}`
+///
+/// * `<gain>` is the output voltage per output unit, e.g. the inverse transconductance in volts
+///   per Ampere of a current driver, or 1 for a voltage output.
+/// * `<min>` is the minimum output in output units.
+/// * `<max>` is the maximum output in output units.
+///
+/// ## Example
+/// Limit the current of a driver with a transconductance of 0.5 A/V to +/- 1 A:
+/// `{"gain": 2.0, "min": -1.0, "max": 1.0}`
+#[derive(
+    Copy, Clone, Debug, PartialEq, MiniconfAtomic, Serialize, Deserialize,
+)]
+pub struct OutputConfig {
+    pub gain: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        // The output is disabled until limits are configured.
+        Self {
+            gain: 1.,
+            min: 0.,
+            max: 0.,
+        }
+    }
+}
+
+/// Represents the errors that can occur when configuring the controller.
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    /// The output limits are inverted.
+    InvalidLimits,
+}
+
+/// The controller status reported in telemetry.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct ThermostatStatus {
+    /// The measured temperature in Kelvin or `None` upon a sensor fault.
+    pub temperature: Option<f32>,
+    /// The current (slew-rate limited) setpoint in Kelvin.
+    pub setpoint: f32,
+    /// The controller output in output units.
+    pub output: f32,
+}
+
+/// A temperature controller of a single channel.
+#[derive(Copy, Clone, Debug)]
+pub struct Thermostat {
+    enable: bool,
+    target: f32,
+    step: f32,
+    pid: PidConfig,
+    output: OutputConfig,
+    period: f32,
+    setpoint: f32,
+    integral: f32,
+    temperature: Option<f32>,
+    value: f32,
+}
+
+impl Default for Thermostat {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            target: 0.,
+            step: 0.,
+            pid: PidConfig::default(),
+            output: OutputConfig::default(),
+            period: 1.,
+            setpoint: 0.,
+            integral: 0.,
+            temperature: None,
+            value: 0.,
+        }
+    }
+}
+
+impl Thermostat {
+    /// Configure the controller.
+    ///
+    /// # Args
+    /// * `enable` - Specifies true if the controller drives the output.
+    /// * `setpoint` - The setpoint in Kelvin.
+    /// * `slew` - The maximum rate of change of the setpoint in Kelvin per second. Zero disables
+    ///   the limit.
+    /// * `pid` - The PID gains.
+    /// * `output` - The output configuration.
+    /// * `period` - The time in seconds between controller updates.
+    pub fn configure(
+        &mut self,
+        enable: bool,
+        setpoint: f32,
+        slew: f32,
+        pid: &PidConfig,
+        output: &OutputConfig,
+        period: f32,
+    ) -> Result<(), Error> {
+        if output.min > output.max {
+            return Err(Error::InvalidLimits);
+        }
+
+        self.enable = enable;
+        self.target = setpoint;
+        self.step = slew * period;
+        self.pid = *pid;
+        self.output = *output;
+        self.period = period;
+        self.integral = self.integral.max(output.min).min(output.max);
+        Ok(())
+    }
+
+    /// Get the current controller status.
+    pub fn status(&self) -> ThermostatStatus {
+        ThermostatStatus {
+            temperature: self.temperature,
+            setpoint: self.setpoint,
+            output: self.value,
+        }
+    }
+
+    /// Get the output voltage for the current controller output.
+    pub fn voltage(&self) -> f32 {
+        self.value * self.output.gain
+    }
+
+    /// Update the controller.
+    ///
+    /// # Note
+    /// While the controller is disabled or upon a sensor fault, the output is set to zero (limited
+    /// to the output range) and the integrator is cleared. The setpoint ramp restarts from the
+    /// measured temperature once the controller is enabled.
+    ///
+    /// # Args
+    /// * `temperature` - The measured temperature in Kelvin or `None` upon a sensor fault.
+    ///
+    /// # Returns
+    /// The controller output in output units.
+    pub fn update(&mut self, temperature: Option<f32>) -> f32 {
+        let last = core::mem::replace(&mut self.temperature, temperature);
+
+        let temperature = match temperature {
+            Some(temperature) if self.enable => temperature,
+            _ => {
+                self.setpoint = temperature.unwrap_or(self.target);
+                self.integral = 0.;
+                self.value = 0f32.max(self.output.min).min(self.output.max);
+                return self.value;
+            }
+        };
+
+        self.setpoint = if self.step > 0. {
+            self.setpoint
+                + (self.target - self.setpoint).max(-self.step).min(self.step)
+        } else {
+            self.target
+        };
+
+        let error = self.setpoint - temperature;
+        let proportional = self.pid.kp * error;
+        let derivative = last
+            .map(|last| self.pid.kd * (last - temperature) / self.period)
+            .unwrap_or(0.);
+        self.integral = (self.integral + self.pid.ki * self.period * error)
+            .max(self.output.min)
+            .min(self.output.max);
+
+        self.value = (proportional + self.integral + derivative)
+            .max(self.output.min)
+            .min(self.output.max);
+        self.value
+    }
+}
@@ -0,0 +1,98 @@
+//! DSP Execution Time Measurement
+//!
+//! # Description
+//! This file provides utilities to measure the execution time of the DSP `process` task using the
+//! DWT cycle counter of the Cortex-M7 core. The cycle count of each invocation is accumulated
+//! over the telemetry period and converted to CPU load figures relative to the batch period upon
+//! reporting.
+//!
+//! As the `process` task is executed exactly once per batch, the mean load is the fraction of CPU
+//! time used by the DSP. The remaining idle fraction is available to lower priority tasks, e.g.
+//! networking. The headroom indicates how close the worst-case execution time is to the batch
+//! period deadline.
+use cortex_m::peripheral::{DCB, DWT};
+use serde::Serialize;
+
+use super::design_parameters::SYSCLK;
+
+/// Enable the DWT cycle counter.
+///
+/// # Args
+/// * `dcb` - The debug control block, used to enable the trace unit.
+/// * `dwt` - The data watchpoint and trace unit providing the cycle counter.
+pub fn enable_cycle_counter(dcb: &mut DCB, dwt: &mut DWT) {
+    dcb.enable_trace();
+    dwt.enable_cycle_counter();
+}
+
+/// An accumulator of the execution time of the `process` task over the telemetry period.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CycleAccumulator {
+    count: u32,
+    total: u64,
+    max: u32,
+}
+
+/// The CPU load of the DSP `process` task over the telemetry period.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct CpuLoad {
+    /// The mean fraction of the batch period spent processing.
+    pub load: f32,
+    /// The worst-case fraction of the batch period spent processing.
+    pub max_load: f32,
+    /// The fraction of the batch period remaining before the deadline in the worst case.
+    pub headroom: f32,
+    /// The mean fraction of CPU time available to lower priority tasks.
+    pub idle: f32,
+    /// The mean execution time of the `process` task in seconds.
+    pub mean_time: f32,
+    /// The worst-case execution time (latency from task entry to completion) in seconds.
+    pub max_time: f32,
+}
+
+impl CycleAccumulator {
+    /// Get the current cycle count, used to mark the start of the `process` task.
+    pub fn start() -> u32 {
+        DWT::cycle_count()
+    }
+
+    /// Record the completion of the `process` task.
+    ///
+    /// # Args
+    /// * `start` - The cycle count at the start of the task as returned by [Self::start].
+    pub fn record(&mut self, start: u32) {
+        let cycles = DWT::cycle_count().wrapping_sub(start);
+        self.max = self.max.max(cycles);
+        self.total += cycles as u64;
+        self.count += 1;
+    }
+
+    /// Convert the accumulated cycle counts to CPU load figures.
+    ///
+    /// # Args
+    /// * `batch_period` - The period of the batch processing in seconds.
+    ///
+    /// # Returns
+    /// The CPU load or `None` if no invocations were recorded.
+    pub fn finalize(&self, batch_period: f32) -> Option<CpuLoad> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let cycle_period = 1. / SYSCLK.to_Hz() as f32;
+        let mean_time = (self.total as f32 / self.count as f32) * cycle_period;
+        let max_time = self.max as f32 * cycle_period;
+
+        let load = mean_time / batch_period;
+        let max_load = max_time / batch_period;
+
+        Some(CpuLoad {
+            load,
+            max_load,
+            headroom: 1. - max_load,
+            idle: 1. - load,
+            mean_time,
+            max_time,
+        })
+    }
+}
@@ -0,0 +1,222 @@
+///! Crash log retained across resets
+///!
+///! # Design
+///! Panic messages are recorded by the panic handler in RAM that is not initialized during startup
+///! (the `.uninit` section of `cortex-m-rt`) and therefore survives all resets except a loss of
+///! power. HardFaults and unhandled exceptions panic with the exception frame or the IRQ number in
+///! their message.
+///!
+///! Upon boot, the cause of the reset is added to a pending panic record. Resets by a watchdog, a
+///! brownout or an illegal low-power mode entry are logged without a panic message. The log is kept
+///! until it is explicitly cleared and counts the unexpected resets since. A new crash replaces the
+///! message and the reset reason of the previous one.
+///!
+///! The record is protected by a magic value and a checksum to detect the random RAM content after
+///! power-on.
+use core::{
+    fmt::{self, Write},
+    mem::MaybeUninit,
+    panic::PanicInfo,
+};
+use heapless::String;
+use serde::Serialize;
+
+use super::reset::ResetReason;
+
+/// The maximum length of a recorded panic message. Longer messages are truncated.
+pub const MESSAGE_SIZE: usize = 256;
+
+// Identifies an initialized record.
+const MAGIC: u32 = 0x4352_4153;
+
+// The encoded reset reason of a record without crash.
+const NO_CRASH: u32 = u32::MAX;
+
+// The encoded reset reason of a panic awaiting the subsequent reset.
+const PENDING: u32 = u32::MAX - 1;
+
+// The reset reasons indexed by their encoding.
+const REASONS: [ResetReason; 8] = [
+    ResetReason::PowerOn,
+    ResetReason::Brownout,
+    ResetReason::Pin,
+    ResetReason::Software,
+    ResetReason::IndependentWatchdog,
+    ResetReason::WindowWatchdog,
+    ResetReason::LowPower,
+    ResetReason::Unknown,
+];
+
+/// A crash logged before a reset.
+#[derive(Clone, Debug, Serialize)]
+pub struct CrashReport {
+    /// The panic message, if the firmware panicked. HardFaults include the exception frame.
+    pub message: Option<String<MESSAGE_SIZE>>,
+
+    /// The cause of the reset following the crash.
+    pub reset_reason: ResetReason,
+
+    /// The number of unexpected resets since the crash log was cleared.
+    pub resets: u32,
+}
+
+#[repr(C)]
+struct Record {
+    magic: u32,
+    resets: u32,
+    reason: u32,
+    length: u32,
+    message: [u8; MESSAGE_SIZE],
+    checksum: u32,
+}
+
+#[link_section = ".uninit.CRASH_LOG"]
+static mut CRASH_LOG: MaybeUninit<Record> = MaybeUninit::uninit();
+
+impl Record {
+    // Compute the FNV-1a hash of the record contents.
+    fn checksum(&self) -> u32 {
+        let length = (self.length as usize).min(MESSAGE_SIZE);
+        [self.magic, self.resets, self.reason, self.length]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .chain(self.message[..length].iter().copied())
+            .fold(0x811C_9DC5, |hash, byte| {
+                (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+            })
+    }
+
+    fn is_valid(&self) -> bool {
+        self.magic == MAGIC
+            && self.length as usize <= MESSAGE_SIZE
+            && self.checksum == self.checksum()
+    }
+
+    fn seal(&mut self) {
+        self.magic = MAGIC;
+        self.checksum = self.checksum();
+    }
+
+    fn clear(&mut self) {
+        self.resets = 0;
+        self.reason = NO_CRASH;
+        self.length = 0;
+        self.seal();
+    }
+}
+
+impl Write for Record {
+    // Append to the message, truncating it at a character boundary.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let length = self.length as usize;
+        let mut count = s.len().min(MESSAGE_SIZE - length);
+        while !s.is_char_boundary(count) {
+            count -= 1;
+        }
+
+        self.message[length..length + count]
+            .copy_from_slice(&s.as_bytes()[..count]);
+        self.length += count as u32;
+
+        if count < s.len() {
+            Err(fmt::Error)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn record() -> &'static mut Record {
+    // Note(unsafe): Any content of the record is valid and checked before use. The record is only
+    // accessed during setup, from the panic handler with interrupts disabled, and within critical
+    // sections.
+    unsafe { &mut *CRASH_LOG.as_mut_ptr() }
+}
+
+/// Record a panic in the crash log.
+///
+/// # Note
+/// This is called by the panic handler with interrupts disabled.
+///
+/// # Args
+/// * `info` - The panic information.
+pub fn record_panic(info: &PanicInfo) {
+    let record = record();
+    if !record.is_valid() {
+        record.clear();
+    }
+
+    record.reason = PENDING;
+    record.length = 0;
+    // Overlong messages are truncated.
+    write!(record, "{}", info).ok();
+    record.seal();
+}
+
+/// Log the cause of the last reset.
+///
+/// # Note
+/// This must be called once during setup.
+///
+/// # Args
+/// * `reset_reason` - The cause of the last reset.
+pub fn update(reset_reason: ResetReason) {
+    let record = record();
+    if !record.is_valid() {
+        record.clear();
+    }
+
+    // Note(unwrap): All reset reasons are encoded.
+    let reason = REASONS
+        .iter()
+        .position(|reason| *reason == reset_reason)
+        .unwrap() as u32;
+
+    if record.reason == PENDING {
+        record.reason = reason;
+        record.resets = record.resets.saturating_add(1);
+    } else if matches!(
+        reset_reason,
+        ResetReason::IndependentWatchdog
+            | ResetReason::WindowWatchdog
+            | ResetReason::Brownout
+            | ResetReason::LowPower
+    ) {
+        record.reason = reason;
+        record.length = 0;
+        record.resets = record.resets.saturating_add(1);
+    }
+
+    record.seal();
+}
+
+/// Get the report of the last crash.
+///
+/// # Returns
+/// The crash report or `None` if no crash was logged since the crash log was cleared.
+pub fn report() -> Option<CrashReport> {
+    cortex_m::interrupt::free(|_| {
+        let record = record();
+        if !record.is_valid() {
+            return None;
+        }
+
+        let reset_reason = *REASONS.get(record.reason as usize)?;
+        let message =
+            core::str::from_utf8(&record.message[..record.length as usize])
+                .ok()
+                .filter(|message| !message.is_empty())
+                .map(String::from);
+
+        Some(CrashReport {
+            message,
+            reset_reason,
+            resets: record.resets,
+        })
+    })
+}
+
+/// Clear the crash log.
+pub fn clear() {
+    cortex_m::interrupt::free(|_| record().clear())
+}
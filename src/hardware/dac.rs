@@ -76,6 +76,23 @@ use hal::{
 static mut DAC_BUF: [[SampleBuffer; 2]; 2] =
     [[[0; MAX_SAMPLE_BUFFER_SIZE]; 2]; 2];
 
+/// The DAC output code of the safe state (0 V), assumed upon startup and after a panic.
+pub const SAFE_OUTPUT: DacCode = DacCode(0x8000);
+
+/// Force both DAC outputs to the safe state.
+///
+/// # Note
+/// The DMA transfers continue with both buffers filled with the [SAFE_OUTPUT] code. This is
+/// intended for the panic handler, after which the DSP routine no longer executes.
+///
+/// # Safety
+/// The DAC buffers must not be accessed concurrently.
+pub unsafe fn force_safe_output() {
+    for buf in DAC_BUF.iter_mut().flatten() {
+        buf.fill(SAFE_OUTPUT.0);
+    }
+}
+
 /// Custom type for referencing DAC output codes.
 /// The internal integer is the raw code written to the DAC output register.
 #[derive(Copy, Clone)]
@@ -212,14 +229,12 @@ macro_rules! dac_output {
                 let mut spi = spi.disable();
                 spi.listen(hal::spi::Event::Error);
 
-                // AXISRAM is uninitialized. As such, we manually initialize it for a 0V DAC output
-                // here before starting the transfer .
+                // AXISRAM is uninitialized. As such, we manually initialize it for the safe 0V DAC
+                // output here before starting the transfer .
                 // Note(unsafe): We currently own all DAC_BUF[index] buffers and are not using them
                 // elsewhere, so it is safe to access them here.
                 for buf in unsafe { DAC_BUF[$index].iter_mut() } {
-                    for byte in buf.iter_mut() {
-                        *byte = DacCode::try_from(0.0f32).unwrap().0;
-                    }
+                    buf.fill(SAFE_OUTPUT.0);
                 }
 
                 // Construct the trigger stream to write from memory to the peripheral.
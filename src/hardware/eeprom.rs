@@ -1,4 +1,7 @@
-use embedded_hal::blocking::{delay::DelayMs, i2c::WriteRead};
+use embedded_hal::blocking::{
+    delay::DelayMs,
+    i2c::{Write, WriteRead},
+};
 
 // The EEPROM is a variant without address bits, so the 3 LSB of this word are "dont-cares".
 const I2C_ADDR: u8 = 0x50;
@@ -6,6 +9,28 @@ const I2C_ADDR: u8 = 0x50;
 // The MAC address is stored in the last 6 bytes of the 256 byte address space.
 const MAC_POINTER: u8 = 0xFA;
 
+// The EEPROM is written in pages of 8 bytes.
+const PAGE_SIZE: usize = 8;
+
+// The number of acknowledge polls to wait for the completion of a page write. A poll takes about
+// 100 us at 100 kHz and a page write completes within 5 ms.
+const WRITE_POLLS: usize = 1000;
+
+/// The size of the writable user area at the start of the address space. The upper half of the
+/// EEPROM is write-protected.
+pub const USER_SIZE: usize = 128;
+
+/// Represents errors that can occur when accessing the EEPROM.
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    /// The access exceeds the user area.
+    Bounds,
+    /// An I2C transfer failed.
+    I2c,
+    /// A page write did not complete in time.
+    Timeout,
+}
+
 pub fn read_eui48<T>(i2c: &mut T, delay: &mut impl DelayMs<u8>) -> [u8; 6]
 where
     T: WriteRead,
@@ -40,3 +65,69 @@ where
 
     panic!("Failed to read MAC address");
 }
+
+/// Read from the user area of the EEPROM.
+///
+/// # Args
+/// * `i2c` - The I2C bus of the EEPROM.
+/// * `address` - The address to start reading at.
+/// * `data` - The buffer to read into.
+pub fn read<T>(
+    i2c: &mut T,
+    address: usize,
+    data: &mut [u8],
+) -> Result<(), Error>
+where
+    T: WriteRead,
+{
+    if address + data.len() > USER_SIZE {
+        return Err(Error::Bounds);
+    }
+
+    i2c.write_read(I2C_ADDR, &[address as u8], data)
+        .map_err(|_| Error::I2c)
+}
+
+/// Write to the user area of the EEPROM.
+///
+/// # Note
+/// This blocks until all pages are written, which takes up to 5 ms per page.
+///
+/// # Args
+/// * `i2c` - The I2C bus of the EEPROM.
+/// * `address` - The address to start writing at.
+/// * `data` - The data to write.
+pub fn write<T>(i2c: &mut T, address: usize, data: &[u8]) -> Result<(), Error>
+where
+    T: Write + WriteRead,
+{
+    if address + data.len() > USER_SIZE {
+        return Err(Error::Bounds);
+    }
+
+    let mut address = address;
+    let mut data = data;
+    while !data.is_empty() {
+        // Page writes wrap around at the page boundary.
+        let len = (PAGE_SIZE - address % PAGE_SIZE).min(data.len());
+        let mut buffer = [0u8; PAGE_SIZE + 1];
+        buffer[0] = address as u8;
+        buffer[1..=len].copy_from_slice(&data[..len]);
+        i2c.write(I2C_ADDR, &buffer[..=len])
+            .map_err(|_| Error::I2c)?;
+
+        // The EEPROM does not acknowledge its address until the write cycle completed.
+        let mut byte = [0u8];
+        (0..WRITE_POLLS)
+            .find(|_| {
+                i2c.write_read(I2C_ADDR, &[address as u8], &mut byte)
+                    .is_ok()
+            })
+            .ok_or(Error::Timeout)?;
+
+        address += len;
+        data = &data[len..];
+    }
+
+    Ok(())
+}
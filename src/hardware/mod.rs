@@ -4,18 +4,21 @@ pub use stm32h7xx_hal as hal;
 
 pub mod adc;
 pub mod afe;
+pub mod cpu_load;
 pub mod cpu_temp_sensor;
+pub mod crash_log;
 pub mod dac;
 pub mod delay;
 pub mod design_parameters;
+pub mod eeprom;
 pub mod input_stamper;
 pub mod pounder;
+pub mod reset;
 pub mod setup;
 pub mod shared_adc;
 pub mod signal_generator;
 pub mod timers;
-
-mod eeprom;
+pub mod watchdog;
 
 // Type alias for the analog front-end (AFE) for ADC0.
 pub type AFE0 = afe::ProgrammableGainAmplifier<
@@ -64,6 +67,9 @@ pub type I2c1 = hal::i2c::I2c<hal::stm32::I2C1>;
 pub type I2c1Proxy =
     shared_bus::I2cProxy<'static, shared_bus::AtomicCheckMutex<I2c1>>;
 
+// The I2C bus of the EEPROM.
+pub type I2c2 = hal::i2c::I2c<hal::stm32::I2C2>;
+
 #[inline(never)]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
@@ -83,6 +89,13 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     }
     PANICKED.store(true, Ordering::Relaxed);
 
+    // Retain the panic message across the reset.
+    crash_log::record_panic(info);
+
+    // Hold the DAC outputs in the safe state until the watchdog resets the device.
+    // Note(unsafe): The DSP routine no longer executes with interrupts disabled.
+    unsafe { dac::force_safe_output() };
+
     // Turn on both red LEDs, FP_LED_1, FP_LED_3
     let gpiod = unsafe { &*hal::stm32::GPIOD::ptr() };
     gpiod.odr.modify(|_, w| w.odr6().high().odr12().high());
@@ -101,7 +114,7 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 
 #[cortex_m_rt::exception]
 unsafe fn HardFault(ef: &cortex_m_rt::ExceptionFrame) -> ! {
-    panic!("HardFault at {:#?}", ef);
+    panic!("HardFault at {:?}", ef);
 }
 
 #[cortex_m_rt::exception]
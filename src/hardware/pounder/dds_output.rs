@@ -110,6 +110,32 @@ impl DdsOutput {
     /// # Args
     /// * `profile` - The serialized DDS profile to write.
     pub fn write(&mut self, profile: &[u32]) {
+        self.push(profile);
+
+        // Trigger the IO_update signal generating timer to asynchronous create the IO_Update pulse.
+        self.io_update_trigger.trigger();
+    }
+
+    /// Write a profile to the stream without generating an IO_Update.
+    ///
+    /// # Note
+    /// The profile takes effect with the IO_Update of the next [DdsOutput::write]. This blocks
+    /// until the profile has left the QSPI FIFO, such that the next profile can be written without
+    /// stalling. It is intended for updates exceeding a single profile outside of the DSP routine.
+    ///
+    /// # Args
+    /// * `profile` - The serialized DDS profile to write.
+    pub fn write_deferred(&mut self, profile: &[u32]) {
+        self.push(profile);
+
+        // Note(unsafe): We own the QSPI interface, so it is safe to access the registers in a raw
+        // fashion.
+        let regs = unsafe { &*hal::stm32::QUADSPI::ptr() };
+        while regs.sr.read().flevel().bits() != 0 {}
+    }
+
+    // Write a profile into the QSPI FIFO.
+    fn push(&mut self, profile: &[u32]) {
         // Note(unsafe): We own the QSPI interface, so it is safe to access the registers in a raw
         // fashion.
         let regs = unsafe { &*hal::stm32::QUADSPI::ptr() };
@@ -123,9 +149,6 @@ impl DdsOutput {
             // Note(unsafe): any bit pattern is valid for a TX FIFO write.
             regs.dr.write(|w| unsafe { w.bits(*word) });
         }
-
-        // Trigger the IO_update signal generating timer to asynchronous create the IO_Update pulse.
-        self.io_update_trigger.trigger();
     }
 }
 
@@ -163,4 +186,14 @@ impl<'a> ProfileBuilder<'a> {
     pub fn write(&mut self) {
         self.dds_output.write(self.serializer.finalize());
     }
+
+    /// Write the profile to the DDS without generating an IO_Update.
+    ///
+    /// # Note
+    /// See [DdsOutput::write_deferred].
+    #[allow(dead_code)]
+    #[inline]
+    pub fn write_deferred(&mut self) {
+        self.dds_output.write_deferred(self.serializer.finalize());
+    }
 }
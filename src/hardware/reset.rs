@@ -0,0 +1,65 @@
+///! Reset cause detection
+///!
+///! # Design
+///! The reset status register of the RCC accumulates flags for all reset sources until it is
+///! explicitly cleared. It is read once during setup to determine the cause of the last reset and
+///! cleared afterwards so that subsequent resets can be distinguished.
+///!
+///! A power-on reset also sets the brownout and pin reset flags, and every reset sets the pin reset
+///! flag since the reset pin is driven during the reset sequence. The flags are therefore
+///! evaluated in the order of their specificity.
+use serde::Serialize;
+
+use super::hal;
+
+/// The cause of the last reset.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum ResetReason {
+    /// The device was powered on.
+    PowerOn,
+    /// The supply voltage dropped below the brownout threshold.
+    Brownout,
+    /// The reset pin was asserted externally, e.g. by a debugger.
+    Pin,
+    /// The firmware requested a reset.
+    Software,
+    /// The independent watchdog expired.
+    IndependentWatchdog,
+    /// The window watchdog expired.
+    WindowWatchdog,
+    /// An illegal low-power mode entry was detected.
+    LowPower,
+    /// No reset flag is set.
+    Unknown,
+}
+
+impl ResetReason {
+    /// Determine the cause of the last reset and clear the reset flags.
+    ///
+    /// # Args
+    /// * `rcc` - The RCC peripheral holding the reset status register.
+    pub fn take(rcc: &hal::stm32::RCC) -> Self {
+        let rsr = rcc.rsr.read();
+
+        let reason = if rsr.iwdg1rstf().bit_is_set() {
+            ResetReason::IndependentWatchdog
+        } else if rsr.wwdg1rstf().bit_is_set() {
+            ResetReason::WindowWatchdog
+        } else if rsr.lpwrrstf().bit_is_set() {
+            ResetReason::LowPower
+        } else if rsr.sftrstf().bit_is_set() {
+            ResetReason::Software
+        } else if rsr.porrstf().bit_is_set() {
+            ResetReason::PowerOn
+        } else if rsr.borrstf().bit_is_set() {
+            ResetReason::Brownout
+        } else if rsr.pinrstf().bit_is_set() {
+            ResetReason::Pin
+        } else {
+            ResetReason::Unknown
+        };
+
+        rcc.rsr.write(|w| w.rmvf().set_bit());
+        reason
+    }
+}
@@ -13,15 +13,25 @@ use stm32h7xx_hal::{
 use smoltcp_nal::smoltcp;
 
 use super::{
-    adc, afe, cpu_temp_sensor::CpuTempSensor, dac, delay, design_parameters,
-    eeprom, input_stamper::InputStamper, pounder,
-    pounder::dds_output::DdsOutput, shared_adc::SharedAdc, timers,
-    DigitalInput0, DigitalInput1, EthernetPhy, NetworkStack, SystemTimer,
+    adc, afe, cpu_load,
+    cpu_temp_sensor::CpuTempSensor,
+    crash_log, dac, delay, design_parameters, eeprom,
+    input_stamper::InputStamper,
+    pounder,
+    pounder::dds_output::DdsOutput,
+    reset::ResetReason,
+    shared_adc::SharedAdc,
+    timers,
+    watchdog::{self, Watchdog},
+    DigitalInput0, DigitalInput1, EthernetPhy, I2c2, NetworkStack, SystemTimer,
     Systick, AFE0, AFE1,
 };
+use crate::net::network_config::{NetworkConfig, Origin};
 
 const NUM_TCP_SOCKETS: usize = 4;
-const NUM_UDP_SOCKETS: usize = 1;
+// One UDP socket is required for each data stream target, one for SNTP, one for DNS and one for
+// mDNS.
+const NUM_UDP_SOCKETS: usize = 5;
 const NUM_SOCKETS: usize = NUM_UDP_SOCKETS + NUM_TCP_SOCKETS;
 
 pub struct NetStorage {
@@ -35,6 +45,7 @@ pub struct NetStorage {
         [Option<(smoltcp::wire::IpAddress, smoltcp::iface::Neighbor)>; 8],
     pub routes_cache:
         [Option<(smoltcp::wire::IpCidr, smoltcp::iface::Route)>; 8],
+    pub ipv4_multicast_groups: [Option<(smoltcp::wire::Ipv4Address, ())>; 1],
 }
 
 #[derive(Copy, Clone)]
@@ -86,6 +97,7 @@ impl Default for NetStorage {
             )],
             neighbor_cache: [None; 8],
             routes_cache: [None; 8],
+            ipv4_multicast_groups: [None; 1],
             sockets: [smoltcp::iface::SocketStorage::EMPTY; NUM_SOCKETS + 1],
             tcp_socket_storage: [TcpSocketStorage::new(); NUM_TCP_SOCKETS],
             udp_socket_storage: [UdpSocketStorage::new(); NUM_UDP_SOCKETS],
@@ -98,6 +110,10 @@ pub struct NetworkDevices {
     pub stack: NetworkStack,
     pub phy: EthernetPhy,
     pub mac_address: smoltcp::wire::EthernetAddress,
+    pub config: NetworkConfig,
+    pub origin: Origin,
+    pub eeprom: I2c2,
+    pub dhcp: Option<smoltcp::iface::SocketHandle>,
 }
 
 /// The available hardware interfaces on Stabilizer.
@@ -112,6 +128,8 @@ pub struct StabilizerDevices {
     pub timestamp_timer: timers::TimestampTimer,
     pub net: NetworkDevices,
     pub digital_inputs: (DigitalInput0, DigitalInput1),
+    pub reset_reason: ResetReason,
+    pub watchdog: Watchdog,
 }
 
 /// The available Pounder-specific hardware interfaces.
@@ -247,8 +265,15 @@ pub fn setup(
     // Enable SRAM3 for the ethernet descriptor ring.
     device.RCC.ahb2enr.modify(|_, w| w.sram3en().set_bit());
 
-    // Clear reset flags.
-    device.RCC.rsr.write(|w| w.rmvf().set_bit());
+    // Determine the reset cause and clear the reset flags.
+    let reset_reason = ResetReason::take(&device.RCC);
+    log::info!("Reset reason: {:?}", reset_reason);
+
+    // Log the reset in the crash log retained across resets.
+    crash_log::update(reset_reason);
+    if let Some(report) = crash_log::report() {
+        log::warn!("Crash before reset: {:?}", report);
+    }
 
     // Select the PLLs for SPI.
     device
@@ -276,6 +301,9 @@ pub fn setup(
     // After ITCM loading.
     core.SCB.enable_icache();
 
+    // Enable the cycle counter for DSP execution time measurement.
+    cpu_load::enable_cycle_counter(&mut core.DCB, &mut core.DWT);
+
     let mut delay = delay::AsmDelay::new(ccdr.clocks.c_ck().to_Hz());
 
     let gpioa = device.GPIOA.split(ccdr.peripheral.GPIOA);
@@ -583,6 +611,8 @@ pub fn setup(
     ));
     log::info!("EUI48: {}", mac_addr);
 
+    let (network_config, origin) = NetworkConfig::load(&mut eeprom_i2c);
+
     let network_devices = {
         let ethernet_pins = {
             // Reset the PHY before configuring pins.
@@ -624,25 +654,42 @@ pub fn setup(
         lan8742a.phy_reset();
         lan8742a.phy_init();
 
+        // Note(unsafe): The MAC is exclusively owned by the ethernet driver, which does not modify
+        // the packet filter after initialization. Multicast frames are passed to receive mDNS
+        // queries.
+        unsafe {
+            (*hal::stm32::ETHERNET_MAC::ptr())
+                .macpfr
+                .modify(|_, w| w.pm().set_bit());
+        }
+
         unsafe { ethernet::enable_interrupt() };
 
-        // Configure IP address according to DHCP socket availability
-        let ip_addrs: smoltcp::wire::IpAddress = option_env!("STATIC_IP")
-            .unwrap_or("0.0.0.0")
-            .parse()
-            .unwrap();
+        // Configure the static address or leave it unspecified for DHCP.
+        let (address, prefix, gateway) = if network_config.dhcp {
+            ([0; 4], 24, [0; 4])
+        } else {
+            (
+                network_config.address,
+                network_config.prefix,
+                network_config.gateway,
+            )
+        };
 
         // Note(unwrap): The hardware configuration function is only allowed to be called once.
         // Unwrapping is intended to panic if called again to prevent re-use of global memory.
         let store =
             cortex_m::singleton!(: NetStorage = NetStorage::default()).unwrap();
 
-        store.ip_addrs[0] = smoltcp::wire::IpCidr::new(ip_addrs, 24);
+        store.ip_addrs[0] = smoltcp::wire::IpCidr::new(
+            smoltcp::wire::Ipv4Address(address).into(),
+            prefix,
+        );
 
         let mut routes =
             smoltcp::iface::Routes::new(&mut store.routes_cache[..]);
         routes
-            .add_default_ipv4_route(smoltcp::wire::Ipv4Address::UNSPECIFIED)
+            .add_default_ipv4_route(smoltcp::wire::Ipv4Address(gateway))
             .unwrap();
 
         let neighbor_cache =
@@ -656,11 +703,17 @@ pub fn setup(
         .neighbor_cache(neighbor_cache)
         .ip_addrs(&mut store.ip_addrs[..])
         .routes(routes)
+        .ipv4_multicast_groups(&mut store.ipv4_multicast_groups[..])
         .finalize();
 
-        if ip_addrs.is_unspecified() {
-            interface.add_socket(smoltcp::socket::Dhcpv4Socket::new());
-        }
+        // Note(unwrap): There is storage for the mDNS group membership.
+        let [a, b, c, d] = crate::net::mdns::MDNS_GROUP;
+        interface
+            .join_multicast_group(
+                smoltcp::wire::Ipv4Address::new(a, b, c, d),
+                smoltcp::time::Instant::from_millis(0),
+            )
+            .unwrap();
 
         for storage in store.tcp_socket_storage[..].iter_mut() {
             let tcp_socket = {
@@ -706,10 +759,22 @@ pub fn setup(
 
         stack.seed_random_port(&random_seed);
 
+        // The DHCP client is added after constructing the network stack so that the network
+        // processor handles its events, including the offered DNS servers.
+        let dhcp = network_config.dhcp.then(|| {
+            stack
+                .interface_mut()
+                .add_socket(smoltcp::socket::Dhcpv4Socket::new())
+        });
+
         NetworkDevices {
             stack,
             phy: lan8742a,
             mac_address: mac_addr,
+            config: network_config,
+            origin,
+            eeprom: eeprom_i2c,
+            dhcp,
         }
     };
 
@@ -973,6 +1038,10 @@ pub fn setup(
         None
     };
 
+    // Start the watchdog once the lengthy initialization is complete.
+    let watchdog = Watchdog::new(device.IWDG, watchdog::TIMEOUT_MS);
+    log::info!("Watchdog timeout: {} ms", watchdog::TIMEOUT_MS);
+
     let stabilizer = StabilizerDevices {
         systick,
         afes,
@@ -986,6 +1055,8 @@ pub fn setup(
         adc_dac_timer: sampling_timer,
         timestamp_timer,
         digital_inputs,
+        reset_reason,
+        watchdog,
     };
 
     // info!("Version {} {}", build_info::PKG_VERSION, build_info::GIT_VERSION.unwrap());
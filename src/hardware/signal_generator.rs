@@ -2,7 +2,9 @@ use miniconf::Miniconf;
 use serde::{Deserialize, Serialize};
 
 /// Types of signals that can be generated.
-#[derive(Copy, Clone, Debug, Deserialize, Serialize, Miniconf)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Miniconf,
+)]
 pub enum Signal {
     Cosine,
     Square,
@@ -17,7 +19,7 @@ pub enum Signal {
 /// Where `<signal>` may be any of [Signal] variants, `frequency` specifies the signal frequency
 /// in Hertz, `symmetry` specifies the normalized signal symmetry which ranges from 0 - 1.0, and
 /// `amplitude` specifies the signal amplitude in Volts.
-#[derive(Copy, Clone, Debug, Miniconf, Deserialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Miniconf, Deserialize)]
 pub struct BasicConfig {
     /// The signal type that should be generated. See [Signal] variants.
     pub signal: Signal,
@@ -172,6 +174,26 @@ impl SignalGenerator {
     pub fn clear_phase_accumulator(&mut self) {
         self.phase_accumulator = 0;
     }
+
+    /// Get the phase of the next sample, including the phase offset.
+    pub fn phase(&self) -> i32 {
+        self.phase_accumulator
+            .wrapping_add(self.config.phase_offset)
+    }
+
+    /// Get the phase increment per sample of the signal fundamental.
+    ///
+    /// # Note
+    /// For asymmetric signals, this is the harmonic mean of the increments of both half
+    /// oscillations and may deviate from the generated period due to rounding.
+    pub fn frequency(&self) -> i32 {
+        let [first, second] = self.config.phase_increment;
+        let sum = first as i64 + second as i64;
+        if sum == 0 {
+            return 0;
+        }
+        (2 * first as i64 * second as i64 / sum) as i32
+    }
 }
 
 impl core::iter::Iterator for SignalGenerator {
@@ -67,6 +67,7 @@ macro_rules! timer_channels {
                 timer: hal::timer::Timer<hal::stm32::[< $TY >]>,
                 channels: Option<[< $TY:lower >]::Channels>,
                 update_event: Option<[< $TY:lower >]::UpdateEvent>,
+                counter: Option<[< $TY:lower >]::Counter>,
             }
 
             impl $name {
@@ -84,6 +85,7 @@ macro_rules! timer_channels {
                         // one Timer2 and this resource takes ownership of it once instantiated.
                         channels: unsafe { Some([< $TY:lower >]::Channels::new()) },
                         update_event: unsafe { Some([< $TY:lower >]::UpdateEvent::new()) },
+                        counter: unsafe { Some([< $TY:lower >]::Counter::new()) },
                     }
                 }
 
@@ -99,6 +101,12 @@ macro_rules! timer_channels {
                     self.update_event.take().unwrap()
                 }
 
+                /// Get the timer counter.
+                #[allow(dead_code)]
+                pub fn counter(&mut self) -> [< $TY:lower >]::Counter {
+                    self.counter.take().unwrap()
+                }
+
                 /// Get the period of the timer.
                 #[allow(dead_code)]
                 pub fn get_period(&self) -> $size {
@@ -204,6 +212,28 @@ macro_rules! timer_channels {
                     }
                 }
 
+                /// The counter of the timer.
+                pub struct Counter {}
+
+                impl Counter {
+                    /// Create a new counter
+                    ///
+                    /// # Safety
+                    /// This is only safe to call once.
+                    #[allow(dead_code)]
+                    pub unsafe fn new() -> Self {
+                        Self {}
+                    }
+
+                    /// Get the current count of the timer.
+                    #[allow(dead_code)]
+                    pub fn get(&self) -> $size {
+                        // Note(unsafe): The counter register is only read.
+                        let regs = unsafe { &*<$TY>::ptr() };
+                        regs.cnt.read().cnt().bits()
+                    }
+                }
+
                 /// The channels representing the timer.
                 pub struct Channels {
                     pub ch1: Channel1,
@@ -399,3 +429,44 @@ timer_channels!(ShadowSamplingTimer, TIM3, u16);
 
 timer_channels!(TimestampTimer, TIM5, u32);
 timer_channels!(PounderTimestampTimer, TIM8, u16);
+
+/// The count of sampling timer ticks since the first sample, extended to 64 bits.
+///
+/// # Note
+/// The timestamp timer counts at the tick rate of the sampling timer, but does not wrap at the
+/// sample period. It has to be started together with the sampling timer. Its 32-bit count
+/// overflows every 43 seconds and is extended in software, so the count has to be latched at least
+/// once per overflow period, e.g. for every batch in the DSP routine.
+pub struct SampleCounter {
+    counter: tim5::Counter,
+    last: u32,
+    overflows: u32,
+}
+
+impl SampleCounter {
+    /// Construct the sample counter.
+    ///
+    /// # Args
+    /// * `counter` - The counter of the timestamp timer.
+    pub fn new(counter: tim5::Counter) -> Self {
+        Self {
+            counter,
+            last: 0,
+            overflows: 0,
+        }
+    }
+
+    /// Latch the current count.
+    ///
+    /// # Returns
+    /// The number of sampling timer ticks since the timestamp timer was started.
+    pub fn latch(&mut self) -> u64 {
+        let count = self.counter.get();
+        if count < self.last {
+            self.overflows = self.overflows.wrapping_add(1);
+        }
+        self.last = count;
+
+        ((self.overflows as u64) << 32) | count as u64
+    }
+}
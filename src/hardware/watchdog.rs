@@ -0,0 +1,101 @@
+///! Independent watchdog
+///!
+///! # Design
+///! The independent watchdog (IWDG) resets the device unless it is fed within its timeout. It is
+///! clocked by the LSI oscillator and can not be stopped once started.
+///!
+///! The watchdog is fed from the idle loop, which processes the network, but only if the DSP
+///! routine checked in since the last feed. A stall of either the network processing or the DSP
+///! routine, or a panic, which halts the firmware, therefore resets the device after the timeout.
+///! The DAC outputs are in the safe state of [SAFE_OUTPUT](super::dac::SAFE_OUTPUT) after the
+///! reset and also from a panic until the reset.
+///!
+///! The timeout in milliseconds is specified at build time by `WATCHDOG_TIMEOUT_MS`. It defaults to
+///! [DEFAULT_TIMEOUT_MS] and must not exceed [MAX_TIMEOUT_MS]. Invalid timeouts fail the build. A
+///! timeout of 0 disables the watchdog, e.g. for debugging.
+use core::sync::atomic::{AtomicBool, Ordering};
+use fugit::ExtU32;
+
+use super::hal::{self, independent_watchdog::IndependentWatchdog};
+
+/// The default watchdog timeout in milliseconds.
+pub const DEFAULT_TIMEOUT_MS: u32 = 1_000;
+
+/// The maximum watchdog timeout in milliseconds at the LSI frequency of 32 kHz.
+pub const MAX_TIMEOUT_MS: u32 = 32_000;
+
+// Set by the DSP routine and cleared upon feeding the watchdog.
+static DSP_ALIVE: AtomicBool = AtomicBool::new(false);
+
+/// The watchdog timeout in milliseconds specified at build time or 0 if the watchdog is disabled.
+pub const TIMEOUT_MS: u32 = match option_env!("WATCHDOG_TIMEOUT_MS") {
+    Some(timeout) => parse_timeout(timeout),
+    None => DEFAULT_TIMEOUT_MS,
+};
+
+// Parse a decimal timeout in milliseconds. Evaluated at compile time, an invalid timeout fails the
+// build.
+const fn parse_timeout(timeout: &str) -> u32 {
+    let digits = timeout.as_bytes();
+    assert!(!digits.is_empty(), "WATCHDOG_TIMEOUT_MS is empty");
+
+    let mut value = 0;
+    let mut i = 0;
+    while i < digits.len() {
+        assert!(
+            digits[i].is_ascii_digit(),
+            "WATCHDOG_TIMEOUT_MS is not a decimal number"
+        );
+        value = value * 10 + (digits[i] - b'0') as u32;
+        assert!(
+            value <= MAX_TIMEOUT_MS,
+            "WATCHDOG_TIMEOUT_MS exceeds MAX_TIMEOUT_MS"
+        );
+        i += 1;
+    }
+
+    value
+}
+
+/// Indicate that the DSP routine is executing.
+///
+/// # Note
+/// This must be called from the DSP routine for every batch.
+#[inline(always)]
+pub fn check_in() {
+    DSP_ALIVE.store(true, Ordering::Relaxed);
+}
+
+/// The independent watchdog fed by the idle loop.
+pub struct Watchdog {
+    iwdg: Option<IndependentWatchdog>,
+}
+
+impl Watchdog {
+    /// Start the independent watchdog.
+    ///
+    /// # Args
+    /// * `iwdg` - The IWDG peripheral.
+    /// * `timeout_ms` - The watchdog timeout in milliseconds. 0 disables the watchdog.
+    pub fn new(iwdg: hal::stm32::IWDG, timeout_ms: u32) -> Self {
+        let iwdg = (timeout_ms > 0).then(|| {
+            let mut watchdog = IndependentWatchdog::new(iwdg);
+            watchdog.start(timeout_ms.min(MAX_TIMEOUT_MS).millis());
+            watchdog
+        });
+
+        Self { iwdg }
+    }
+
+    /// Feed the watchdog if the DSP routine checked in since the last feed.
+    ///
+    /// # Note
+    /// This must be called regularly from the idle loop.
+    pub fn feed(&mut self) {
+        if let Some(iwdg) = &mut self.iwdg {
+            if DSP_ALIVE.swap(false, Ordering::Relaxed) {
+                iwdg.feed();
+            }
+        }
+    }
+}
@@ -0,0 +1,364 @@
+///! MQTT broker address resolution
+///!
+///! # Design
+///! The MQTT broker is specified either as an IPv4 address or as a hostname. Hostnames are resolved
+///! using the [DnsClient] once an IP address is available.
+///!
+///! All MQTT clients connect through a [BrokerStack], a wrapper of the shared network stack that
+///! directs their TCP connections to the current broker address and the configured port. Connection attempts fail with
+///! `NoIpAddress` until the broker is resolved. If no MQTT client succeeds to connect within
+///! [CONNECT_TIMEOUT_MS], e.g. because the address of the broker changed, the hostname is resolved
+///! again. The MQTT clients reconnect on their own and thereby follow the new address.
+///!
+///! # Note
+///! A DNS server in the network configuration takes precedence over the DNS servers offered by
+///! DHCP. Without either, the compiled-in DNS server is used.
+use core::cell::Cell;
+use cortex_m::interrupt::Mutex;
+use heapless::String;
+use serde::Serialize;
+use smoltcp_nal::embedded_nal::{
+    nb, IpAddr, Ipv4Addr, SocketAddr, TcpClientStack,
+};
+
+use super::{
+    dns::{self, DnsClient},
+    network_config,
+    sntp::SystemTime,
+    NetworkReference,
+};
+
+/// The broker is resolved again if no MQTT client connected within this period.
+pub const CONNECT_TIMEOUT_MS: u64 = 30_000;
+
+// The interval between failed resolutions.
+const RETRY_INTERVAL_MS: u64 = 5_000;
+
+/// The state of the broker address resolution.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum Resolution {
+    /// The broker is specified as an IP address.
+    Static,
+    /// The hostname of the broker is being resolved.
+    Resolving,
+    /// The hostname of the broker was resolved.
+    Resolved,
+    /// The last resolution of the hostname failed.
+    Failed,
+}
+
+/// The broker address resolution status reported in telemetry.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct BrokerStatus {
+    /// The state of the address resolution.
+    pub resolution: Resolution,
+
+    /// The broker address in use, if known.
+    pub address: Option<[u8; 4]>,
+
+    /// The number of successful resolutions since boot.
+    pub resolutions: u32,
+}
+
+#[derive(Copy, Clone, Default)]
+struct BrokerState {
+    address: Option<IpAddr>,
+    attempt: Option<u64>,
+    connected: bool,
+}
+
+/// The broker address shared by all MQTT clients.
+pub struct Broker {
+    time: &'static SystemTime,
+    port: u16,
+    state: Mutex<Cell<BrokerState>>,
+}
+
+impl Broker {
+    /// Construct the shared broker address.
+    ///
+    /// # Note
+    /// This may only be called once.
+    ///
+    /// # Args
+    /// * `time` - The device clock.
+    /// * `port` - The TCP port of the broker.
+    pub fn new(time: &'static SystemTime, port: u16) -> &'static Self {
+        cortex_m::singleton!(: Broker = Broker {
+            time,
+            port,
+            state: Mutex::new(Cell::new(BrokerState::default())),
+        })
+        .unwrap()
+    }
+
+    fn modify<R>(&self, f: impl FnOnce(&mut BrokerState) -> R) -> R {
+        cortex_m::interrupt::free(|cs| {
+            let cell = self.state.borrow(cs);
+            let mut state = cell.get();
+            let result = f(&mut state);
+            cell.set(state);
+            result
+        })
+    }
+
+    fn address(&self) -> Option<IpAddr> {
+        self.modify(|state| state.address)
+    }
+
+    fn set_address(&self, address: IpAddr) {
+        self.modify(|state| {
+            state.address.replace(address);
+            state.attempt.take();
+        })
+    }
+
+    // Get the address to connect to and note the start of unsuccessful connection attempts.
+    fn connect(&self) -> Option<IpAddr> {
+        let now = self.time.now();
+        self.modify(|state| {
+            state.attempt.get_or_insert(now);
+            state.address
+        })
+    }
+
+    fn connected(&self) {
+        self.modify(|state| {
+            state.attempt.take();
+            state.connected = true;
+        })
+    }
+
+    // Restart the timeout of unsuccessful connection attempts.
+    fn retry(&self) {
+        self.modify(|state| state.attempt.take());
+    }
+
+    /// Check whether any MQTT client connected to the broker since boot.
+    pub fn has_connected(&self) -> bool {
+        self.modify(|state| state.connected)
+    }
+
+    // Check whether connection attempts are failing for longer than the timeout.
+    fn unreachable(&self, now: u64) -> bool {
+        self.modify(|state| {
+            state
+                .attempt
+                .map(|attempt| now - attempt >= CONNECT_TIMEOUT_MS)
+                .unwrap_or(false)
+        })
+    }
+}
+
+/// The broker address passed to MQTT clients using a [BrokerStack].
+///
+/// # Note
+/// The address is replaced by the current broker address upon connection.
+pub fn unresolved() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))
+}
+
+/// A network stack directing TCP connections to the current broker address.
+pub struct BrokerStack {
+    stack: NetworkReference,
+    broker: &'static Broker,
+}
+
+impl BrokerStack {
+    /// Construct a new broker stack.
+    ///
+    /// # Args
+    /// * `stack` - A reference to the shared network stack.
+    /// * `broker` - The shared broker address.
+    pub fn new(stack: NetworkReference, broker: &'static Broker) -> Self {
+        Self { stack, broker }
+    }
+}
+
+impl TcpClientStack for BrokerStack {
+    type TcpSocket = <NetworkReference as TcpClientStack>::TcpSocket;
+    type Error = <NetworkReference as TcpClientStack>::Error;
+
+    fn socket(&mut self) -> Result<Self::TcpSocket, Self::Error> {
+        self.stack.socket()
+    }
+
+    fn connect(
+        &mut self,
+        socket: &mut Self::TcpSocket,
+        _remote: SocketAddr,
+    ) -> nb::Result<(), Self::Error> {
+        let address = self
+            .broker
+            .connect()
+            .ok_or(nb::Error::Other(smoltcp_nal::NetworkError::NoIpAddress))?;
+        self.stack
+            .connect(socket, SocketAddr::new(address, self.broker.port))
+    }
+
+    fn is_connected(
+        &mut self,
+        socket: &Self::TcpSocket,
+    ) -> Result<bool, Self::Error> {
+        let connected = self.stack.is_connected(socket)?;
+        if connected {
+            self.broker.connected();
+        }
+        Ok(connected)
+    }
+
+    fn send(
+        &mut self,
+        socket: &mut Self::TcpSocket,
+        buffer: &[u8],
+    ) -> nb::Result<usize, Self::Error> {
+        self.stack.send(socket, buffer)
+    }
+
+    fn receive(
+        &mut self,
+        socket: &mut Self::TcpSocket,
+        buffer: &mut [u8],
+    ) -> nb::Result<usize, Self::Error> {
+        self.stack.receive(socket, buffer)
+    }
+
+    fn close(&mut self, socket: Self::TcpSocket) -> Result<(), Self::Error> {
+        self.stack.close(socket)
+    }
+}
+
+/// Resolves the broker hostname and keeps the shared broker address up to date.
+pub struct BrokerResolver {
+    broker: &'static Broker,
+    time: &'static SystemTime,
+    hostname: Option<String<64>>,
+    dns: DnsClient,
+    dns_server: Option<IpAddr>,
+    resolving: bool,
+    failed: bool,
+    next_resolution: u64,
+    resolutions: u32,
+}
+
+impl BrokerResolver {
+    /// Construct a new broker resolver.
+    ///
+    /// # Args
+    /// * `stack` - A reference to the shared network stack.
+    /// * `broker` - The shared broker address to maintain.
+    /// * `time` - The device clock.
+    /// * `host` - The IP address or hostname of the broker.
+    /// * `dns_server` - The configured DNS server, if any.
+    pub fn new(
+        stack: NetworkReference,
+        broker: &'static Broker,
+        time: &'static SystemTime,
+        host: &str,
+        dns_server: Option<IpAddr>,
+    ) -> Self {
+        let hostname = match host.parse() {
+            Ok(address) => {
+                broker.set_address(address);
+                None
+            }
+            Err(_) => Some(String::from(host)),
+        };
+
+        Self {
+            broker,
+            time,
+            hostname,
+            dns: DnsClient::new(
+                stack,
+                dns_server.unwrap_or_else(network_config::default_dns_server),
+            ),
+            dns_server,
+            resolving: true,
+            failed: false,
+            next_resolution: 0,
+            resolutions: 0,
+        }
+    }
+
+    /// Use the DNS server offered by DHCP unless a DNS server is configured.
+    ///
+    /// # Args
+    /// * `offered` - The DNS server offered by DHCP, if any. Without it, the compiled-in DNS server
+    ///   is used.
+    pub fn set_offered_dns_server(&mut self, offered: Option<Ipv4Addr>) {
+        let server = self
+            .dns_server
+            .or_else(|| offered.map(IpAddr::V4))
+            .unwrap_or_else(network_config::default_dns_server);
+        self.dns.set_server(server);
+    }
+
+    /// Get the current broker address resolution status.
+    pub fn status(&self) -> BrokerStatus {
+        let resolution = match (&self.hostname, self.resolving, self.failed) {
+            (None, _, _) => Resolution::Static,
+            (Some(_), _, true) => Resolution::Failed,
+            (Some(_), true, _) => Resolution::Resolving,
+            (Some(_), false, _) => Resolution::Resolved,
+        };
+
+        BrokerStatus {
+            resolution,
+            address: match self.broker.address() {
+                Some(IpAddr::V4(address)) => Some(address.octets()),
+                _ => None,
+            },
+            resolutions: self.resolutions,
+        }
+    }
+
+    /// Update the broker resolver.
+    ///
+    /// # Note
+    /// This function must be called regularly to resolve the broker hostname.
+    pub fn update(&mut self) {
+        let hostname = match &self.hostname {
+            Some(hostname) => hostname,
+            None => return,
+        };
+
+        let now = self.time.now();
+
+        if !self.resolving && self.broker.unreachable(now) {
+            log::warn!("Broker unreachable, resolving {} again", hostname);
+            self.broker.retry();
+            self.resolving = true;
+        }
+
+        if !self.resolving || now < self.next_resolution {
+            return;
+        }
+
+        match self.dns.resolve(hostname, now) {
+            Ok(address) => {
+                log::info!("Resolved broker {} to {}", hostname, address);
+                self.broker.set_address(address);
+                self.resolving = false;
+                self.failed = false;
+                self.resolutions += 1;
+            }
+            Err(nb::Error::WouldBlock) => {}
+            // Resolution is not possible until an IP address is available.
+            Err(nb::Error::Other(dns::Error::Network)) => {
+                self.next_resolution = now + RETRY_INTERVAL_MS;
+            }
+            Err(nb::Error::Other(error)) => {
+                if !self.failed {
+                    log::warn!(
+                        "Failed to resolve broker {}: {:?}",
+                        hostname,
+                        error
+                    );
+                }
+                self.failed = true;
+                self.next_resolution = now + RETRY_INTERVAL_MS;
+            }
+        }
+    }
+}
@@ -0,0 +1,507 @@
+///! Stabilizer triggered data capture
+///!
+///! # Design
+///! In contrast to the best-effort livestream, a capture records a fixed-length window of ADC and
+///! DAC samples into a RAM buffer and uploads it reliably afterwards.
+///!
+///! The [Capture] recorder is updated from the DSP routine with every batch. Once armed, it
+///! continuously records into a ring buffer until the configured [Trigger] condition is met and
+///! the post-trigger part of the window has been recorded. The recorder then stops recording and
+///! the completed window is uploaded over MQTT by the [CaptureClient] in chunks. Once the upload
+///! is complete, the recorder may be armed again.
+///!
+///! Digital input triggers are evaluated once per batch and thus have batch resolution. ADC level
+///! triggers are evaluated for every sample.
+///!
+///! ## Upload format
+///! The window is published to the `<prefix>/capture` topic in chunks. Each chunk consists of the
+///! following header, all in little-endian:
+///!
+///! * **Offset** <u32>: The index of the first sample in the chunk within the window.
+///! * **Length** <u32>: The total number of samples in the window.
+///! * **Pre-trigger** <u32>: The index of the trigger sample within the window.
+///!
+///! The header is followed by the samples of the chunk. Each sample consists of the ADC0, ADC1,
+///! DAC0, and DAC1 codes as <u16> in the same format as
+///! [super::data_stream::StreamFormat::AdcDacData].
+///!
+///! ## Reliability
+///! Chunks are published with QoS 1 (at least once) and only one chunk is in flight at any time:
+///! the next chunk is published once the broker acknowledged the previous one. The MQTT client
+///! retransmits an unacknowledged chunk upon reconnection. If the broker reset the session, the
+///! upload resumes at the lost chunk. The capture is released once the last chunk is acknowledged.
+///! Receivers should identify chunks by their offset since a chunk may be delivered more than once.
+use core::sync::atomic::{AtomicBool, Ordering};
+use heapless::{String, Vec};
+use miniconf::MiniconfAtomic;
+use minimq::{QoS, Retain};
+use serde::{Deserialize, Serialize};
+
+use super::broker::{self, BrokerStack};
+use crate::hardware::{adc::AdcCode, afe::Gain, SystemTimer};
+
+/// The number of samples in a capture window.
+pub const CAPTURE_LENGTH: usize = 1 << 15;
+
+/// The maximum number of samples uploaded in a single MQTT message.
+pub const CHUNK_LENGTH: usize = 32;
+
+/// A captured sample of ADC0, ADC1, DAC0 and DAC1 codes.
+pub type Sample = [u16; 4];
+
+// The size of a chunk payload in bytes. Each chunk has a header of three 32-bit words.
+const CHUNK_SIZE: usize = 12 + CHUNK_LENGTH * core::mem::size_of::<Sample>();
+
+// Static storage for the capture window. Note that the contents of AXI SRAM are uninitialized.
+#[link_section = ".axisram.capture"]
+static mut CAPTURE_BUFFER: [Sample; CAPTURE_LENGTH] = [[0; 4]; CAPTURE_LENGTH];
+
+/// The condition that triggers a capture.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Trigger {
+    /// No capture is recorded.
+    Disabled,
+    /// Trigger as soon as the capture is armed and the pre-trigger window has been recorded.
+    Immediate,
+    /// Trigger on a rising edge of DI0.
+    Di0Rising,
+    /// Trigger on a falling edge of DI0.
+    Di0Falling,
+    /// Trigger on a rising edge of DI1.
+    Di1Rising,
+    /// Trigger on a falling edge of DI1.
+    Di1Falling,
+    /// Trigger when ADC0 crosses the trigger level upwards.
+    Adc0Rising,
+    /// Trigger when ADC0 crosses the trigger level downwards.
+    Adc0Falling,
+    /// Trigger when ADC1 crosses the trigger level upwards.
+    Adc1Rising,
+    /// Trigger when ADC1 crosses the trigger level downwards.
+    Adc1Falling,
+}
+
+/// Configuration of a triggered capture.
+///
+/// # Miniconf
+/// `{"trigger": <trigger>, "level": <level>, "pre_trigger": <n>}`
+///
+/// * `<trigger>` is any of the [Trigger] variants enclosed in double quotes.
+/// * `<level>` is the ADC trigger level in volts at the input (including the AFE gain).
+/// * `<n>` is the number of samples in the window before the trigger. Must be less than
+///   [CAPTURE_LENGTH].
+///
+/// Every update of the configuration (re-)arms the capture.
+///
+/// ## Example
+/// `{"trigger": "Adc0Rising", "level": 0.5, "pre_trigger": 1024}`
+#[derive(Copy, Clone, Debug, MiniconfAtomic, Serialize, Deserialize)]
+pub struct CaptureConfig {
+    pub trigger: Trigger,
+    pub level: f32,
+    pub pre_trigger: u32,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            trigger: Trigger::Disabled,
+            level: 0.0,
+            pre_trigger: 0,
+        }
+    }
+}
+
+/// Errors that can occur when arming a capture.
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    /// The pre-trigger length is not less than the capture window.
+    InvalidPreTrigger,
+    /// The trigger level is outside of the ADC range.
+    InvalidLevel,
+    /// The previous capture has not been uploaded yet.
+    Busy,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum State {
+    Idle,
+    Armed,
+    Triggered { remaining: usize },
+    Complete,
+}
+
+/// The capture recorder.
+pub struct Capture {
+    buffer: &'static mut [Sample; CAPTURE_LENGTH],
+    state: State,
+    trigger: Trigger,
+    level: i16,
+    pre_trigger: usize,
+    index: usize,
+    filled: usize,
+    start: usize,
+    last_adcs: [i16; 2],
+    last_digital_inputs: [bool; 2],
+}
+
+/// Configure the capture recorder.
+///
+/// # Note
+/// This function may only be called once as it takes ownership of the capture buffer.
+///
+/// # Returns
+/// The capture recorder, which can be updated with sample batches.
+pub fn setup_capture() -> Capture {
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+    assert!(!TAKEN.swap(true, Ordering::Relaxed));
+
+    // Note(unsafe): We guarantee above that CAPTURE_BUFFER is only accessed once.
+    Capture::new(unsafe { &mut CAPTURE_BUFFER })
+}
+
+impl Capture {
+    fn new(buffer: &'static mut [Sample; CAPTURE_LENGTH]) -> Self {
+        Self {
+            buffer,
+            state: State::Idle,
+            trigger: Trigger::Disabled,
+            level: 0,
+            pre_trigger: 0,
+            index: 0,
+            filled: 0,
+            start: 0,
+            last_adcs: [0; 2],
+            last_digital_inputs: [false; 2],
+        }
+    }
+
+    /// Arm the recorder.
+    ///
+    /// # Note
+    /// A completed capture that is still being uploaded can not be discarded by re-arming.
+    ///
+    /// # Args
+    /// * `config` - The capture configuration. [Trigger::Disabled] disarms the recorder.
+    /// * `afe` - The current AFE gains of ADC0/ADC1 used to convert the trigger level.
+    pub fn arm(
+        &mut self,
+        config: &CaptureConfig,
+        afe: [Gain; 2],
+    ) -> Result<(), Error> {
+        let pre_trigger = config.pre_trigger as usize;
+        if pre_trigger >= CAPTURE_LENGTH {
+            return Err(Error::InvalidPreTrigger);
+        }
+
+        let channel = match config.trigger {
+            Trigger::Adc1Rising | Trigger::Adc1Falling => 1,
+            _ => 0,
+        };
+        let level =
+            AdcCode::try_from(config.level * afe[channel].as_multiplier())
+                .or(Err(Error::InvalidLevel))?;
+
+        if self.state == State::Complete {
+            return Err(Error::Busy);
+        }
+
+        self.trigger = config.trigger;
+        self.level = level.into();
+        self.pre_trigger = pre_trigger;
+        self.filled = 0;
+        self.state = if config.trigger == Trigger::Disabled {
+            State::Idle
+        } else {
+            State::Armed
+        };
+
+        Ok(())
+    }
+
+    /// Check if a completed capture is available for upload.
+    pub fn is_complete(&self) -> bool {
+        self.state == State::Complete
+    }
+
+    /// Get the number of samples before the trigger in the completed capture.
+    pub fn pre_trigger(&self) -> usize {
+        self.pre_trigger
+    }
+
+    /// Read samples of the completed capture.
+    ///
+    /// # Args
+    /// * `offset` - The index of the first sample to read within the window.
+    /// * `samples` - The destination of the samples.
+    ///
+    /// # Returns
+    /// The number of samples read.
+    pub fn read(&self, offset: usize, samples: &mut [Sample]) -> usize {
+        if !self.is_complete() || offset >= CAPTURE_LENGTH {
+            return 0;
+        }
+
+        let len = samples.len().min(CAPTURE_LENGTH - offset);
+        for (i, sample) in samples[..len].iter_mut().enumerate() {
+            *sample = self.buffer[(self.start + offset + i) % CAPTURE_LENGTH];
+        }
+
+        len
+    }
+
+    /// Release the buffer of a completed capture after upload.
+    pub fn release(&mut self) {
+        if self.is_complete() {
+            self.state = State::Idle;
+        }
+    }
+
+    fn is_triggered(
+        &self,
+        channel: usize,
+        sample: i16,
+        digital_edges: [Option<bool>; 2],
+        first: bool,
+    ) -> bool {
+        let crossing = |adc: usize, rising: bool| {
+            let last = self.last_adcs[adc];
+            if rising {
+                last < self.level && sample >= self.level
+            } else {
+                last > self.level && sample <= self.level
+            }
+        };
+
+        match self.trigger {
+            Trigger::Disabled => false,
+            Trigger::Immediate => true,
+            Trigger::Di0Rising => first && digital_edges[0] == Some(true),
+            Trigger::Di0Falling => first && digital_edges[0] == Some(false),
+            Trigger::Di1Rising => first && digital_edges[1] == Some(true),
+            Trigger::Di1Falling => first && digital_edges[1] == Some(false),
+            Trigger::Adc0Rising => channel == 0 && crossing(0, true),
+            Trigger::Adc0Falling => channel == 0 && crossing(0, false),
+            Trigger::Adc1Rising => channel == 1 && crossing(1, true),
+            Trigger::Adc1Falling => channel == 1 && crossing(1, false),
+        }
+    }
+
+    /// Record a batch of samples.
+    ///
+    /// # Args
+    /// * `adcs` - The ADC0 and ADC1 samples of the batch.
+    /// * `dacs` - The DAC0 and DAC1 codes of the batch.
+    /// * `digital_inputs` - The DI0 and DI1 states during the batch.
+    ///
+    /// # Returns
+    /// True if the capture completed with this batch.
+    pub fn update(
+        &mut self,
+        adcs: [&[u16]; 2],
+        dacs: [&[u16]; 2],
+        digital_inputs: [bool; 2],
+    ) -> bool {
+        let edges = [0, 1].map(|i| {
+            (digital_inputs[i] != self.last_digital_inputs[i])
+                .then(|| digital_inputs[i])
+        });
+        self.last_digital_inputs = digital_inputs;
+
+        if !matches!(self.state, State::Armed | State::Triggered { .. }) {
+            return false;
+        }
+
+        for (i, (((&adc0, &adc1), &dac0), &dac1)) in adcs[0]
+            .iter()
+            .zip(adcs[1].iter())
+            .zip(dacs[0].iter())
+            .zip(dacs[1].iter())
+            .enumerate()
+        {
+            self.buffer[self.index] = [adc0, adc1, dac0, dac1];
+            let position = self.index;
+            self.index = (self.index + 1) % CAPTURE_LENGTH;
+
+            let sample = [adc0 as i16, adc1 as i16];
+
+            match self.state {
+                State::Armed => {
+                    if self.filled >= self.pre_trigger
+                        && (self.is_triggered(0, sample[0], edges, i == 0)
+                            || self.is_triggered(1, sample[1], edges, i == 0))
+                    {
+                        self.start = (position + CAPTURE_LENGTH
+                            - self.pre_trigger)
+                            % CAPTURE_LENGTH;
+                        self.state = State::Triggered {
+                            remaining: CAPTURE_LENGTH - self.pre_trigger - 1,
+                        };
+                    }
+                    self.filled = (self.filled + 1).min(CAPTURE_LENGTH);
+                }
+                State::Triggered { remaining } => {
+                    self.state = State::Triggered {
+                        remaining: remaining - 1,
+                    };
+                }
+                _ => {}
+            }
+
+            self.last_adcs = sample;
+
+            if self.state == (State::Triggered { remaining: 0 }) {
+                self.state = State::Complete;
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+/// Errors that can occur when uploading a capture.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UploadError {
+    /// The previous chunk is not yet acknowledged. The upload should be retried later.
+    Busy,
+    /// The chunk at the contained offset was lost. The upload should resume at this offset.
+    Resend(usize),
+}
+
+/// The capture client for uploading completed captures over MQTT.
+pub struct CaptureClient {
+    mqtt: minimq::Minimq<BrokerStack, SystemTimer, 512, 1>,
+    capture_topic: String<128>,
+    // The offset of the chunk awaiting acknowledgement.
+    pending: Option<usize>,
+    // The offset of an unacknowledged chunk that was lost upon a session reset.
+    lost: Option<usize>,
+}
+
+impl CaptureClient {
+    /// Construct a new capture client.
+    ///
+    /// # Args
+    /// * `stack` - A reference to the (shared) underlying network stack directing connections to
+    ///   the broker.
+    /// * `clock` - A `SystemTimer` implementing `Clock`.
+    /// * `client_id` - The MQTT client ID of the capture client.
+    /// * `prefix` - The device prefix to use for MQTT capture uploads.
+    ///
+    /// # Returns
+    /// A new capture client.
+    pub fn new(
+        stack: BrokerStack,
+        clock: SystemTimer,
+        client_id: &str,
+        prefix: &str,
+    ) -> Self {
+        let mqtt =
+            minimq::Minimq::new(broker::unresolved(), client_id, stack, clock)
+                .unwrap();
+
+        let mut capture_topic: String<128> = String::from(prefix);
+        capture_topic.push_str("/capture").unwrap();
+
+        Self {
+            mqtt,
+            capture_topic,
+            pending: None,
+            lost: None,
+        }
+    }
+
+    // Check the acknowledgement of the pending chunk.
+    fn check_pending(&mut self) -> Result<(), UploadError> {
+        if let Some(offset) = self.lost.take() {
+            return Err(UploadError::Resend(offset));
+        }
+
+        // Only a single chunk is in flight, so the client can publish once it is acknowledged.
+        if self.pending.is_some() {
+            if !self.mqtt.client.can_publish(QoS::AtLeastOnce) {
+                return Err(UploadError::Busy);
+            }
+            self.pending.take();
+        }
+
+        Ok(())
+    }
+
+    /// Upload a chunk of a completed capture.
+    ///
+    /// # Args
+    /// * `offset` - The index of the first sample of the chunk within the window.
+    /// * `pre_trigger` - The number of samples before the trigger in the window.
+    /// * `samples` - The samples of the chunk. See [Capture::read].
+    ///
+    /// # Returns
+    /// `Err` if the chunk could not be published. See [UploadError].
+    pub fn upload(
+        &mut self,
+        offset: usize,
+        pre_trigger: usize,
+        samples: &[Sample],
+    ) -> Result<(), UploadError> {
+        self.check_pending()?;
+
+        let mut payload: Vec<u8, CHUNK_SIZE> = Vec::new();
+        for word in [offset, CAPTURE_LENGTH, pre_trigger] {
+            payload
+                .extend_from_slice(&(word as u32).to_le_bytes())
+                .unwrap();
+        }
+        // Note(unwrap): Chunks are read with at most CHUNK_LENGTH samples.
+        for code in samples.iter().flatten() {
+            payload.extend_from_slice(&code.to_le_bytes()).unwrap();
+        }
+
+        self.mqtt
+            .client
+            .publish(
+                &self.capture_topic,
+                &payload,
+                QoS::AtLeastOnce,
+                Retain::NotRetained,
+                &[],
+            )
+            .or(Err(UploadError::Busy))?;
+
+        self.pending.replace(offset);
+        Ok(())
+    }
+
+    /// Check whether all uploaded chunks are acknowledged.
+    ///
+    /// # Returns
+    /// `Err` if a chunk is awaiting acknowledgement or was lost. See [UploadError].
+    pub fn flush(&mut self) -> Result<(), UploadError> {
+        self.check_pending()
+    }
+
+    /// Update the capture client
+    ///
+    /// # Note
+    /// This function is provided to force the underlying MQTT state machine to process incoming
+    /// and outgoing messages. Without this, the client will never connect to the broker. This
+    /// should be called regularly.
+    pub fn update(&mut self) {
+        match self.mqtt.poll(|_client, _topic, _message, _properties| {}) {
+            Err(minimq::Error::Network(
+                smoltcp_nal::NetworkError::NoIpAddress,
+            )) => {}
+
+            // The in-flight chunk is not retransmitted if the broker reset the session.
+            Err(minimq::Error::SessionReset) => {
+                if let Some(offset) = self.pending.take() {
+                    log::warn!("Capture chunk at {} lost, resending", offset);
+                    self.lost.replace(offset);
+                }
+            }
+
+            Err(error) => log::info!("Unexpected error: {:?}", error),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,83 @@
+///! One-shot commands over MQTT
+///!
+///! # Design
+///! Actions such as clearing the crash log are commands rather than settings. Settings are
+///! typically published retained and are delivered again upon every connection to the broker,
+///! which would repeat the action after every reset and every reconnection.
+///!
+///! Commands are published to `<prefix>/command/<name>`, where `<name>` may address a channel,
+///! e.g. `iir_state/0`. The payload carries the JSON arguments of the command. Commands without
+///! arguments accept any non-empty payload, e.g. `true`. The telemetry client subscribes to the
+///! commands and queues them until they are handled.
+///!
+///! Upon reception, the telemetry client publishes an empty retained message to the topic of the
+///! command. This removes the command from the broker in case it was published retained, so that
+///! it is never delivered again. Empty messages are ignored.
+///!
+///! # Device Commands
+///! * `clear_crash_log`: Clear the crash log and remove the retained crash report from the broker.
+///! * `clear_stream_statistics`: Reset the stream statistics reported in telemetry.
+use heapless::{String, Vec};
+use serde::Deserialize;
+
+/// The maximum length of a command name.
+pub const NAME_SIZE: usize = 32;
+
+/// The maximum size of the payload of a command.
+pub const PAYLOAD_SIZE: usize = 128;
+
+/// The number of received commands queued until they are handled.
+pub const QUEUE_SIZE: usize = 4;
+
+/// A command received over MQTT.
+#[derive(Clone, Debug)]
+pub struct Command {
+    /// The name of the command, i.e. the topic below `<prefix>/command/`.
+    pub name: String<NAME_SIZE>,
+    /// The payload of the command.
+    pub payload: Vec<u8, PAYLOAD_SIZE>,
+}
+
+impl Command {
+    /// Construct a command from a received message.
+    ///
+    /// # Args
+    /// * `name` - The name of the command.
+    /// * `payload` - The payload of the command.
+    ///
+    /// # Returns
+    /// The command or `None` if the name or the payload are too long.
+    pub fn new(name: &str, payload: &[u8]) -> Option<Self> {
+        let mut command = Self {
+            name: String::new(),
+            payload: Vec::new(),
+        };
+        command.name.push_str(name).ok()?;
+        command.payload.extend_from_slice(payload).ok()?;
+        Some(command)
+    }
+
+    /// Get the channel addressed by a command.
+    ///
+    /// # Args
+    /// * `name` - The name of the command without the channel, e.g. `iir_state`.
+    /// * `channels` - The number of channels.
+    ///
+    /// # Returns
+    /// The channel of a command named `<name>/<n>` or `None` if the command has another name or
+    /// the channel does not exist.
+    pub fn channel(&self, name: &str, channels: usize) -> Option<usize> {
+        self.name
+            .strip_prefix(name)
+            .and_then(|channel| channel.strip_prefix('/'))
+            .and_then(|channel| channel.parse().ok())
+            .filter(|&channel| channel < channels)
+    }
+
+    /// Parse the JSON arguments of the command.
+    pub fn parse<'a, T: Deserialize<'a>>(
+        &'a self,
+    ) -> Result<T, serde_json_core::de::Error> {
+        serde_json_core::from_slice(&self.payload).map(|(args, _)| args)
+    }
+}
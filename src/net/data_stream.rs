@@ -4,6 +4,9 @@
 //! Data streamining utilizes UDP packets to send live data streams at high throughput.
 //! Packets are always sent in a best-effort fashion, and data may be dropped.
 //!
+//! Every frame is sent to all enabled stream targets, up to [STREAM_TARGETS]. Alternatively, a
+//! single IPv4 multicast group may be used as the target to reach any number of hosts.
+//!
 //! Stabilizer organizes livestreamed data into batches within a "Frame" that will be sent as a UDP
 //! packet. Each frame consits of a header followed by sequential batch serializations. The packet
 //! header is constant for all streaming capabilities, but the serialization format after the header
@@ -12,17 +15,43 @@
 //! ## Frame Header
 //! The header consists of the following, all in little-endian.
 //!
-//! * **Magic word 0x057B** <u16>: a constant to identify Stabilizer streaming data.
+//! * **Magic word 0x057C** <u16>: a constant to identify Stabilizer streaming data and the header
+//!   version.
 //! * **Format Code** <u8>: a unique ID that indicates the serialization format of each batch of data
 //!   in the frame. Refer to [StreamFormat] for further information.
 //! * **Batch Size** <u8>: the number of samples in each batch of data.
 //! * **Sequence Number** <u32>: an the sequence number of the first batch in the frame.
 //!   This can be used to determine if and how many stream batches are lost.
+//! * **Timestamp** <u64>: the sampling timer count latched by the DSP routine when processing the
+//!   first batch in the frame, extended to 64 bits. The count starts at zero with the first sample
+//!   after boot and increments at [crate::hardware::design_parameters::TIMER_FREQUENCY]. The first
+//!   sample of the batch was acquired one batch period plus the processing latency before.
+//! * **System Time** <u32>: the system monotonic time in milliseconds since boot at which the frame
+//!   was started.
+//! * **UTC Time** <u64>: the system time converted to milliseconds since the Unix epoch using the
+//!   offset determined by network time synchronization, or zero if the offset is not yet known.
+//!   See [crate::net::sntp] for details.
+//!
+//! ## Header Versions
+//! The magic word identifies the header version. Hosts should use it to select the header layout:
+//!
+//! * **0x057B**: the original 8 byte header consisting of magic word, format code, batch size and
+//!   sequence number only.
+//! * **0x057C**: the current 28 byte header including the timestamp, system time and UTC time.
+//!
+//! ## Statistics
+//! The stream keeps track of the number of frames sent and of data lost on the device, e.g. due to
+//! frame buffer exhaustion or failed transmissions. Refer to [StreamStatistics] for details. The
+//! statistics are reset whenever the stream is (re-)opened, e.g. when the stream target changes,
+//! and on demand by the `clear_stream_statistics` command (see [crate::net::command]).
+//! Data lost in the network is not captured by these statistics and has to be determined by the
+//! host from the sequence numbers.
 //!
 //! # Example
 //! A sample Python script is available in `scripts/stream_throughput.py` to demonstrate reception
 //! of livestreamed data.
 use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU32, Ordering};
 use heapless::{
     pool::{Box, Init, Pool, Uninit},
     spsc::{Consumer, Producer, Queue},
@@ -32,15 +61,17 @@ use num_enum::IntoPrimitive;
 use serde::{Deserialize, Serialize};
 use smoltcp_nal::embedded_nal::{IpAddr, Ipv4Addr, SocketAddr, UdpClientStack};
 
+use super::sntp::SystemTime;
 use super::NetworkReference;
 
-// Magic first bytes indicating a UDP frame of straming data
-const MAGIC: u16 = 0x057B;
+// Magic first bytes indicating a UDP frame of straming data with the timestamped header
+const MAGIC: u16 = 0x057C;
 
 // The size of the header, calculated in words.
-// The header has a 16-bit magic word, an 8-bit format, 8-bit batch-size, and 32-bit sequence
-// number, which corresponds to 8 bytes.
-const HEADER_SIZE: usize = 8;
+// The header has a 16-bit magic word, an 8-bit format, 8-bit batch-size, 32-bit sequence
+// number, 64-bit timestamp, 32-bit system time and 64-bit UTC time, which corresponds to 28
+// bytes.
+const HEADER_SIZE: usize = 28;
 
 // The number of frames that can be buffered.
 const FRAME_COUNT: usize = 4;
@@ -61,22 +92,28 @@ static mut FRAME_DATA: [u8; core::mem::size_of::<u8>()
 
 type Frame = [MaybeUninit<u8>; FRAME_SIZE];
 
+/// The number of simultaneous stream targets.
+pub const STREAM_TARGETS: usize = 2;
+
 /// Represents the destination for the UDP stream to send data to.
 ///
 /// # Miniconf
-/// `{"ip": <addr>, "port": <port>}`
+/// `{"ip": <addr>, "port": <port>, "enabled": <enabled>}`
 ///
-/// * `<addr>` is an array of 4 bytes. E.g. `[192, 168, 0, 1]`
+/// * `<addr>` is an array of 4 bytes. E.g. `[192, 168, 0, 1]`. This may also be an IPv4
+///   multicast group address, e.g. `[239, 0, 0, 1]`.
 /// * `<port>` is any unsigned 16-bit value.
+/// * `<enabled>` is `true` if data should be streamed to the target.
 ///
 /// ## Example
-/// `{"ip": [192, 168,0, 1], "port": 1111}`
+/// `{"ip": [192, 168,0, 1], "port": 1111, "enabled": true}`
 #[derive(
     Copy, Clone, Debug, MiniconfAtomic, Serialize, Deserialize, Default,
 )]
 pub struct StreamTarget {
     pub ip: [u8; 4],
     pub port: u16,
+    pub enabled: bool,
 }
 
 /// Specifies the format of streamed data
@@ -98,6 +135,60 @@ pub enum StreamFormat {
     /// Streamed data in FLS (fiber length stabilization) format. See the FLS application for
     /// detailed definition.
     Fls = 2,
+
+    /// Streamed data contains the demodulated lockin output (in-phase and quadrature) of each of
+    /// the three demodulators of both ADC inputs as well as the reference phase and reference
+    /// frequency of each batch as `i32` in little-endian format. Disabled demodulators are zero.
+    ///
+    /// # Example
+    /// Each batch is serialized as follows, independent of the batch size, where `<In.m>` is the
+    /// in-phase component of demodulator `m` of ADC input `n`:
+    /// ```
+    /// <I0.0> <Q0.0> <I0.1> <Q0.1> <I0.2> <Q0.2> <I1.0> <Q1.0> <I1.1> <Q1.1> <I1.2> <Q1.2>
+    /// <Reference phase> <Reference frequency>
+    /// ```
+    Lockin = 3,
+}
+
+/// Statistics of the data stream on the device side.
+#[derive(Copy, Clone, Debug, Default, Serialize)]
+pub struct StreamStatistics {
+    /// The number of frames sent.
+    pub frames_sent: u32,
+    /// The number of batches lost on the device, either because no frame buffer was available or
+    /// because sending their frame failed.
+    pub batches_dropped: u32,
+    /// The number of times a batch could not be added because all frame buffers were in use.
+    pub pool_exhausted: u32,
+    /// The number of frames that could not be sent.
+    pub send_failures: u32,
+}
+
+/// Counters shared between the generator and the stream.
+#[derive(Default)]
+struct StreamCounters {
+    frames_sent: AtomicU32,
+    batches_dropped: AtomicU32,
+    pool_exhausted: AtomicU32,
+    send_failures: AtomicU32,
+}
+
+impl StreamCounters {
+    fn statistics(&self) -> StreamStatistics {
+        StreamStatistics {
+            frames_sent: self.frames_sent.load(Ordering::Relaxed),
+            batches_dropped: self.batches_dropped.load(Ordering::Relaxed),
+            pool_exhausted: self.pool_exhausted.load(Ordering::Relaxed),
+            send_failures: self.send_failures.load(Ordering::Relaxed),
+        }
+    }
+
+    fn reset(&self) {
+        self.frames_sent.store(0, Ordering::Relaxed);
+        self.batches_dropped.store(0, Ordering::Relaxed);
+        self.pool_exhausted.store(0, Ordering::Relaxed);
+        self.send_failures.store(0, Ordering::Relaxed);
+    }
 }
 
 impl From<StreamTarget> for SocketAddr {
@@ -118,12 +209,14 @@ impl From<StreamTarget> for SocketAddr {
 ///
 /// # Args
 /// * `stack` - A reference to the shared network stack.
+/// * `time` - The device clock used to provide the system and UTC time of each frame.
 ///
 /// # Returns
 /// (generator, stream) where `generator` can be used to enqueue "batches" for transmission. The
 /// `stream` is the logically consumer (UDP transmitter) of the enqueued data.
 pub fn setup_streaming(
     stack: NetworkReference,
+    time: &'static SystemTime,
 ) -> (FrameGenerator, DataStream) {
     // The queue needs to be at least as large as the frame count to ensure that every allocated
     // frame can potentially be enqueued for transmission.
@@ -134,13 +227,17 @@ pub fn setup_streaming(
 
     let frame_pool = cortex_m::singleton!(: Pool<Frame> = Pool::new()).unwrap();
 
+    let counters =
+        cortex_m::singleton!(: StreamCounters = StreamCounters::default())
+            .unwrap();
+
     // Note(unsafe): We guarantee that FRAME_DATA is only accessed once in this function.
     let memory = unsafe { &mut FRAME_DATA };
     frame_pool.grow(memory);
 
-    let generator = FrameGenerator::new(producer, frame_pool);
+    let generator = FrameGenerator::new(producer, frame_pool, counters, time);
 
-    let stream = DataStream::new(stack, consumer, frame_pool);
+    let stream = DataStream::new(stack, consumer, frame_pool, counters);
 
     (generator, stream)
 }
@@ -149,6 +246,7 @@ pub fn setup_streaming(
 struct StreamFrame {
     buffer: Box<Frame, Init>,
     offset: usize,
+    batches: u32,
 }
 
 impl StreamFrame {
@@ -157,6 +255,9 @@ impl StreamFrame {
         format_id: u8,
         batch_size: u8,
         sequence_number: u32,
+        timestamp: u64,
+        system_time: u32,
+        utc_time: u64,
     ) -> Self {
         let mut buffer = buffer.init([MaybeUninit::uninit(); FRAME_SIZE]);
 
@@ -165,6 +266,9 @@ impl StreamFrame {
             .iter()
             .chain(&[format_id, batch_size])
             .chain(sequence_number.to_le_bytes().iter())
+            .chain(timestamp.to_le_bytes().iter())
+            .chain(system_time.to_le_bytes().iter())
+            .chain(utc_time.to_le_bytes().iter())
             .enumerate()
         {
             buffer[offset].write(*byte);
@@ -173,6 +277,7 @@ impl StreamFrame {
         Self {
             buffer,
             offset: HEADER_SIZE,
+            batches: 0,
         }
     }
 
@@ -183,6 +288,7 @@ impl StreamFrame {
         f(&mut self.buffer[self.offset..self.offset + T]);
 
         self.offset += T;
+        self.batches += 1;
     }
 
     pub fn is_full<const T: usize>(&self) -> bool {
@@ -202,16 +308,22 @@ pub struct FrameGenerator {
     sequence_number: u32,
     format: u8,
     batch_size: u8,
+    counters: &'static StreamCounters,
+    time: &'static SystemTime,
 }
 
 impl FrameGenerator {
     fn new(
         queue: Producer<'static, StreamFrame, FRAME_QUEUE_SIZE>,
         pool: &'static Pool<Frame>,
+        counters: &'static StreamCounters,
+        time: &'static SystemTime,
     ) -> Self {
         Self {
             queue,
             pool,
+            counters,
+            time,
             batch_size: 0,
             format: StreamFormat::Unknown.into(),
             current_frame: None,
@@ -237,9 +349,11 @@ impl FrameGenerator {
     /// Add a batch to the current stream frame.
     ///
     /// # Args
+    /// * `timestamp` - The sampling timer count latched by the DSP routine processing the batch.
+    ///   See [crate::hardware::timers::SampleCounter].
     /// * `f` - A closure that will be provided the buffer to write batch data into. The buffer will
     ///   be the size of the `T` template argument.
-    pub fn add<F, const T: usize>(&mut self, f: F)
+    pub fn add<F, const T: usize>(&mut self, timestamp: u64, f: F)
     where
         F: FnMut(&mut [MaybeUninit<u8>]),
     {
@@ -248,13 +362,23 @@ impl FrameGenerator {
 
         if self.current_frame.is_none() {
             if let Some(buffer) = self.pool.alloc() {
+                let system_time = self.time.now();
+                let utc_time = self.time.to_utc(system_time).unwrap_or(0);
+
                 self.current_frame.replace(StreamFrame::new(
                     buffer,
                     self.format as u8,
                     self.batch_size,
                     sequence_number,
+                    timestamp,
+                    system_time as u32,
+                    utc_time,
                 ));
             } else {
+                self.counters.pool_exhausted.fetch_add(1, Ordering::Relaxed);
+                self.counters
+                    .batches_dropped
+                    .fetch_add(1, Ordering::Relaxed);
                 return;
             }
         }
@@ -280,10 +404,12 @@ impl FrameGenerator {
 /// This is responsible for consuming data and sending it over UDP.
 pub struct DataStream {
     stack: NetworkReference,
-    socket: Option<<NetworkReference as UdpClientStack>::UdpSocket>,
+    sockets: [Option<<NetworkReference as UdpClientStack>::UdpSocket>;
+        STREAM_TARGETS],
     queue: Consumer<'static, StreamFrame, FRAME_QUEUE_SIZE>,
     frame_pool: &'static Pool<Frame>,
-    remote: SocketAddr,
+    remotes: [SocketAddr; STREAM_TARGETS],
+    counters: &'static StreamCounters,
 }
 
 impl DataStream {
@@ -293,87 +419,137 @@ impl DataStream {
     /// * `stack` - A reference to the shared network stack.
     /// * `consumer` - The read side of the queue containing data to transmit.
     /// * `frame_pool` - The Pool to return stream frame objects into.
+    /// * `counters` - The statistics counters shared with the generator.
     fn new(
         stack: NetworkReference,
         consumer: Consumer<'static, StreamFrame, FRAME_QUEUE_SIZE>,
         frame_pool: &'static Pool<Frame>,
+        counters: &'static StreamCounters,
     ) -> Self {
         Self {
             stack,
-            socket: None,
-            remote: StreamTarget::default().into(),
+            sockets: Default::default(),
+            remotes: [StreamTarget::default().into(); STREAM_TARGETS],
             queue: consumer,
             frame_pool,
+            counters,
         }
     }
 
-    fn close(&mut self) {
-        if let Some(socket) = self.socket.take() {
-            log::info!("Closing stream");
+    fn close(&mut self, index: usize) {
+        if let Some(socket) = self.sockets[index].take() {
+            log::info!("Closing stream {}", index);
             // Note(unwrap): We guarantee that the socket is available above.
             self.stack.close(socket).unwrap();
         }
     }
 
     // Open new socket.
-    fn open(&mut self) -> Result<(), ()> {
+    fn open(&mut self, index: usize) -> Result<(), ()> {
         // If there is already a socket of if remote address is unspecified,
         // do not open a new socket.
-        if self.socket.is_some() || self.remote.ip().is_unspecified() {
+        if self.sockets[index].is_some()
+            || self.remotes[index].ip().is_unspecified()
+        {
             return Err(());
         }
 
-        log::info!("Opening stream");
-
         let mut socket = self.stack.socket().or(Err(()))?;
 
+        log::info!("Opening stream {}", index);
+
         // Note(unwrap): We only connect with a new socket, so it is guaranteed to not already be
         // bound.
-        self.stack.connect(&mut socket, self.remote).unwrap();
+        self.stack
+            .connect(&mut socket, self.remotes[index])
+            .unwrap();
 
-        self.socket.replace(socket);
+        self.sockets[index].replace(socket);
 
         Ok(())
     }
 
-    /// Configure the remote endpoint of the stream.
+    /// Configure the remote endpoints of the stream.
     ///
     /// # Args
-    /// * `remote` - The destination to send stream data to.
-    pub fn set_remote(&mut self, remote: SocketAddr) {
-        // Close socket to be reopened if the remote has changed.
-        if remote != self.remote {
-            self.close();
+    /// * `targets` - The destinations to send stream data to. Disabled targets are closed.
+    pub fn set_remotes(&mut self, targets: &[StreamTarget; STREAM_TARGETS]) {
+        for (index, target) in targets.iter().enumerate() {
+            let remote = if target.enabled {
+                (*target).into()
+            } else {
+                StreamTarget::default().into()
+            };
+
+            // Close socket to be reopened if the remote has changed.
+            if remote != self.remotes[index] {
+                self.close(index);
+            }
+            self.remotes[index] = remote;
         }
-        self.remote = remote;
+    }
+
+    /// Get the current stream statistics.
+    pub fn statistics(&self) -> StreamStatistics {
+        self.counters.statistics()
+    }
+
+    /// Reset the stream statistics.
+    pub fn clear_statistics(&self) {
+        self.counters.reset();
     }
 
     /// Process any data for transmission.
     pub fn process(&mut self) {
-        match self.socket.as_mut() {
-            None => {
-                // If there's no socket available, try to connect to our remote.
-                if self.open().is_ok() {
-                    // If we just successfully opened the socket, flush old data from queue.
-                    while let Some(frame) = self.queue.dequeue() {
-                        self.frame_pool.free(frame.buffer);
-                    }
+        let was_open = self.sockets.iter().any(Option::is_some);
+
+        // Try to connect to any newly configured remotes.
+        for index in 0..STREAM_TARGETS {
+            self.open(index).ok();
+        }
+
+        if !was_open {
+            if self.sockets.iter().any(Option::is_some) {
+                // If we just successfully opened the first socket, flush old data from queue.
+                while let Some(frame) = self.queue.dequeue() {
+                    self.frame_pool.free(frame.buffer);
                 }
+
+                // Data generated before the stream was opened is not considered lost.
+                self.counters.reset();
             }
-            Some(handle) => {
-                if let Some(frame) = self.queue.dequeue() {
-                    // Transmit the frame and return it to the pool.
-                    let buf = frame.finish();
-                    let data = unsafe {
-                        core::slice::from_raw_parts(
-                            buf.as_ptr() as *const u8,
-                            buf.len() * core::mem::size_of::<MaybeUninit<u8>>(),
-                        )
-                    };
-                    self.stack.send(handle, data).ok();
-                    self.frame_pool.free(frame.buffer)
+
+            return;
+        }
+
+        if let Some(frame) = self.queue.dequeue() {
+            // Transmit the frame to all targets and return it to the pool.
+            let buf = frame.finish();
+            let data = unsafe {
+                core::slice::from_raw_parts(
+                    buf.as_ptr() as *const u8,
+                    buf.len() * core::mem::size_of::<MaybeUninit<u8>>(),
+                )
+            };
+
+            let mut sent = false;
+            for handle in self.sockets.iter_mut().flatten() {
+                if self.stack.send(handle, data).is_ok() {
+                    sent = true;
+                } else {
+                    self.counters.send_failures.fetch_add(1, Ordering::Relaxed);
                 }
             }
+
+            if sent {
+                self.counters.frames_sent.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.counters
+                    .batches_dropped
+                    .fetch_add(frame.batches, Ordering::Relaxed);
+            }
+
+            self.frame_pool.free(frame.buffer)
         }
     }
 }
@@ -0,0 +1,288 @@
+///! Minimal DNS client
+///!
+///! # Design
+///! Hostnames are resolved to IPv4 addresses by querying a recursive DNS server for A records over
+///! UDP (RFC 1035). Only a single query is outstanding at any time. Unanswered queries time out
+///! after [QUERY_TIMEOUT_MS] and are reported as failed, leaving retries to the caller.
+///!
+///! The response is matched to the query by its ID. CNAME records in the answer section are
+///! skipped, since recursive servers include the A records of the canonical name.
+use smoltcp_nal::embedded_nal::{
+    nb, IpAddr, Ipv4Addr, SocketAddr, UdpClientStack,
+};
+
+use super::NetworkReference;
+
+// The UDP port of the DNS server.
+const DNS_PORT: u16 = 53;
+
+// The maximum size of a DNS message over UDP.
+const MESSAGE_SIZE: usize = 512;
+
+// The size of the DNS message header.
+const HEADER_SIZE: usize = 12;
+
+// The maximum length of a hostname.
+const MAX_NAME_LENGTH: usize = 253;
+
+// The maximum length of a label within a hostname.
+const MAX_LABEL_LENGTH: usize = 63;
+
+// The record type and class of IPv4 host addresses.
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+/// Unanswered queries are considered lost after this period.
+pub const QUERY_TIMEOUT_MS: u64 = 2_000;
+
+/// Represents errors that can occur when resolving a hostname.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The hostname is not valid.
+    InvalidName,
+    /// The query could not be sent, e.g. because no IP address is available yet.
+    Network,
+    /// No response was received in time.
+    Timeout,
+    /// The server reported an error.
+    Server,
+    /// The hostname has no IPv4 address.
+    NotFound,
+}
+
+/// Check whether a hostname is valid.
+///
+/// # Args
+/// * `name` - The hostname, consisting of dot-separated labels of letters, digits and hyphens.
+pub fn is_valid_hostname(name: &str) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= MAX_LABEL_LENGTH
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|c| c.is_ascii_alphanumeric() || c == b'-')
+        })
+}
+
+/// Skip over an encoded name.
+///
+/// # Returns
+/// The position after the name or `None` if the name exceeds the message.
+fn skip_name(message: &[u8], mut position: usize) -> Option<usize> {
+    loop {
+        let length = *message.get(position)? as usize;
+        match length {
+            0 => return Some(position + 1),
+            // Compressed names end with a pointer.
+            length if length & 0xC0 == 0xC0 => return Some(position + 2),
+            length => position += 1 + length,
+        }
+    }
+}
+
+/// Read a big-endian 16-bit field.
+fn read_u16(message: &[u8], position: usize) -> Option<u16> {
+    message
+        .get(position..position + 2)
+        .map(|field| u16::from_be_bytes([field[0], field[1]]))
+}
+
+/// A DNS client resolving hostnames to IPv4 addresses.
+pub struct DnsClient {
+    stack: NetworkReference,
+    socket: Option<<NetworkReference as UdpClientStack>::UdpSocket>,
+    server: SocketAddr,
+    query: Option<u64>,
+    id: u16,
+}
+
+impl DnsClient {
+    /// Construct a new DNS client.
+    ///
+    /// # Args
+    /// * `stack` - A reference to the shared network stack.
+    /// * `server` - The IP address of the DNS server to query.
+    pub fn new(stack: NetworkReference, server: IpAddr) -> Self {
+        Self {
+            stack,
+            socket: None,
+            server: SocketAddr::new(server, DNS_PORT),
+            query: None,
+            id: 0,
+        }
+    }
+
+    /// Change the DNS server to query.
+    ///
+    /// # Note
+    /// An outstanding query is abandoned if the server changes.
+    ///
+    /// # Args
+    /// * `server` - The IP address of the DNS server to query.
+    pub fn set_server(&mut self, server: IpAddr) {
+        let server = SocketAddr::new(server, DNS_PORT);
+        if server != self.server {
+            self.server = server;
+            self.query.take();
+            self.close();
+        }
+    }
+
+    fn close(&mut self) {
+        if let Some(socket) = self.socket.take() {
+            // Note(unwrap): We guarantee that the socket is available above.
+            self.stack.close(socket).unwrap();
+        }
+    }
+
+    fn send_query(&mut self, hostname: &str) -> Result<(), Error> {
+        if !is_valid_hostname(hostname) {
+            return Err(Error::InvalidName);
+        }
+
+        if self.socket.is_none() {
+            let mut socket = self.stack.socket().or(Err(Error::Network))?;
+
+            // Connecting fails as long as no IP address is available.
+            if self.stack.connect(&mut socket, self.server).is_err() {
+                // Note(unwrap): The socket was just allocated and is not in use.
+                self.stack.close(socket).unwrap();
+                return Err(Error::Network);
+            }
+
+            self.socket.replace(socket);
+        }
+
+        self.id = self.id.wrapping_add(1);
+
+        // Standard query with recursion desired for a single question.
+        let mut query = [0u8; HEADER_SIZE + MAX_NAME_LENGTH + 2 + 4];
+        query[..2].copy_from_slice(&self.id.to_be_bytes());
+        query[2] = 0x01;
+        query[5] = 1;
+
+        let mut length = HEADER_SIZE;
+        for label in hostname.trim_end_matches('.').split('.') {
+            query[length] = label.len() as u8;
+            query[length + 1..length + 1 + label.len()]
+                .copy_from_slice(label.as_bytes());
+            length += 1 + label.len();
+        }
+
+        // The root label terminates the name.
+        query[length] = 0;
+        length += 1;
+        query[length..length + 2].copy_from_slice(&TYPE_A.to_be_bytes());
+        query[length + 2..length + 4].copy_from_slice(&CLASS_IN.to_be_bytes());
+        length += 4;
+
+        // Note(unwrap): The socket is guaranteed to be available above.
+        let socket = self.socket.as_mut().unwrap();
+        self.stack
+            .send(socket, &query[..length])
+            .map_err(|_| Error::Network)
+    }
+
+    /// Parse a response to the outstanding query.
+    ///
+    /// # Returns
+    /// `None` if the message is not a response to the outstanding query.
+    fn parse_response(&self, response: &[u8]) -> Option<Result<IpAddr, Error>> {
+        if response.len() < HEADER_SIZE
+            || read_u16(response, 0)? != self.id
+            || response[2] & 0x80 == 0
+        {
+            return None;
+        }
+
+        match response[3] & 0x0F {
+            0 => {}
+            3 => return Some(Err(Error::NotFound)),
+            _ => return Some(Err(Error::Server)),
+        }
+
+        let questions = read_u16(response, 4)?;
+        let answers = read_u16(response, 6)?;
+
+        let mut position = HEADER_SIZE;
+        for _ in 0..questions {
+            position = skip_name(response, position)? + 4;
+        }
+
+        for _ in 0..answers {
+            position = skip_name(response, position)?;
+            let kind = read_u16(response, position)?;
+            let class = read_u16(response, position + 2)?;
+            let length = read_u16(response, position + 8)? as usize;
+            position += 10;
+
+            let data = response.get(position..position + length)?;
+            if kind == TYPE_A && class == CLASS_IN && length == 4 {
+                return Some(Ok(IpAddr::V4(Ipv4Addr::new(
+                    data[0], data[1], data[2], data[3],
+                ))));
+            }
+
+            position += length;
+        }
+
+        Some(Err(Error::NotFound))
+    }
+
+    /// Resolve a hostname to an IPv4 address.
+    ///
+    /// # Note
+    /// This function must be called repeatedly with the same hostname until it completes.
+    ///
+    /// # Args
+    /// * `hostname` - The hostname to resolve.
+    /// * `now` - The current device time in milliseconds.
+    ///
+    /// # Returns
+    /// The address of the host or `WouldBlock` while the query is outstanding.
+    pub fn resolve(
+        &mut self,
+        hostname: &str,
+        now: u64,
+    ) -> nb::Result<IpAddr, Error> {
+        let sent = match self.query {
+            Some(sent) => sent,
+            None => {
+                self.send_query(hostname)?;
+                self.query.replace(now);
+                return Err(nb::Error::WouldBlock);
+            }
+        };
+
+        // Note(unwrap): A query is only outstanding with an open socket.
+        let socket = self.socket.as_mut().unwrap();
+        let mut response = [0u8; MESSAGE_SIZE];
+        match self.stack.receive(socket, &mut response) {
+            Ok((length, _)) => {
+                if let Some(result) = self.parse_response(&response[..length]) {
+                    self.query.take();
+                    return result.map_err(nb::Error::Other);
+                }
+            }
+            Err(nb::Error::WouldBlock) => {}
+            Err(_) => {
+                self.query.take();
+                self.close();
+                return Err(nb::Error::Other(Error::Network));
+            }
+        }
+
+        if now - sent >= QUERY_TIMEOUT_MS {
+            self.query.take();
+            return Err(nb::Error::Other(Error::Timeout));
+        }
+
+        Err(nb::Error::WouldBlock)
+    }
+}
@@ -0,0 +1,476 @@
+///! mDNS responder and DNS-SD service announcement
+///!
+///! # Design
+///! To allow discovering devices on the local link without knowledge of their address or MQTT
+///! prefix, Stabilizer answers multicast DNS queries (RFC 6762) for its hostname
+///! `stabilizer-<mac>.local` and advertises itself as an instance `<app>-<mac>` of the DNS-SD
+///! service (RFC 6763) `_stabilizer._udp.local`.
+///!
+///! The service instance carries the following TXT record keys:
+///! * `app`: The name of the application.
+///! * `prefix`: The MQTT prefix of the device.
+///! * `stream`: The stream format code, if the application streams data.
+///! * `batch`: The number of samples per batch of the stream.
+///! * `ticks`: The number of sampling timer ticks between samples of the stream.
+///! * `targets`: The maximum number of stream targets.
+///!
+///! Stabilizer does not accept connections itself but is reached through the MQTT broker. The
+///! service port is therefore zero.
+///!
+///! The records are announced twice upon boot and whenever the IP address changes. Afterwards,
+///! queries are answered by multicast responses. Legacy unicast queries (from ports other than
+///! 5353) are not answered.
+use core::fmt::Write;
+use heapless::{String, Vec};
+use smoltcp_nal::embedded_nal::{
+    nb, IpAddr, Ipv4Addr, SocketAddr, UdpClientStack, UdpFullStack,
+};
+use smoltcp_nal::smoltcp::wire::IpAddress;
+
+use super::{data_stream::STREAM_TARGETS, sntp::SystemTime, NetworkReference};
+
+// The UDP port of mDNS.
+const MDNS_PORT: u16 = 5353;
+
+/// The IPv4 multicast group of mDNS.
+pub const MDNS_GROUP: [u8; 4] = [224, 0, 0, 251];
+
+// The maximum size of an mDNS message handled.
+const MESSAGE_SIZE: usize = 512;
+
+// The size of the DNS message header.
+const HEADER_SIZE: usize = 12;
+
+// The time to live of all records in seconds.
+const TTL: u32 = 120;
+
+// The number of unsolicited announcements and the interval between them.
+const ANNOUNCEMENTS: u8 = 2;
+const ANNOUNCEMENT_INTERVAL_MS: u64 = 1_000;
+
+// The DNS-SD service type and the service type enumeration name.
+const SERVICE: &str = "_stabilizer._udp.local";
+const SERVICES: &str = "_services._dns-sd._udp.local";
+
+// Record types and classes.
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+// The cache-flush bit of the class of unique records.
+const CACHE_FLUSH: u16 = 0x8000;
+
+// The records of the device.
+const RECORD_A: u8 = 1 << 0;
+const RECORD_PTR: u8 = 1 << 1;
+const RECORD_SRV: u8 = 1 << 2;
+const RECORD_TXT: u8 = 1 << 3;
+const RECORD_SERVICES: u8 = 1 << 4;
+
+/// Decode a possibly compressed name into dotted form.
+///
+/// # Returns
+/// The position after the name in the message or `None` if the name is malformed.
+fn read_name(
+    message: &[u8],
+    mut position: usize,
+    name: &mut String<128>,
+) -> Option<usize> {
+    let mut end = None;
+
+    // Limit the number of labels and pointers to reject loops.
+    for _ in 0..128 {
+        let length = *message.get(position)? as usize;
+        if length == 0 {
+            return Some(end.unwrap_or(position + 1));
+        }
+
+        if length & 0xC0 == 0xC0 {
+            let low = *message.get(position + 1)? as usize;
+            end.get_or_insert(position + 2);
+            position = ((length & 0x3F) << 8) | low;
+            continue;
+        }
+
+        let label = message.get(position + 1..position + 1 + length)?;
+        if !name.is_empty() {
+            name.push('.').ok()?;
+        }
+        name.push_str(core::str::from_utf8(label).ok()?).ok()?;
+        position += 1 + length;
+    }
+
+    None
+}
+
+/// A DNS message under construction.
+struct Message {
+    buffer: [u8; MESSAGE_SIZE],
+    length: usize,
+    valid: bool,
+}
+
+impl Message {
+    fn new() -> Self {
+        let mut message = Self {
+            buffer: [0; MESSAGE_SIZE],
+            length: HEADER_SIZE,
+            valid: true,
+        };
+
+        // Authoritative response.
+        message.buffer[2] = 0x84;
+        message
+    }
+
+    fn put(&mut self, data: &[u8]) {
+        match self.buffer.get_mut(self.length..self.length + data.len()) {
+            Some(buffer) => {
+                buffer.copy_from_slice(data);
+                self.length += data.len();
+            }
+            None => self.valid = false,
+        }
+    }
+
+    fn put_name(&mut self, name: &str) {
+        for label in name.split('.') {
+            self.put(&[label.len() as u8]);
+            self.put(label.as_bytes());
+        }
+        self.put(&[0]);
+    }
+
+    // Add a record to the answer or additional section.
+    fn record(
+        &mut self,
+        answer: bool,
+        name: &str,
+        kind: u16,
+        unique: bool,
+        data: impl FnOnce(&mut Self),
+    ) {
+        self.put_name(name);
+        self.put(&kind.to_be_bytes());
+        let class = if unique {
+            CLASS_IN | CACHE_FLUSH
+        } else {
+            CLASS_IN
+        };
+        self.put(&class.to_be_bytes());
+        self.put(&TTL.to_be_bytes());
+
+        // The data length is filled in after the data.
+        let start = self.length;
+        self.put(&[0, 0]);
+        data(self);
+
+        if self.valid {
+            let length = (self.length - start - 2) as u16;
+            self.buffer[start..start + 2]
+                .copy_from_slice(&length.to_be_bytes());
+
+            let count = if answer { 6 } else { 10 };
+            let records = u16::from_be_bytes([
+                self.buffer[count],
+                self.buffer[count + 1],
+            ]) + 1;
+            self.buffer[count..count + 2]
+                .copy_from_slice(&records.to_be_bytes());
+        }
+    }
+
+    fn data(&self) -> Option<&[u8]> {
+        self.valid.then(|| &self.buffer[..self.length])
+    }
+}
+
+/// An mDNS responder advertising the device.
+pub struct MdnsResponder {
+    stack: NetworkReference,
+    socket: Option<<NetworkReference as UdpClientStack>::UdpSocket>,
+    time: &'static SystemTime,
+    hostname: String<64>,
+    instance: String<64>,
+    txt: Vec<String<64>, 6>,
+    address: Option<[u8; 4]>,
+    announcements: u8,
+    next_announcement: u64,
+}
+
+impl MdnsResponder {
+    /// Construct a new mDNS responder.
+    ///
+    /// # Args
+    /// * `stack` - A reference to the shared network stack.
+    /// * `time` - The device clock.
+    /// * `app` - The name of the application.
+    /// * `mac` - The MAC address of the device.
+    /// * `prefix` - The MQTT prefix of the device.
+    pub fn new(
+        stack: NetworkReference,
+        time: &'static SystemTime,
+        app: &str,
+        mac: smoltcp_nal::smoltcp::wire::EthernetAddress,
+        prefix: &str,
+    ) -> Self {
+        // Note(unwrap): The application name and MAC address are short enough to fit.
+        let mut hostname = String::new();
+        write!(&mut hostname, "stabilizer-{}.local", mac).unwrap();
+
+        let mut instance = String::new();
+        write!(&mut instance, "{}-{}.{}", app, mac, SERVICE).unwrap();
+
+        let mut responder = Self {
+            stack,
+            socket: None,
+            time,
+            hostname,
+            instance,
+            txt: Vec::new(),
+            address: None,
+            announcements: 0,
+            next_announcement: 0,
+        };
+
+        responder.add_txt("app", app);
+        responder.add_txt("prefix", prefix);
+        responder
+    }
+
+    // Entries not fitting into the TXT record are omitted.
+    fn add_txt(&mut self, key: &str, value: impl core::fmt::Display) {
+        let mut entry = String::new();
+        if write!(&mut entry, "{}={}", key, value).is_ok() {
+            self.txt.push(entry).ok();
+        }
+    }
+
+    /// Advertise the stream capabilities.
+    ///
+    /// # Args
+    /// * `format` - The stream format code.
+    /// * `batch_size` - The number of samples in each batch.
+    /// * `sample_ticks` - The number of sampling timer ticks between samples.
+    pub fn set_stream(
+        &mut self,
+        format: u8,
+        batch_size: u8,
+        sample_ticks: u32,
+    ) {
+        self.txt.truncate(2);
+        self.add_txt("stream", format);
+        self.add_txt("batch", batch_size);
+        self.add_txt("ticks", sample_ticks);
+        self.add_txt("targets", STREAM_TARGETS);
+
+        // Announce the updated record.
+        self.announcements = 0;
+    }
+
+    fn close(&mut self) {
+        if let Some(socket) = self.socket.take() {
+            // Note(unwrap): We guarantee that the socket is available above.
+            self.stack.close(socket).unwrap();
+        }
+    }
+
+    // Get the current IPv4 address of the interface.
+    fn ip_address(&mut self) -> Option<[u8; 4]> {
+        self.stack.lock(|stack| {
+            stack.interface().ip_addrs().iter().find_map(|cidr| {
+                match cidr.address() {
+                    IpAddress::Ipv4(address) if !address.is_unspecified() => {
+                        Some(address.0)
+                    }
+                    _ => None,
+                }
+            })
+        })
+    }
+
+    fn open(&mut self) -> Result<(), ()> {
+        if self.socket.is_none() {
+            let mut socket = self.stack.socket().or(Err(()))?;
+            if self.stack.bind(&mut socket, MDNS_PORT).is_err() {
+                // Note(unwrap): The socket was just allocated and is not in use.
+                self.stack.close(socket).unwrap();
+                return Err(());
+            }
+
+            self.socket.replace(socket);
+        }
+
+        Ok(())
+    }
+
+    // Build a response containing the requested records and the related records in the additional
+    // section.
+    fn respond(&mut self, records: u8, address: [u8; 4]) {
+        let mut additional = 0;
+        if records & RECORD_PTR != 0 {
+            additional |= RECORD_SRV | RECORD_TXT | RECORD_A;
+        }
+        if records & RECORD_SRV != 0 {
+            additional |= RECORD_A;
+        }
+        additional &= !records;
+
+        let mut message = Message::new();
+        for (section, answer) in [(records, true), (additional, false)] {
+            if section & RECORD_SERVICES != 0 {
+                message.record(answer, SERVICES, TYPE_PTR, false, |m| {
+                    m.put_name(SERVICE)
+                });
+            }
+            if section & RECORD_PTR != 0 {
+                message.record(answer, SERVICE, TYPE_PTR, false, |m| {
+                    m.put_name(&self.instance)
+                });
+            }
+            if section & RECORD_SRV != 0 {
+                message.record(answer, &self.instance, TYPE_SRV, true, |m| {
+                    // Priority, weight and port.
+                    m.put(&[0; 6]);
+                    m.put_name(&self.hostname);
+                });
+            }
+            if section & RECORD_TXT != 0 {
+                message.record(answer, &self.instance, TYPE_TXT, true, |m| {
+                    for entry in self.txt.iter() {
+                        m.put(&[entry.len() as u8]);
+                        m.put(entry.as_bytes());
+                    }
+                });
+            }
+            if section & RECORD_A != 0 {
+                message.record(answer, &self.hostname, TYPE_A, true, |m| {
+                    m.put(&address)
+                });
+            }
+        }
+
+        let [a, b, c, d] = MDNS_GROUP;
+        let group =
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(a, b, c, d)), MDNS_PORT);
+
+        match (message.data(), self.socket.as_mut()) {
+            (Some(data), Some(socket)) => {
+                self.stack.send_to(socket, group, data).ok();
+            }
+            (None, _) => log::warn!("mDNS response exceeds message size"),
+            _ => {}
+        }
+    }
+
+    // Determine the records answering the questions of a query.
+    fn handle_query(&self, query: &[u8]) -> u8 {
+        // Ignore responses and queries other than standard queries.
+        if query.len() < HEADER_SIZE || query[2] & 0xF8 != 0 {
+            return 0;
+        }
+
+        let questions = u16::from_be_bytes([query[4], query[5]]);
+        let mut position = HEADER_SIZE;
+        let mut records = 0;
+
+        for _ in 0..questions {
+            let mut name = String::new();
+            position = match read_name(query, position, &mut name) {
+                Some(position) => position,
+                None => break,
+            };
+
+            let kind = match query.get(position..position + 4) {
+                Some(question) => {
+                    u16::from_be_bytes([question[0], question[1]])
+                }
+                None => break,
+            };
+            position += 4;
+
+            let any = kind == TYPE_ANY;
+            if name.eq_ignore_ascii_case(&self.hostname)
+                && (any || kind == TYPE_A)
+            {
+                records |= RECORD_A;
+            } else if name.eq_ignore_ascii_case(SERVICE)
+                && (any || kind == TYPE_PTR)
+            {
+                records |= RECORD_PTR;
+            } else if name.eq_ignore_ascii_case(SERVICES)
+                && (any || kind == TYPE_PTR)
+            {
+                records |= RECORD_SERVICES;
+            } else if name.eq_ignore_ascii_case(&self.instance) {
+                if any || kind == TYPE_SRV {
+                    records |= RECORD_SRV;
+                }
+                if any || kind == TYPE_TXT {
+                    records |= RECORD_TXT;
+                }
+            }
+        }
+
+        records
+    }
+
+    /// Update the mDNS responder.
+    ///
+    /// # Note
+    /// This function must be called regularly to answer queries and announce the device.
+    pub fn update(&mut self) {
+        let address = match self.ip_address() {
+            Some(address) => address,
+            None => {
+                self.address.take();
+                self.close();
+                return;
+            }
+        };
+
+        if self.address.replace(address) != Some(address) {
+            self.announcements = 0;
+        }
+
+        if self.open().is_err() {
+            return;
+        }
+
+        let mut query = [0u8; MESSAGE_SIZE];
+        loop {
+            // Note(unwrap): The socket is guaranteed to be available above.
+            let socket = self.socket.as_mut().unwrap();
+            match self.stack.receive(socket, &mut query) {
+                Ok((length, remote)) => {
+                    if remote.port() != MDNS_PORT {
+                        continue;
+                    }
+
+                    let records = self.handle_query(&query[..length]);
+                    if records != 0 {
+                        self.respond(records, address);
+                    }
+                }
+                Err(nb::Error::WouldBlock) => break,
+                Err(_) => {
+                    self.close();
+                    return;
+                }
+            }
+        }
+
+        let now = self.time.now();
+        if self.announcements < ANNOUNCEMENTS && now >= self.next_announcement {
+            self.respond(
+                RECORD_PTR | RECORD_SRV | RECORD_TXT | RECORD_A,
+                address,
+            );
+            self.announcements += 1;
+            self.next_announcement = now + ANNOUNCEMENT_INTERVAL_MS;
+        }
+    }
+}
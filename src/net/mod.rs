@@ -9,21 +9,38 @@ pub use heapless;
 pub use miniconf;
 pub use serde;
 
+pub mod broker;
+pub mod capture;
+pub mod command;
 pub mod data_stream;
+pub mod dns;
+pub mod mdns;
+pub mod network_config;
 pub mod network_processor;
+pub mod sntp;
 pub mod telemetry;
 
-use crate::hardware::{EthernetPhy, NetworkManager, NetworkStack, SystemTimer};
-use data_stream::{DataStream, FrameGenerator};
+use crate::hardware::{
+    crash_log, design_parameters, reset::ResetReason, setup::NetworkDevices,
+    I2c2, NetworkManager, NetworkStack, SystemTimer,
+};
+use broker::{Broker, BrokerResolver, BrokerStack, BrokerStatus};
+use capture::CaptureClient;
+use command::Command;
+use data_stream::{
+    DataStream, FrameGenerator, StreamStatistics, StreamTarget, STREAM_TARGETS,
+};
+use mdns::MdnsResponder;
 use minimq::embedded_nal::IpAddr;
+use network_config::{Fallback, NetworkSettings};
 use network_processor::NetworkProcessor;
-use telemetry::TelemetryClient;
+use sntp::{SntpClient, TimeStatus};
+use telemetry::{Metadata, SystemStatus, TelemetryClient};
 
 use core::fmt::Write;
 use heapless::String;
 use miniconf::Miniconf;
 use serde::Serialize;
-use smoltcp_nal::embedded_nal::SocketAddr;
 
 pub type NetworkReference =
     smoltcp_nal::shared::NetworkStackProxy<'static, NetworkStack>;
@@ -39,17 +56,27 @@ pub enum UpdateState {
 
 pub enum NetworkState {
     SettingsChanged(String<64>),
+    Command(Command),
     Updated,
     NoChange,
 }
 
 /// A structure of Stabilizer's default network users.
 pub struct NetworkUsers<S: Default + Miniconf + Clone, T: Serialize> {
-    pub miniconf: miniconf::MqttClient<S, NetworkReference, SystemTimer, 512>,
+    pub miniconf: miniconf::MqttClient<S, BrokerStack, SystemTimer, 512>,
     pub processor: NetworkProcessor,
     stream: DataStream,
     generator: Option<FrameGenerator>,
     pub telemetry: TelemetryClient<T>,
+    pub capture: Option<CaptureClient>,
+    pub sntp: SntpClient,
+    pub broker: BrokerResolver,
+    pub mdns: MdnsResponder,
+    fallback: Fallback,
+    pub network:
+        miniconf::MqttClient<NetworkSettings, BrokerStack, SystemTimer, 512>,
+    eeprom: I2c2,
+    metadata: Metadata,
 }
 
 impl<S, T> NetworkUsers<S, T>
@@ -60,59 +87,149 @@ where
     /// Construct Stabilizer's default network users.
     ///
     /// # Args
-    /// * `stack` - The network stack that will be used to share with all network users.
-    /// * `phy` - The ethernet PHY connecting the network.
+    /// * `net` - The network devices including the stack, PHY, MAC address and the stored network
+    ///   configuration.
     /// * `clock` - A `SystemTimer` implementing `Clock`.
     /// * `app` - The name of the application.
-    /// * `mac` - The MAC address of the network.
-    /// * `broker` - The IP address of the MQTT broker to use.
+    /// * `ntp_server` - The IP address of the NTP server to synchronize the device clock to.
+    /// * `reset_reason` - The cause of the last reset, published in the device metadata.
+    /// * `pounder` - Specifies true if Pounder is present, published in the device metadata.
+    /// * `capture` - Specifies true to construct the capture client of applications supporting
+    ///   triggered capture.
     ///
     /// # Returns
     /// A new struct of network users.
     pub fn new(
-        stack: NetworkStack,
-        phy: EthernetPhy,
+        net: NetworkDevices,
         clock: SystemTimer,
         app: &str,
-        mac: smoltcp_nal::smoltcp::wire::EthernetAddress,
-        broker: IpAddr,
+        ntp_server: IpAddr,
+        reset_reason: ResetReason,
+        pounder: bool,
+        capture: bool,
     ) -> Self {
-        let stack_manager =
-            cortex_m::singleton!(: NetworkManager = NetworkManager::new(stack))
-                .unwrap();
+        let stack_manager = cortex_m::singleton!(: NetworkManager = NetworkManager::new(net.stack))
+            .unwrap();
 
-        let processor =
-            NetworkProcessor::new(stack_manager.acquire_stack(), phy);
+        let processor = NetworkProcessor::new(
+            stack_manager.acquire_stack(),
+            net.phy,
+            net.dhcp,
+        );
+
+        let mac = net.mac_address;
+        let time = sntp::setup_time(clock);
+
+        // All MQTT clients connect to the shared, resolved broker address.
+        let broker = Broker::new(time, net.config.port);
+        let broker_resolver = BrokerResolver::new(
+            stack_manager.acquire_stack(),
+            broker,
+            time,
+            net.config.broker(),
+            net.config.dns_server(),
+        );
+
+        let fallback = Fallback::new(time, broker, net.origin);
 
         let prefix = get_device_prefix(app, mac);
 
         let settings = miniconf::MqttClient::new(
-            stack_manager.acquire_stack(),
+            BrokerStack::new(stack_manager.acquire_stack(), broker),
             &get_client_id(app, "settings", mac),
             &prefix,
-            broker,
+            broker::unresolved(),
             clock,
             S::default(),
         )
         .unwrap();
 
-        let telemetry = TelemetryClient::new(
-            stack_manager.acquire_stack(),
+        let mut telemetry = TelemetryClient::new(
+            BrokerStack::new(stack_manager.acquire_stack(), broker),
             clock,
             &get_client_id(app, "tlm", mac),
             &prefix,
-            broker,
         );
 
+        // Note(unwrap): The application name and MAC address are short enough to fit.
+        let mut metadata = Metadata {
+            firmware_version: env!("CARGO_PKG_VERSION"),
+            git_revision: env!("GIT_REVISION"),
+            app: String::from(app),
+            mac: String::new(),
+            pounder,
+            pounder_version: if cfg!(feature = "pounder_v1_0") {
+                "v1.0"
+            } else {
+                "v1.1"
+            },
+            batch_size: None,
+            sample_rate: None,
+            reset_reason,
+        };
+        write!(&mut metadata.mac, "{}", mac).unwrap();
+        telemetry.set_metadata(&metadata);
+
+        // A retained report of an earlier crash remains on the broker until it is cleared.
+        if let Some(report) = crash_log::report() {
+            telemetry.set_crash_report(Some(&report));
+        }
+
+        // The capture client occupies a TCP socket and is only constructed if required.
+        let capture = capture.then(|| {
+            CaptureClient::new(
+                BrokerStack::new(stack_manager.acquire_stack(), broker),
+                clock,
+                &get_client_id(app, "capture", mac),
+                &prefix,
+            )
+        });
+
+        let sntp =
+            SntpClient::new(stack_manager.acquire_stack(), time, ntp_server);
+
         let (generator, stream) =
-            data_stream::setup_streaming(stack_manager.acquire_stack());
+            data_stream::setup_streaming(stack_manager.acquire_stack(), time);
+
+        let mdns = MdnsResponder::new(
+            stack_manager.acquire_stack(),
+            time,
+            app,
+            mac,
+            &prefix,
+        );
+
+        // Note(unwrap): The device prefix is short enough to append the suffix.
+        let mut network_prefix: String<128> = prefix.clone();
+        network_prefix.push_str("/net").unwrap();
+
+        let network = miniconf::MqttClient::new(
+            BrokerStack::new(stack_manager.acquire_stack(), broker),
+            &get_client_id(app, "net", mac),
+            &network_prefix,
+            broker::unresolved(),
+            clock,
+            NetworkSettings {
+                config: net.config,
+                ..Default::default()
+            },
+        )
+        .unwrap();
 
         NetworkUsers {
             miniconf: settings,
             processor,
             telemetry,
+            capture,
+            sntp,
+            broker: broker_resolver,
+            mdns,
+            fallback,
             stream,
             generator: Some(generator),
+            network,
+            eeprom: net.eeprom,
+            metadata,
         }
     }
 
@@ -120,23 +237,75 @@ where
     ///
     /// # Args
     /// * `format` - A unique u8 code indicating the format of the data.
+    /// * `batch_size` - The number of samples in each data batch.
+    /// * `sample_ticks` - The number of sampling timer ticks between samples.
     pub fn configure_streaming(
         &mut self,
         format: impl Into<u8>,
         batch_size: u8,
+        sample_ticks: u32,
     ) -> FrameGenerator {
+        let format = format.into();
+        self.mdns.set_stream(format, batch_size, sample_ticks);
+
+        self.metadata.batch_size.replace(batch_size);
+        self.metadata.sample_rate.replace(
+            1. / (sample_ticks as f32 * design_parameters::TIMER_PERIOD),
+        );
+        self.telemetry.set_metadata(&self.metadata);
+
         let mut generator = self.generator.take().unwrap();
         generator.configure(format, batch_size);
         generator
     }
 
-    /// Direct the stream to the provided remote target.
+    /// Direct the stream to the provided remote targets.
     ///
     /// # Args
-    /// * `remote` - The destination for the streamed data.
-    pub fn direct_stream(&mut self, remote: SocketAddr) {
+    /// * `targets` - The destinations for the streamed data.
+    pub fn direct_stream(&mut self, targets: &[StreamTarget; STREAM_TARGETS]) {
         if self.generator.is_none() {
-            self.stream.set_remote(remote);
+            self.stream.set_remotes(targets);
+        }
+    }
+
+    /// Get the device-side statistics of the data stream.
+    pub fn stream_statistics(&self) -> StreamStatistics {
+        self.stream.statistics()
+    }
+
+    /// Get the current time synchronization status of the device clock.
+    pub fn time_status(&self) -> TimeStatus {
+        self.sntp.status()
+    }
+
+    /// Indicate whether the application is actively controlling, e.g. whether a channel is locked.
+    ///
+    /// # Note
+    /// The device does not reset into or out of the fallback network configuration while the
+    /// application is actively controlling.
+    ///
+    /// # Args
+    /// * `controlling` - Specifies true if the application is actively controlling.
+    pub fn set_controlling(&mut self, controlling: bool) {
+        self.fallback.set_controlling(controlling);
+    }
+
+    /// Get the current address resolution status of the MQTT broker.
+    pub fn broker_status(&self) -> BrokerStatus {
+        self.broker.status()
+    }
+
+    /// Get the current status of the device-level services reported in telemetry.
+    pub fn system_status(&self) -> SystemStatus {
+        SystemStatus {
+            stream: self.stream_statistics(),
+            time: self.time_status(),
+            broker: self.broker_status(),
+            watchdog_reset: matches!(
+                self.metadata.reset_reason,
+                ResetReason::IndependentWatchdog | ResetReason::WindowWatchdog
+            ),
         }
     }
 
@@ -148,6 +317,36 @@ where
     pub fn update(&mut self) -> NetworkState {
         // Update the MQTT clients.
         self.telemetry.update();
+        if let Some(capture) = &mut self.capture {
+            capture.update();
+        }
+
+        // Synchronize the device clock.
+        self.sntp.update();
+
+        // Resolve the broker address.
+        self.broker.update();
+
+        // Fall back to the default network configuration if the broker is unreachable.
+        self.fallback.update();
+
+        // Answer mDNS queries.
+        self.mdns.update();
+
+        // Store network configuration updates.
+        let eeprom = &mut self.eeprom;
+        self.network
+            .handled_update(|path, old, new| {
+                if path == "config" {
+                    new.config.store(eeprom)?;
+                    old.config = new.config.clone();
+                    log::info!(
+                        "Network configuration stored, applied after reset"
+                    );
+                }
+                Result::<(), network_config::Error>::Ok(())
+            })
+            .ok();
 
         // Update the data stream.
         if self.generator.is_none() {
@@ -160,6 +359,10 @@ where
             UpdateState::Updated => NetworkState::Updated,
         };
 
+        // Resolve the broker using the DNS server offered by DHCP.
+        self.broker
+            .set_offered_dns_server(self.processor.dns_server());
+
         let mut settings_path = String::new();
         match self.miniconf.handled_update(|path, old, new| {
             settings_path.push_str(path).unwrap();
@@ -167,7 +370,35 @@ where
             Result::<(), &'static str>::Ok(())
         }) {
             Ok(true) => NetworkState::SettingsChanged(settings_path),
-            _ => poll_result,
+            _ => match self
+                .telemetry
+                .take_command()
+                .and_then(|command| self.handle_command(command))
+            {
+                Some(command) => NetworkState::Command(command),
+                None => poll_result,
+            },
+        }
+    }
+
+    // Handle a device command.
+    //
+    // # Returns
+    // The command if it is not a device command and has to be handled by the application.
+    fn handle_command(&mut self, command: Command) -> Option<Command> {
+        match command.name.as_str() {
+            "clear_crash_log" => {
+                crash_log::clear();
+                self.telemetry.set_crash_report(None);
+                log::info!("Crash log cleared");
+                None
+            }
+            "clear_stream_statistics" => {
+                self.stream.clear_statistics();
+                log::info!("Stream statistics cleared");
+                None
+            }
+            _ => Some(command),
         }
     }
 }
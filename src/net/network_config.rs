@@ -0,0 +1,492 @@
+///! Persistent network configuration
+///!
+///! # Design
+///! The network configuration (DHCP or static address, netmask and gateway, the DNS server and the
+///! MQTT broker and port) is stored in the user area of the EEPROM that also holds the MAC address. It is
+///! loaded upon boot and takes precedence over the compiled-in defaults (`STATIC_IP`, `DNS_SERVER`
+///! and `BROKER`).
+///!
+///! The configuration can be changed at runtime over MQTT as the `config` setting of a dedicated
+///! settings client with the prefix `<prefix>/net`. Every update is validated and stored. It takes
+///! effect after the next reset.
+///!
+///! If the stored configuration can not be read, fails its checksum or is invalid, the compiled-in
+///! defaults are used, i.e. DHCP (or `STATIC_IP` if specified), the compiled-in DNS server and the
+///! compiled-in broker.
+///!
+///! If no MQTT client connects to the broker within [FALLBACK_TIMEOUT_MS] after booting with the
+///! stored configuration, the device resets into a fallback configuration of DHCP and the
+///! compiled-in broker. This keeps a device with an unusable stored configuration reachable to
+///! correct it. The stored configuration is left untouched. The device reverts to it by another
+///! reset if the fallback configuration does not connect within [FALLBACK_TIMEOUT_MS] either, and
+///! in any case after [FALLBACK_DURATION_MS], e.g. to apply a corrected configuration. After
+///! reverting, the device does not fall back again until the next reset, so that an outage of the
+///! broker does not cause a sequence of resets.
+///!
+///! The device does not reset into or out of the fallback configuration while the application
+///! indicates that it is actively controlling, e.g. that a channel is locked. The requests are kept
+///! across the reset in RAM that is not initialized during startup.
+use core::{fmt, mem::MaybeUninit};
+
+use heapless::String;
+use miniconf::{Miniconf, MiniconfAtomic};
+use minimq::embedded_nal::{IpAddr, Ipv4Addr};
+use serde::{Deserialize, Serialize};
+
+use super::{broker::Broker, dns, sntp::SystemTime};
+use crate::hardware::{eeprom, I2c2};
+
+// The EEPROM address of the stored configuration.
+const STORAGE_ADDRESS: usize = 0;
+
+// The size of the serialized configuration including the header and checksum.
+const STORAGE_SIZE: usize = 20 + BROKER_SIZE + 2;
+
+// The maximum length of the broker address.
+const BROKER_SIZE: usize = 64;
+
+// The header identifying a stored configuration.
+const MAGIC: [u8; 2] = *b"NC";
+
+// The version of the storage layout.
+const VERSION: u8 = 3;
+
+// Identifies a pending request to use the fallback configuration.
+const FALLBACK_MAGIC: u32 = 0x4E46_4C42;
+
+// Identifies a pending request to revert to the stored configuration.
+const REVERT_MAGIC: u32 = 0x4E46_5256;
+
+/// The device resets into the fallback configuration if no MQTT client connected within this
+/// period after booting with the stored configuration. Likewise, it reverts to the stored
+/// configuration if no MQTT client connected within this period using the fallback configuration.
+pub const FALLBACK_TIMEOUT_MS: u64 = 60_000;
+
+/// The device reverts from the fallback configuration to the stored configuration after this
+/// period.
+pub const FALLBACK_DURATION_MS: u64 = 600_000;
+
+#[link_section = ".uninit.NETWORK_FALLBACK"]
+static mut FALLBACK_REQUEST: MaybeUninit<u32> = MaybeUninit::uninit();
+
+/// The compiled-in MQTT broker IP address or hostname.
+pub const DEFAULT_BROKER: &str = match option_env!("BROKER") {
+    Some(broker) => broker,
+    None => "10.34.16.10",
+};
+
+/// The default MQTT broker port.
+pub const DEFAULT_BROKER_PORT: u16 = 1883;
+
+/// The compiled-in DNS server.
+pub const DEFAULT_DNS_SERVER: &str = match option_env!("DNS_SERVER") {
+    Some(server) => server,
+    None => "10.34.16.1",
+};
+
+/// Get the compiled-in DNS server address.
+pub fn default_dns_server() -> IpAddr {
+    // Note(unwrap): The compiled-in DNS server is required to be valid.
+    DEFAULT_DNS_SERVER.parse().unwrap()
+}
+
+/// The origin of the network configuration in use.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Origin {
+    /// The compiled-in configuration, as no valid configuration is stored.
+    Default,
+    /// The stored configuration.
+    Stored,
+    /// The fallback configuration after the stored configuration failed to connect.
+    Fallback,
+    /// The stored configuration after reverting from the fallback configuration.
+    Reverted,
+}
+
+/// Represents errors of the network configuration.
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    /// The EEPROM could not be accessed.
+    Storage(eeprom::Error),
+    /// No configuration is stored.
+    Empty,
+    /// The stored configuration is corrupted.
+    Checksum,
+    /// The static IP address is unspecified, broadcast or multicast.
+    InvalidAddress,
+    /// The prefix length is out of range.
+    InvalidPrefix,
+    /// The gateway is outside of the subnet.
+    InvalidGateway,
+    /// The broker is neither a valid IP address nor a valid hostname.
+    InvalidBroker,
+    /// The broker port is zero.
+    InvalidPort,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Storage(_) => f.write_str("EEPROM access failed"),
+            Error::Empty => f.write_str("No configuration stored"),
+            Error::Checksum => f.write_str("Stored configuration corrupted"),
+            Error::InvalidAddress => f.write_str("Invalid static address"),
+            Error::InvalidPrefix => f.write_str("Invalid prefix length"),
+            Error::InvalidGateway => f.write_str("Gateway outside of subnet"),
+            Error::InvalidBroker => f.write_str("Invalid broker"),
+            Error::InvalidPort => f.write_str("Invalid broker port"),
+        }
+    }
+}
+
+/// The network configuration.
+///
+/// # Miniconf
+/// `{"dhcp": <dhcp>, "address": [<a>, <b>, <c>, <d>], "prefix": <prefix>, "gateway": [<a>, <b>,
+/// <c>, <d>], "dns": [<a>, <b>, <c>, <d>], "broker": "<broker>", "port": <port>}`
+///
+/// * `<dhcp>` specifies true to obtain the address, netmask and gateway by DHCP. The static
+///   configuration is ignored in this case.
+/// * `address` is the static IPv4 address.
+/// * `<prefix>` is the static network prefix length, e.g. 24 for a netmask of 255.255.255.0.
+/// * `gateway` is the static default gateway. `[0, 0, 0, 0]` specifies no gateway.
+/// * `dns` is the DNS server used to resolve the broker hostname. It takes precedence over the DNS
+///   servers offered by DHCP. `[0, 0, 0, 0]` selects the DNS server offered by DHCP, if any, or the
+///   compiled-in DNS server.
+/// * `<broker>` is the IPv4 address or the hostname of the MQTT broker. An empty string selects the
+///   compiled-in broker.
+/// * `<port>` is the TCP port of the MQTT broker, usually 1883.
+///
+/// ## Example
+/// `{"dhcp": false, "address": [192, 168, 1, 10], "prefix": 24, "gateway": [192, 168, 1, 1],
+/// "dns": [192, 168, 1, 1], "broker": "mqtt.example.com", "port": 1883}`
+#[derive(
+    Clone, Debug, PartialEq, Eq, MiniconfAtomic, Serialize, Deserialize,
+)]
+pub struct NetworkConfig {
+    pub dhcp: bool,
+    pub address: [u8; 4],
+    pub prefix: u8,
+    pub gateway: [u8; 4],
+    pub dns: [u8; 4],
+    pub broker: String<BROKER_SIZE>,
+    pub port: u16,
+}
+
+impl Default for NetworkConfig {
+    /// The compiled-in configuration.
+    fn default() -> Self {
+        // Note(unwrap): The compiled-in static address is required to be valid.
+        let address: IpAddr = option_env!("STATIC_IP")
+            .unwrap_or("0.0.0.0")
+            .parse()
+            .unwrap();
+        let address = match address {
+            IpAddr::V4(address) => address.octets(),
+            IpAddr::V6(_) => panic!("STATIC_IP must be an IPv4 address"),
+        };
+
+        Self {
+            dhcp: address == [0; 4],
+            address,
+            prefix: 24,
+            gateway: [0; 4],
+            dns: [0; 4],
+            broker: String::new(),
+            port: DEFAULT_BROKER_PORT,
+        }
+    }
+}
+
+/// The settings of the network configuration client.
+#[derive(Clone, Debug, Default, Miniconf)]
+pub struct NetworkSettings {
+    /// The persistent network configuration. Every update is validated and stored. It takes effect
+    /// after the next reset.
+    ///
+    /// # Path
+    /// `config`
+    ///
+    /// # Value
+    /// See [NetworkConfig#miniconf]
+    pub config: NetworkConfig,
+}
+
+impl NetworkConfig {
+    /// Load the stored configuration.
+    ///
+    /// # Note
+    /// If the stored configuration can not be used, the compiled-in defaults are returned. If the
+    /// fallback was requested before the last reset, the fallback configuration is returned once.
+    ///
+    /// # Args
+    /// * `i2c` - The I2C bus of the EEPROM.
+    ///
+    /// # Returns
+    /// (config, origin) where `origin` indicates which configuration is used.
+    pub fn load(i2c: &mut I2c2) -> (Self, Origin) {
+        // Note(unsafe): The request is only accessed during setup and immediately before a reset.
+        // Any content is valid.
+        let request = unsafe {
+            let request = FALLBACK_REQUEST.as_mut_ptr();
+            let value = request.read_volatile();
+            request.write_volatile(0);
+            value
+        };
+
+        if request == FALLBACK_MAGIC {
+            log::warn!("Using the fallback network configuration");
+            return (
+                Self {
+                    dhcp: true,
+                    ..Default::default()
+                },
+                Origin::Fallback,
+            );
+        }
+
+        let mut buffer = [0u8; STORAGE_SIZE];
+        let config = eeprom::read(i2c, STORAGE_ADDRESS, &mut buffer)
+            .map_err(Error::Storage)
+            .and_then(|_| Self::deserialize(&buffer))
+            .and_then(|config| config.validate().map(|_| config));
+
+        match config {
+            Ok(config) => {
+                log::info!("Loaded network configuration: {:?}", config);
+                if request == REVERT_MAGIC {
+                    (config, Origin::Reverted)
+                } else {
+                    (config, Origin::Stored)
+                }
+            }
+            Err(Error::Empty) => (Self::default(), Origin::Default),
+            Err(err) => {
+                log::warn!("Ignoring stored network configuration: {}", err);
+                (Self::default(), Origin::Default)
+            }
+        }
+    }
+
+    /// Validate and store the configuration.
+    ///
+    /// # Args
+    /// * `i2c` - The I2C bus of the EEPROM.
+    pub fn store(&self, i2c: &mut I2c2) -> Result<(), Error> {
+        self.validate()?;
+
+        let mut buffer = [0u8; STORAGE_SIZE];
+        self.serialize(&mut buffer);
+        eeprom::write(i2c, STORAGE_ADDRESS, &buffer).map_err(Error::Storage)
+    }
+
+    /// Check whether the configuration can be applied.
+    pub fn validate(&self) -> Result<(), Error> {
+        if !self.broker.is_empty()
+            && self.broker.parse::<IpAddr>().is_err()
+            && !dns::is_valid_hostname(&self.broker)
+        {
+            return Err(Error::InvalidBroker);
+        }
+
+        if self.port == 0 {
+            return Err(Error::InvalidPort);
+        }
+
+        if self.dhcp {
+            return Ok(());
+        }
+
+        let address = u32::from_be_bytes(self.address);
+        // Unspecified, broadcast and multicast (224.0.0.0/4) addresses are invalid.
+        if address == 0 || address == u32::MAX || address >> 28 == 0xE {
+            return Err(Error::InvalidAddress);
+        }
+
+        if !(1..=32).contains(&self.prefix) {
+            return Err(Error::InvalidPrefix);
+        }
+
+        let mask = u32::MAX << (32 - self.prefix as u32);
+        let gateway = u32::from_be_bytes(self.gateway);
+        if gateway != 0
+            && (gateway & mask != address & mask || gateway == address)
+        {
+            return Err(Error::InvalidGateway);
+        }
+
+        Ok(())
+    }
+
+    /// Get the MQTT broker IP address or hostname.
+    ///
+    /// # Note
+    /// The compiled-in broker is used if no broker is configured.
+    pub fn broker(&self) -> &str {
+        if self.broker.is_empty() {
+            DEFAULT_BROKER
+        } else {
+            &self.broker
+        }
+    }
+
+    /// Get the configured DNS server address, if any.
+    pub fn dns_server(&self) -> Option<IpAddr> {
+        if self.dns == [0; 4] {
+            None
+        } else {
+            let [a, b, c, d] = self.dns;
+            Some(IpAddr::V4(Ipv4Addr::new(a, b, c, d)))
+        }
+    }
+
+    fn serialize(&self, buffer: &mut [u8; STORAGE_SIZE]) {
+        buffer[..2].copy_from_slice(&MAGIC);
+        buffer[2] = VERSION;
+        buffer[3] = self.dhcp as u8;
+        buffer[4..8].copy_from_slice(&self.address);
+        buffer[8] = self.prefix;
+        buffer[9..13].copy_from_slice(&self.gateway);
+        buffer[13..17].copy_from_slice(&self.dns);
+        buffer[17..19].copy_from_slice(&self.port.to_be_bytes());
+        buffer[19] = self.broker.len() as u8;
+        buffer[20..20 + self.broker.len()]
+            .copy_from_slice(self.broker.as_bytes());
+
+        let checksum = fletcher16(&buffer[..STORAGE_SIZE - 2]);
+        buffer[STORAGE_SIZE - 2..].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    fn deserialize(buffer: &[u8; STORAGE_SIZE]) -> Result<Self, Error> {
+        if buffer[..2] != MAGIC || buffer[2] != VERSION {
+            return Err(Error::Empty);
+        }
+
+        let checksum = fletcher16(&buffer[..STORAGE_SIZE - 2]);
+        if buffer[STORAGE_SIZE - 2..] != checksum.to_be_bytes() {
+            return Err(Error::Checksum);
+        }
+
+        let len = (buffer[19] as usize).min(BROKER_SIZE);
+        let broker = core::str::from_utf8(&buffer[20..20 + len])
+            .map_err(|_| Error::Checksum)?;
+
+        let mut config = Self {
+            dhcp: buffer[3] != 0,
+            prefix: buffer[8],
+            port: u16::from_be_bytes([buffer[17], buffer[18]]),
+            ..Default::default()
+        };
+        config
+            .broker
+            .push_str(broker)
+            .map_err(|_| Error::Checksum)?;
+        config.address.copy_from_slice(&buffer[4..8]);
+        config.gateway.copy_from_slice(&buffer[9..13]);
+        config.dns.copy_from_slice(&buffer[13..17]);
+        Ok(config)
+    }
+}
+
+/// Resets into the fallback configuration if the stored configuration does not allow to connect
+/// to the broker, and back into the stored configuration.
+pub struct Fallback {
+    time: &'static SystemTime,
+    broker: &'static Broker,
+    origin: Origin,
+    armed: bool,
+    controlling: bool,
+}
+
+impl Fallback {
+    /// Construct the fallback monitor.
+    ///
+    /// # Args
+    /// * `time` - The device clock.
+    /// * `broker` - The shared broker address tracking the connections of the MQTT clients.
+    /// * `origin` - The origin of the network configuration in use.
+    pub fn new(
+        time: &'static SystemTime,
+        broker: &'static Broker,
+        origin: Origin,
+    ) -> Self {
+        Self {
+            time,
+            broker,
+            origin,
+            armed: matches!(origin, Origin::Stored | Origin::Fallback),
+            controlling: false,
+        }
+    }
+
+    /// Indicate whether the application is actively controlling.
+    ///
+    /// # Note
+    /// The device is not reset while the application is actively controlling.
+    ///
+    /// # Args
+    /// * `controlling` - Specifies true if the application is actively controlling.
+    pub fn set_controlling(&mut self, controlling: bool) {
+        self.controlling = controlling;
+    }
+
+    /// Update the fallback monitor.
+    ///
+    /// # Note
+    /// This function must be called regularly. It resets the device into the fallback
+    /// configuration if no MQTT client connected within [FALLBACK_TIMEOUT_MS] after booting with
+    /// the stored configuration. It reverts to the stored configuration if no MQTT client connected
+    /// within [FALLBACK_TIMEOUT_MS] using the fallback configuration, or after
+    /// [FALLBACK_DURATION_MS].
+    pub fn update(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        let now = self.time.now();
+        let connected = self.broker.has_connected();
+
+        let request = match self.origin {
+            Origin::Stored if connected => {
+                self.armed = false;
+                return;
+            }
+            Origin::Stored if now >= FALLBACK_TIMEOUT_MS => FALLBACK_MAGIC,
+            Origin::Fallback
+                if (!connected && now >= FALLBACK_TIMEOUT_MS)
+                    || now >= FALLBACK_DURATION_MS =>
+            {
+                REVERT_MAGIC
+            }
+            _ => return,
+        };
+
+        if self.controlling {
+            return;
+        }
+
+        if request == FALLBACK_MAGIC {
+            log::error!("Broker unreachable, resetting into the fallback network configuration");
+        } else {
+            log::warn!("Resetting into the stored network configuration");
+        }
+
+        // Note(unsafe): The request is written immediately before the reset.
+        unsafe {
+            FALLBACK_REQUEST.as_mut_ptr().write_volatile(request);
+        }
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+}
+
+/// Compute the Fletcher-16 checksum of the data.
+fn fletcher16(data: &[u8]) -> u16 {
+    let (a, b) = data.iter().fold((0u16, 0u16), |(a, b), &x| {
+        let a = (a + x as u16) % 255;
+        (a, (b + a) % 255)
+    });
+    (b << 8) | a
+}
+
+// Ensure the stored configuration fits into the user area of the EEPROM.
+const _: () = assert!(STORAGE_ADDRESS + STORAGE_SIZE <= eeprom::USER_SIZE);
@@ -3,6 +3,18 @@
 ///! # Design
 ///! The network processir is a small taks to regularly process incoming data over ethernet, handle
 ///! the ethernet PHY state, and reset the network as appropriate.
+///!
+///! The processor also runs the DHCP client, if any. In contrast to the network stack, it retains
+///! the DNS servers offered by DHCP.
+use smoltcp_nal::{
+    embedded_nal::Ipv4Addr,
+    smoltcp::{
+        iface::SocketHandle,
+        socket::{Dhcpv4Event, Dhcpv4Socket},
+        wire::{IpCidr, Ipv4Address, Ipv4Cidr},
+    },
+};
+
 use super::{NetworkReference, UpdateState};
 use crate::hardware::EthernetPhy;
 
@@ -10,6 +22,8 @@ use crate::hardware::EthernetPhy;
 pub struct NetworkProcessor {
     pub stack: NetworkReference,
     phy: EthernetPhy,
+    dhcp: Option<SocketHandle>,
+    dns_server: Option<Ipv4Addr>,
     network_was_reset: bool,
 }
 
@@ -19,17 +33,29 @@ impl NetworkProcessor {
     /// # Args
     /// * `stack` - A reference to the shared network stack
     /// * `phy` - The ethernet PHY used for the network.
+    /// * `dhcp` - The DHCP client socket, if the address is obtained by DHCP.
     ///
     /// # Returns
     /// The newly constructed processor.
-    pub fn new(stack: NetworkReference, phy: EthernetPhy) -> Self {
+    pub fn new(
+        stack: NetworkReference,
+        phy: EthernetPhy,
+        dhcp: Option<SocketHandle>,
+    ) -> Self {
         Self {
             stack,
             phy,
+            dhcp,
+            dns_server: None,
             network_was_reset: false,
         }
     }
 
+    /// Get the first DNS server offered by DHCP, if any.
+    pub fn dns_server(&self) -> Option<Ipv4Addr> {
+        self.dns_server
+    }
+
     /// Handle ethernet link connection status.
     ///
     /// # Note
@@ -49,7 +75,16 @@ impl NetworkProcessor {
             (false, false) => {
                 log::warn!("Network link DOWN");
                 self.network_was_reset = true;
-                self.stack.lock(|stack| stack.handle_link_reset());
+                let dhcp = self.dhcp;
+                self.stack.lock(|stack| {
+                    stack.handle_link_reset();
+                    if let Some(handle) = dhcp {
+                        stack
+                            .interface_mut()
+                            .get_socket::<Dhcpv4Socket>(handle)
+                            .reset();
+                    }
+                });
             }
             _ => {}
         };
@@ -64,10 +99,80 @@ impl NetworkProcessor {
     /// # Returns
     /// An update state corresponding with any changes in the underlying network.
     pub fn update(&mut self) -> UpdateState {
-        match self.stack.lock(|stack| stack.poll()) {
+        let state = match self.stack.lock(|stack| stack.poll()) {
             Ok(true) => UpdateState::Updated,
             Ok(false) => UpdateState::NoChange,
             Err(_) => UpdateState::Updated,
+        };
+
+        if self.handle_dhcp() {
+            UpdateState::Updated
+        } else {
+            state
         }
     }
+
+    /// Apply the address, default route and DNS server offered by DHCP.
+    ///
+    /// # Returns
+    /// True if the DHCP configuration changed.
+    fn handle_dhcp(&mut self) -> bool {
+        let handle = match self.dhcp {
+            Some(handle) => handle,
+            None => return false,
+        };
+
+        let dns_server = &mut self.dns_server;
+        self.stack.lock(|stack| {
+            let interface = stack.interface_mut();
+            match interface.get_socket::<Dhcpv4Socket>(handle).poll() {
+                Some(Dhcpv4Event::Configured(config)) => {
+                    interface.update_ip_addrs(|addrs| {
+                        addrs[0] = IpCidr::Ipv4(config.address)
+                    });
+
+                    // Note(unwrap): There is storage for the default route.
+                    match config.router {
+                        Some(router) => {
+                            interface
+                                .routes_mut()
+                                .add_default_ipv4_route(router)
+                                .unwrap();
+                        }
+                        None => {
+                            interface.routes_mut().remove_default_ipv4_route();
+                        }
+                    }
+
+                    *dns_server =
+                        config.dns_servers.iter().flatten().next().map(
+                            |&Ipv4Address([a, b, c, d])| {
+                                Ipv4Addr::new(a, b, c, d)
+                            },
+                        );
+
+                    log::info!(
+                        "DHCP address {}, DNS server {:?}",
+                        config.address,
+                        dns_server
+                    );
+                }
+                Some(Dhcpv4Event::Deconfigured) => {
+                    interface.update_ip_addrs(|addrs| {
+                        addrs[0] = IpCidr::Ipv4(Ipv4Cidr::new(
+                            Ipv4Address::UNSPECIFIED,
+                            0,
+                        ))
+                    });
+                    interface.routes_mut().remove_default_ipv4_route();
+                    *dns_server = None;
+
+                    log::warn!("DHCP address lost");
+                }
+                None => return false,
+            }
+
+            true
+        })
+    }
 }
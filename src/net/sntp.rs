@@ -0,0 +1,344 @@
+///! Stabilizer network time synchronization
+///!
+///! # Design
+///! The device clock (`SystemTimer`) is a free-running millisecond counter without any relation to
+///! wall time. To allow aligning telemetry and stream data with other instruments, a simple SNTP
+///! client (RFC 4330) regularly queries an NTP server over UDP and disciplines an offset of the
+///! device clock to UTC.
+///!
+///! Each query yields an offset measurement compensated for the symmetric network delay. The first
+///! measurement, as well as any measurement deviating by more than [STEP_THRESHOLD_MS] from the
+///! current offset, steps the offset. Smaller deviations are slewed into the offset to reject
+///! jitter.
+///!
+///! The device is reported as synchronized as long as valid responses are received. If the server
+///! becomes unavailable, the last known offset is retained and UTC time continues to be provided,
+///! but the device is reported as unsynchronized.
+use core::cell::Cell;
+use cortex_m::interrupt::Mutex;
+use minimq::embedded_time::Clock;
+use serde::Serialize;
+use smoltcp_nal::embedded_nal::{nb, IpAddr, SocketAddr, UdpClientStack};
+
+use super::NetworkReference;
+use crate::hardware::SystemTimer;
+
+// The UDP port of the NTP server.
+const NTP_PORT: u16 = 123;
+
+// The size of an SNTP message without any extension fields or authenticator.
+const NTP_PACKET_SIZE: usize = 48;
+
+// The number of seconds between the NTP epoch (1900-01-01) and the Unix epoch (1970-01-01).
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+// The interval between successive queries of the server.
+const POLL_INTERVAL_MS: u64 = 16_000;
+
+// The interval after which an unanswered query is considered lost and retried.
+const RESPONSE_TIMEOUT_MS: u64 = 2_000;
+
+// The device is considered unsynchronized if no valid response was received within this period.
+const SYNC_TIMEOUT_MS: u64 = 4 * POLL_INTERVAL_MS;
+
+/// Offset deviations larger than this are stepped instead of slewed.
+pub const STEP_THRESHOLD_MS: i64 = 128;
+
+// The slew of small offset deviations is performed with a time constant of 2^SLEW_SHIFT polls.
+const SLEW_SHIFT: u32 = 2;
+
+/// The time synchronization status reported in telemetry.
+#[derive(Copy, Clone, Debug, Default, Serialize)]
+pub struct TimeStatus {
+    /// Indicates that valid responses from the NTP server are being received.
+    pub synchronized: bool,
+
+    /// The current UTC time in milliseconds since the Unix epoch, if the offset is known.
+    pub utc: Option<u64>,
+
+    /// The offset of the device clock to UTC in milliseconds, if known.
+    pub offset: Option<i64>,
+
+    /// The round-trip delay to the NTP server of the last valid response in milliseconds.
+    pub delay: Option<u32>,
+
+    /// The time since the last valid response in milliseconds.
+    pub age: Option<u64>,
+}
+
+#[derive(Copy, Clone, Default)]
+struct ClockState {
+    last: u32,
+    epochs: u32,
+    offset: Option<i64>,
+}
+
+/// The device clock extended to 64 bits, with an optional offset to UTC.
+///
+/// # Note
+/// The state is shared between the SNTP client and time consumers executing at any priority, e.g.
+/// the data stream generator. All accesses are therefore performed in short critical sections.
+pub struct SystemTime {
+    clock: SystemTimer,
+    state: Mutex<Cell<ClockState>>,
+}
+
+impl SystemTime {
+    fn new(clock: SystemTimer) -> Self {
+        Self {
+            clock,
+            state: Mutex::new(Cell::new(ClockState::default())),
+        }
+    }
+
+    /// Get the device time.
+    ///
+    /// # Note
+    /// The 32-bit `SystemTimer` wraps after approximately 49 days. Wraps are detected on access,
+    /// which happens at least once every poll interval of the SNTP client.
+    ///
+    /// # Returns
+    /// The number of milliseconds since boot.
+    pub fn now(&self) -> u64 {
+        cortex_m::interrupt::free(|cs| {
+            let cell = self.state.borrow(cs);
+            let mut state = cell.get();
+
+            // Note(unwrap): The monotonic clock cannot fail to provide the current time.
+            let now = self
+                .clock
+                .try_now()
+                .unwrap()
+                .duration_since_epoch()
+                .integer();
+
+            if now < state.last {
+                state.epochs = state.epochs.wrapping_add(1);
+            }
+            state.last = now;
+            cell.set(state);
+
+            ((state.epochs as u64) << 32) | now as u64
+        })
+    }
+
+    /// Get the offset of the device time to UTC in milliseconds, if known.
+    pub fn offset(&self) -> Option<i64> {
+        cortex_m::interrupt::free(|cs| self.state.borrow(cs).get().offset)
+    }
+
+    fn set_offset(&self, offset: i64) {
+        cortex_m::interrupt::free(|cs| {
+            let cell = self.state.borrow(cs);
+            let mut state = cell.get();
+            state.offset.replace(offset);
+            cell.set(state);
+        })
+    }
+
+    /// Convert a device time to UTC.
+    ///
+    /// # Args
+    /// * `time` - The device time in milliseconds since boot.
+    ///
+    /// # Returns
+    /// The UTC time in milliseconds since the Unix epoch, if the offset is known.
+    pub fn to_utc(&self, time: u64) -> Option<u64> {
+        self.offset().map(|offset| (time as i64 + offset) as u64)
+    }
+
+    /// Get the current UTC time in milliseconds since the Unix epoch, if the offset is known.
+    pub fn utc(&self) -> Option<u64> {
+        self.to_utc(self.now())
+    }
+}
+
+/// Construct the device clock shared by all time consumers.
+///
+/// # Note
+/// This may only be called once.
+///
+/// # Args
+/// * `clock` - The `SystemTimer` providing the device time.
+pub fn setup_time(clock: SystemTimer) -> &'static SystemTime {
+    cortex_m::singleton!(: SystemTime = SystemTime::new(clock)).unwrap()
+}
+
+/// Convert an NTP timestamp to milliseconds since the Unix epoch.
+fn from_ntp(timestamp: &[u8]) -> u64 {
+    // Note(unwrap): The timestamp is always 8 bytes long.
+    let seconds = u32::from_be_bytes(timestamp[..4].try_into().unwrap());
+    let fraction = u32::from_be_bytes(timestamp[4..].try_into().unwrap());
+
+    ((seconds as u64).wrapping_sub(NTP_UNIX_OFFSET)) * 1000
+        + ((fraction as u64 * 1000) >> 32)
+}
+
+/// An SNTP client disciplining the device clock offset to UTC.
+pub struct SntpClient {
+    stack: NetworkReference,
+    socket: Option<<NetworkReference as UdpClientStack>::UdpSocket>,
+    server: SocketAddr,
+    time: &'static SystemTime,
+    request: Option<u64>,
+    next_request: u64,
+    last_sync: Option<u64>,
+    delay: Option<u32>,
+}
+
+impl SntpClient {
+    /// Construct a new SNTP client.
+    ///
+    /// # Args
+    /// * `stack` - A reference to the shared network stack.
+    /// * `time` - The device clock to discipline.
+    /// * `server` - The IP address of the NTP server to query.
+    pub fn new(
+        stack: NetworkReference,
+        time: &'static SystemTime,
+        server: IpAddr,
+    ) -> Self {
+        Self {
+            stack,
+            socket: None,
+            server: SocketAddr::new(server, NTP_PORT),
+            time,
+            request: None,
+            next_request: 0,
+            last_sync: None,
+            delay: None,
+        }
+    }
+
+    /// Get the current time synchronization status.
+    pub fn status(&self) -> TimeStatus {
+        let now = self.time.now();
+        let age = self.last_sync.map(|sync| now - sync);
+
+        TimeStatus {
+            synchronized: matches!(age, Some(age) if age < SYNC_TIMEOUT_MS),
+            utc: self.time.to_utc(now),
+            offset: self.time.offset(),
+            delay: self.delay,
+            age,
+        }
+    }
+
+    fn close(&mut self) {
+        if let Some(socket) = self.socket.take() {
+            // Note(unwrap): We guarantee that the socket is available above.
+            self.stack.close(socket).unwrap();
+        }
+    }
+
+    fn send_request(&mut self, now: u64) -> Result<(), ()> {
+        if self.socket.is_none() {
+            let mut socket = self.stack.socket().or(Err(()))?;
+
+            // Connecting fails as long as no IP address is available.
+            if self.stack.connect(&mut socket, self.server).is_err() {
+                // Note(unwrap): The socket was just allocated and is not in use.
+                self.stack.close(socket).unwrap();
+                return Err(());
+            }
+
+            self.socket.replace(socket);
+        }
+
+        // Leap indicator 0, version 4, mode 3 (client). The device time is used as transmit
+        // timestamp. It is echoed by the server as the originate timestamp to match the response
+        // to this request.
+        let mut request = [0u8; NTP_PACKET_SIZE];
+        request[0] = 0x23;
+        request[40..].copy_from_slice(&now.to_be_bytes());
+
+        // Note(unwrap): The socket is guaranteed to be available above.
+        let socket = self.socket.as_mut().unwrap();
+        self.stack.send(socket, &request).map_err(|_| ())?;
+
+        self.request.replace(now);
+        Ok(())
+    }
+
+    fn handle_response(&mut self, response: &[u8], now: u64) {
+        // Note(unwrap): A request is guaranteed to be pending by the caller.
+        let request = self.request.unwrap();
+
+        if response.len() < NTP_PACKET_SIZE {
+            return;
+        }
+
+        let leap = response[0] >> 6;
+        let mode = response[0] & 0b111;
+        let stratum = response[1];
+
+        // Ignore responses to other requests as well as unsynchronized servers and kiss-of-death
+        // messages.
+        if response[24..32] != request.to_be_bytes()
+            || mode != 4
+            || leap == 3
+            || stratum == 0
+        {
+            return;
+        }
+
+        self.request.take();
+
+        let receive = from_ntp(&response[32..40]) as i64;
+        let transmit = from_ntp(&response[40..48]) as i64;
+        let (request, now) = (request as i64, now as i64);
+
+        let measured = ((receive - request) + (transmit - now)) / 2;
+        let delay = (now - request) - (transmit - receive);
+
+        let offset = match self.time.offset() {
+            Some(offset) if (measured - offset).abs() <= STEP_THRESHOLD_MS => {
+                offset + ((measured - offset) >> SLEW_SHIFT)
+            }
+            _ => {
+                log::info!("Stepping UTC offset to {} ms", measured);
+                measured
+            }
+        };
+
+        self.time.set_offset(offset);
+        self.delay.replace(delay.max(0) as u32);
+        self.last_sync.replace(now as u64);
+    }
+
+    /// Update the SNTP client.
+    ///
+    /// # Note
+    /// This function must be called regularly to query the server and process its responses.
+    pub fn update(&mut self) {
+        let now = self.time.now();
+
+        if let (Some(socket), Some(_)) = (self.socket.as_mut(), self.request) {
+            let mut response = [0u8; NTP_PACKET_SIZE];
+            match self.stack.receive(socket, &mut response) {
+                Ok((length, _)) => {
+                    self.handle_response(&response[..length], now)
+                }
+                Err(nb::Error::WouldBlock) => {}
+                Err(_) => self.close(),
+            }
+        }
+
+        // Consider unanswered requests as lost after the response timeout and retry.
+        if let Some(request) = self.request {
+            if now - request < RESPONSE_TIMEOUT_MS {
+                return;
+            }
+
+            self.request.take();
+            self.next_request = now;
+        }
+
+        if now >= self.next_request {
+            self.next_request = match self.send_request(now) {
+                Ok(()) => now + POLL_INTERVAL_MS,
+                Err(()) => now + RESPONSE_TIMEOUT_MS,
+            };
+        }
+    }
+}
@@ -10,21 +10,98 @@
 ///! sampling frequency. Instead, the raw codes are stored and the telemetry is generated as
 ///! required immediately before transmission. This ensures that any slower computation required
 ///! for unit conversion can be off-loaded to lower priority tasks.
-use heapless::{String, Vec};
+///!
+///! In addition to the latest codes, the buffer accumulates the minimum, maximum, sum and sum of
+///! squares of all ADC and DAC codes over each telemetry period using integer arithmetic. These are
+///! converted to minimum, maximum, mean and RMS voltages upon reporting and reset afterwards.
+///! Likewise, the execution time of the DSP routine is accumulated to report the CPU load.
+///!
+///! Upon every connection to the broker, the telemetry client publishes the retained birth message
+///! `online` to `<prefix>/status` and the retained device [Metadata] to `<prefix>/meta`. The broker
+///! publishes the retained last-will message `offline` to `<prefix>/status` once the connection is
+///! lost.
+///!
+///! A [CrashReport] of a crash before the last reset is published retained to `<prefix>/crash` upon
+///! every connection. Clearing the crash log removes the retained report from the broker.
+///!
+///! The telemetry client also receives the [Command]s published to `<prefix>/command/<name>`.
+use heapless::{Deque, String, Vec};
 use minimq::{QoS, Retain};
-use serde::Serialize;
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+
+use super::{
+    broker::{self, BrokerStack, BrokerStatus},
+    command::{self, Command},
+    data_stream::StreamStatistics,
+    sntp::TimeStatus,
+};
+use crate::hardware::{
+    adc::AdcCode,
+    afe::Gain,
+    cpu_load::{CpuLoad, CycleAccumulator},
+    crash_log::{self, CrashReport},
+    dac::DacCode,
+    reset::ResetReason,
+    SystemTimer,
+};
 
-use super::NetworkReference;
-use crate::hardware::{adc::AdcCode, afe::Gain, dac::DacCode, SystemTimer};
-use minimq::embedded_nal::IpAddr;
+// The maximum size of a serialized telemetry message.
+const TELEMETRY_SIZE: usize = 2048;
+
+// The maximum size of the serialized device metadata.
+const METADATA_SIZE: usize = 512;
+
+// The maximum size of a serialized crash report, allowing for escaped characters in the message.
+const CRASH_REPORT_SIZE: usize = 2 * crash_log::MESSAGE_SIZE + 128;
 
 /// The telemetry client for reporting telemetry data over MQTT.
 pub struct TelemetryClient<T: Serialize> {
-    mqtt: minimq::Minimq<NetworkReference, SystemTimer, 512, 1>,
+    mqtt: minimq::Minimq<BrokerStack, SystemTimer, TELEMETRY_SIZE, 1>,
     telemetry_topic: String<128>,
+    event_topic: String<128>,
+    status_topic: String<128>,
+    metadata_topic: String<128>,
+    metadata: Vec<u8, METADATA_SIZE>,
+    crash_topic: String<128>,
+    crash_report: Option<Vec<u8, CRASH_REPORT_SIZE>>,
+    announced: bool,
+    command_topic: String<128>,
+    commands: Deque<Command, { command::QUEUE_SIZE }>,
+    subscribed: bool,
     _telemetry: core::marker::PhantomData<T>,
 }
 
+/// Device metadata published retained upon connection to the broker.
+#[derive(Clone, Debug, Serialize)]
+pub struct Metadata {
+    /// The firmware version.
+    pub firmware_version: &'static str,
+
+    /// The git revision the firmware was built from.
+    pub git_revision: &'static str,
+
+    /// The name of the application.
+    pub app: String<32>,
+
+    /// The MAC address of the device.
+    pub mac: String<32>,
+
+    /// Indicates that Pounder is present.
+    pub pounder: bool,
+
+    /// The Pounder hardware version the firmware was built for.
+    pub pounder_version: &'static str,
+
+    /// The number of samples per batch, if the application streams data.
+    pub batch_size: Option<u8>,
+
+    /// The sample rate in Hz, if the application streams data.
+    pub sample_rate: Option<f32>,
+
+    /// The cause of the last reset.
+    pub reset_reason: ResetReason,
+}
+
 /// The telemetry buffer is used for storing sample values during execution.
 ///
 /// # Note
@@ -39,6 +116,55 @@ pub struct TelemetryBuffer {
     pub dacs: [DacCode; 2],
     /// The latest digital input states during processing.
     pub digital_inputs: [bool; 2],
+    /// The accumulated input sample statistics of ADC0/ADC1.
+    pub adc_statistics: [SampleAccumulator; 2],
+    /// The accumulated output code statistics of DAC0/DAC1.
+    pub dac_statistics: [SampleAccumulator; 2],
+    /// The accumulated execution time of the DSP routine.
+    pub cpu_load: CycleAccumulator,
+}
+
+/// An accumulator of sample statistics over the telemetry period.
+///
+/// # Note
+/// The sums are accumulated in 64 bits and do not overflow for telemetry periods of up to several
+/// hours at the maximum sample rate.
+#[derive(Copy, Clone, Debug)]
+pub struct SampleAccumulator {
+    min: i16,
+    max: i16,
+    sum: i64,
+    sum_squares: u64,
+    count: u32,
+}
+
+/// Sample statistics over the telemetry period in SI units.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct SampleStatistics {
+    /// The minimum value.
+    pub min: f32,
+    /// The maximum value.
+    pub max: f32,
+    /// The mean value.
+    pub mean: f32,
+    /// The root-mean-square value.
+    pub rms: f32,
+}
+
+/// The status of device-level services reported in telemetry.
+#[derive(Copy, Clone, Debug)]
+pub struct SystemStatus {
+    /// Device-side statistics of the data stream.
+    pub stream: StreamStatistics,
+
+    /// The synchronization status of the device clock to UTC.
+    pub time: TimeStatus,
+
+    /// The address resolution status of the MQTT broker.
+    pub broker: BrokerStatus,
+
+    /// Indicates that the last reset was triggered by a watchdog.
+    pub watchdog_reset: bool,
 }
 
 /// The telemetry structure is data that is ultimately reported as telemetry over MQTT.
@@ -46,7 +172,9 @@ pub struct TelemetryBuffer {
 /// # Note
 /// This structure should be generated on-demand by the buffer when required to minimize conversion
 /// overhead.
-#[derive(Serialize)]
+///
+/// Applications reporting additional telemetry embed this structure in their own telemetry
+/// structure and serialize its fields alongside their own using [Telemetry::serialize_fields].
 pub struct Telemetry {
     /// Most recent input voltage measurement.
     pub adcs: [f32; 2],
@@ -59,6 +187,27 @@ pub struct Telemetry {
 
     /// The CPU temperature in degrees Celsius.
     pub cpu_temp: f32,
+
+    /// Input voltage statistics over the telemetry period, or `None` if no samples were processed.
+    pub adc_statistics: [Option<SampleStatistics>; 2],
+
+    /// Output voltage statistics over the telemetry period, or `None` if no samples were processed.
+    pub dac_statistics: [Option<SampleStatistics>; 2],
+
+    /// The CPU load of the DSP routine over the telemetry period, or `None` if it was not executed.
+    pub cpu_load: Option<CpuLoad>,
+
+    /// Device-side statistics of the data stream.
+    pub stream: StreamStatistics,
+
+    /// The synchronization status of the device clock to UTC.
+    pub time: TimeStatus,
+
+    /// The address resolution status of the MQTT broker.
+    pub broker: BrokerStatus,
+
+    /// Indicates that the last reset was triggered by a watchdog.
+    pub watchdog_reset: bool,
 }
 
 impl Default for TelemetryBuffer {
@@ -67,61 +216,248 @@ impl Default for TelemetryBuffer {
             adcs: [AdcCode(0), AdcCode(0)],
             dacs: [DacCode(0), DacCode(0)],
             digital_inputs: [false, false],
+            adc_statistics: [SampleAccumulator::default(); 2],
+            dac_statistics: [SampleAccumulator::default(); 2],
+            cpu_load: CycleAccumulator::default(),
+        }
+    }
+}
+
+impl Default for SampleAccumulator {
+    fn default() -> Self {
+        Self {
+            min: i16::MAX,
+            max: i16::MIN,
+            sum: 0,
+            sum_squares: 0,
+            count: 0,
+        }
+    }
+}
+
+impl SampleAccumulator {
+    /// Accumulate samples.
+    ///
+    /// # Args
+    /// * `samples` - The samples to accumulate as stabilizer-defined codes (i16 full range).
+    pub fn update(&mut self, samples: impl Iterator<Item = i16>) {
+        for sample in samples {
+            self.min = self.min.min(sample);
+            self.max = self.max.max(sample);
+            self.sum += sample as i64;
+            self.sum_squares += (sample as i32 * sample as i32) as u64;
+            self.count += 1;
+        }
+    }
+
+    /// Convert the accumulated statistics to SI units.
+    ///
+    /// # Args
+    /// * `scale` - The value of one LSB in SI units.
+    ///
+    /// # Returns
+    /// The sample statistics or `None` if no samples were accumulated.
+    pub fn finalize(&self, scale: f32) -> Option<SampleStatistics> {
+        if self.count == 0 {
+            return None;
         }
+
+        let count = self.count as u64;
+
+        // The mean square is scaled by 2^16 before the integer square root to retain 8 fractional
+        // bits of the RMS value.
+        let rms = isqrt((self.sum_squares / count) << 16) as f32 / 256.;
+
+        Some(SampleStatistics {
+            min: self.min as f32 * scale,
+            max: self.max as f32 * scale,
+            mean: (self.sum as f32 / self.count as f32) * scale,
+            rms: rms * scale,
+        })
+    }
+}
+
+/// Compute the integer square root, rounded down.
+fn isqrt(value: u64) -> u64 {
+    if value < 2 {
+        return value;
+    }
+
+    // Newton iteration starting from a power of two at or above the root.
+    let mut x = 1u64 << ((64 - value.leading_zeros() + 1) / 2);
+    loop {
+        let y = (x + value / x) / 2;
+        if y >= x {
+            return x;
+        }
+        x = y;
     }
 }
 
 impl TelemetryBuffer {
+    /// Reset the accumulated sample statistics and execution time to start a new telemetry period.
+    pub fn reset_statistics(&mut self) {
+        self.adc_statistics = [SampleAccumulator::default(); 2];
+        self.dac_statistics = [SampleAccumulator::default(); 2];
+        self.cpu_load = CycleAccumulator::default();
+    }
+
     /// Convert the telemetry buffer to finalized, SI-unit telemetry for reporting.
     ///
     /// # Args
     /// * `afe0` - The current AFE configuration for channel 0.
     /// * `afe1` - The current AFE configuration for channel 1.
     /// * `cpu_temp` - The current CPU temperature.
+    /// * `batch_period` - The period of the DSP routine in seconds.
+    /// * `status` - The current status of the device-level services.
     ///
     /// # Returns
     /// The finalized telemetry structure that can be serialized and reported.
-    pub fn finalize(self, afe0: Gain, afe1: Gain, cpu_temp: f32) -> Telemetry {
+    pub fn finalize(
+        self,
+        afe0: Gain,
+        afe1: Gain,
+        cpu_temp: f32,
+        batch_period: f32,
+        status: SystemStatus,
+    ) -> Telemetry {
         let in0_volts = Into::<f32>::into(self.adcs[0]) / afe0.as_multiplier();
         let in1_volts = Into::<f32>::into(self.adcs[1]) / afe1.as_multiplier();
 
+        let adc_statistics = [
+            self.adc_statistics[0]
+                .finalize(AdcCode::VOLT_PER_LSB / afe0.as_multiplier()),
+            self.adc_statistics[1]
+                .finalize(AdcCode::VOLT_PER_LSB / afe1.as_multiplier()),
+        ];
+
+        let dac_statistics = [
+            self.dac_statistics[0].finalize(DacCode::VOLT_PER_LSB),
+            self.dac_statistics[1].finalize(DacCode::VOLT_PER_LSB),
+        ];
+
         Telemetry {
             cpu_temp,
             adcs: [in0_volts, in1_volts],
             dacs: [self.dacs[0].into(), self.dacs[1].into()],
             digital_inputs: self.digital_inputs,
+            adc_statistics,
+            dac_statistics,
+            cpu_load: self.cpu_load.finalize(batch_period),
+            stream: status.stream,
+            time: status.time,
+            broker: status.broker,
+            watchdog_reset: status.watchdog_reset,
         }
     }
 }
 
+impl Telemetry {
+    /// The number of serialized fields.
+    pub const FIELDS: usize = 11;
+
+    /// Serialize the telemetry fields into a structure.
+    ///
+    /// # Note
+    /// This allows application telemetry to embed the device telemetry and report its fields
+    /// alongside the application-specific fields in a single flat structure.
+    ///
+    /// # Args
+    /// * `state` - The serializer state of the structure to serialize the fields into.
+    pub fn serialize_fields<S: SerializeStruct>(
+        &self,
+        state: &mut S,
+    ) -> Result<(), S::Error> {
+        state.serialize_field("adcs", &self.adcs)?;
+        state.serialize_field("dacs", &self.dacs)?;
+        state.serialize_field("digital_inputs", &self.digital_inputs)?;
+        state.serialize_field("cpu_temp", &self.cpu_temp)?;
+        state.serialize_field("adc_statistics", &self.adc_statistics)?;
+        state.serialize_field("dac_statistics", &self.dac_statistics)?;
+        state.serialize_field("cpu_load", &self.cpu_load)?;
+        state.serialize_field("stream", &self.stream)?;
+        state.serialize_field("time", &self.time)?;
+        state.serialize_field("broker", &self.broker)?;
+        state.serialize_field("watchdog_reset", &self.watchdog_reset)
+    }
+}
+
+impl Serialize for Telemetry {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut state =
+            serializer.serialize_struct("Telemetry", Self::FIELDS)?;
+        self.serialize_fields(&mut state)?;
+        state.end()
+    }
+}
+
 impl<T: Serialize> TelemetryClient<T> {
     /// Construct a new telemetry client.
     ///
     /// # Args
-    /// * `stack` - A reference to the (shared) underlying network stack.
+    /// * `stack` - A reference to the (shared) underlying network stack directing connections to
+    ///   the broker.
     /// * `clock` - A `SystemTimer` implementing `Clock`.
     /// * `client_id` - The MQTT client ID of the telemetry client.
     /// * `prefix` - The device prefix to use for MQTT telemetry reporting.
-    /// * `broker` - The IP address of the MQTT broker to use.
     ///
     /// # Returns
     /// A new telemetry client.
     pub fn new(
-        stack: NetworkReference,
+        stack: BrokerStack,
         clock: SystemTimer,
         client_id: &str,
         prefix: &str,
-        broker: IpAddr,
     ) -> Self {
-        let mqtt =
-            minimq::Minimq::new(broker, client_id, stack, clock).unwrap();
+        let mut mqtt =
+            minimq::Minimq::new(broker::unresolved(), client_id, stack, clock)
+                .unwrap();
 
         let mut telemetry_topic: String<128> = String::from(prefix);
         telemetry_topic.push_str("/telemetry").unwrap();
 
+        let mut event_topic: String<128> = String::from(prefix);
+        event_topic.push_str("/event").unwrap();
+
+        let mut status_topic: String<128> = String::from(prefix);
+        status_topic.push_str("/status").unwrap();
+
+        let mut metadata_topic: String<128> = String::from(prefix);
+        metadata_topic.push_str("/meta").unwrap();
+
+        let mut crash_topic: String<128> = String::from(prefix);
+        crash_topic.push_str("/crash").unwrap();
+
+        let mut command_topic: String<128> = String::from(prefix);
+        command_topic.push_str("/command/").unwrap();
+
+        // Note(unwrap): The will is set before any connection attempt and fits into the buffer.
+        mqtt.client
+            .set_will(
+                &status_topic,
+                b"offline",
+                QoS::AtMostOnce,
+                Retain::Retained,
+                &[],
+            )
+            .unwrap();
+
         Self {
             mqtt,
             telemetry_topic,
+            event_topic,
+            status_topic,
+            metadata_topic,
+            metadata: Vec::new(),
+            crash_topic,
+            crash_report: None,
+            announced: false,
+            command_topic,
+            commands: Deque::new(),
+            subscribed: false,
             _telemetry: core::marker::PhantomData::default(),
         }
     }
@@ -135,7 +471,7 @@ impl<T: Serialize> TelemetryClient<T> {
     /// # Args
     /// * `telemetry` - The telemetry to report
     pub fn publish(&mut self, telemetry: &T) {
-        let telemetry: Vec<u8, 512> =
+        let telemetry: Vec<u8, TELEMETRY_SIZE> =
             serde_json_core::to_vec(telemetry).unwrap();
         self.mqtt
             .client
@@ -149,6 +485,105 @@ impl<T: Serialize> TelemetryClient<T> {
             .ok();
     }
 
+    /// Publish an event over MQTT
+    ///
+    /// # Note
+    /// Events are published to the `<prefix>/event` topic in a "best-effort" fashion. Failure to
+    /// transmit an event will cause it to be silently dropped.
+    ///
+    /// # Args
+    /// * `event` - The event to report
+    pub fn publish_event(&mut self, event: &impl Serialize) {
+        let event: Vec<u8, 256> = serde_json_core::to_vec(event).unwrap();
+        self.mqtt
+            .client
+            .publish(
+                &self.event_topic,
+                &event,
+                QoS::AtMostOnce,
+                Retain::NotRetained,
+                &[],
+            )
+            .ok();
+    }
+
+    /// Set the device metadata.
+    ///
+    /// # Note
+    /// The metadata is published retained upon every connection and immediately if connected.
+    ///
+    /// # Args
+    /// * `metadata` - The device metadata.
+    pub fn set_metadata(&mut self, metadata: &Metadata) {
+        // Note(unwrap): The metadata is bounded in size.
+        self.metadata = serde_json_core::to_vec(metadata).unwrap();
+        self.announced = false;
+    }
+
+    /// Set the crash report.
+    ///
+    /// # Note
+    /// The report is published retained upon every connection and immediately if connected.
+    ///
+    /// # Args
+    /// * `report` - The report of the last crash or `None` to remove the retained report.
+    pub fn set_crash_report(&mut self, report: Option<&CrashReport>) {
+        let serialized = match report {
+            // The message is omitted if the report does not fit otherwise.
+            Some(report) => {
+                serde_json_core::to_vec(report).unwrap_or_else(|_| {
+                    let report = CrashReport {
+                        message: None,
+                        ..report.clone()
+                    };
+                    // Note(unwrap): The report without message is bounded in size.
+                    serde_json_core::to_vec(&report).unwrap()
+                })
+            }
+            // An empty retained message removes the retained report.
+            None => Vec::new(),
+        };
+
+        self.crash_report.replace(serialized);
+        self.announced = false;
+    }
+
+    // Publish the birth message, the metadata and the crash report.
+    fn announce(&mut self) -> bool {
+        let status = self.mqtt.client.publish(
+            &self.status_topic,
+            b"online",
+            QoS::AtMostOnce,
+            Retain::Retained,
+            &[],
+        );
+
+        let metadata = if self.metadata.is_empty() {
+            Ok(())
+        } else {
+            self.mqtt.client.publish(
+                &self.metadata_topic,
+                &self.metadata,
+                QoS::AtMostOnce,
+                Retain::Retained,
+                &[],
+            )
+        };
+
+        let crash = match &self.crash_report {
+            Some(report) => self.mqtt.client.publish(
+                &self.crash_topic,
+                report,
+                QoS::AtMostOnce,
+                Retain::Retained,
+                &[],
+            ),
+            None => Ok(()),
+        };
+
+        status.is_ok() && metadata.is_ok() && crash.is_ok()
+    }
+
     /// Update the telemetry client
     ///
     /// # Note
@@ -156,7 +591,33 @@ impl<T: Serialize> TelemetryClient<T> {
     /// and outgoing messages. Without this, the client will never connect to the broker. This
     /// should be called regularly.
     pub fn update(&mut self) {
-        match self.mqtt.poll(|_client, _topic, _message, _properties| {}) {
+        let command_topic = &self.command_topic;
+        let commands = &mut self.commands;
+        match self.mqtt.poll(|client, topic, message, _properties| {
+            let name = match topic.strip_prefix(command_topic.as_str()) {
+                Some(name) => name,
+                None => return,
+            };
+
+            // Empty messages remove retained commands and are not commands themselves.
+            if message.is_empty() {
+                return;
+            }
+
+            // Remove the command from the broker in case it was published retained.
+            client
+                .publish(topic, &[], QoS::AtMostOnce, Retain::Retained, &[])
+                .ok();
+
+            match Command::new(name, message) {
+                Some(command) => {
+                    if commands.push_back(command).is_err() {
+                        log::warn!("Command {} dropped", name);
+                    }
+                }
+                None => log::warn!("Invalid command {}", name),
+            }
+        }) {
             Err(minimq::Error::Network(
                 smoltcp_nal::NetworkError::NoIpAddress,
             )) => {}
@@ -164,5 +625,32 @@ impl<T: Serialize> TelemetryClient<T> {
             Err(error) => log::info!("Unexpected error: {:?}", error),
             _ => {}
         }
+
+        // Announce the device and subscribe to commands upon every connection. Failed
+        // announcements and subscriptions are retried.
+        if !self.mqtt.client.is_connected() {
+            self.announced = false;
+            self.subscribed = false;
+        } else {
+            if !self.announced {
+                self.announced = self.announce();
+            }
+
+            if !self.subscribed {
+                let mut topic = self.command_topic.clone();
+                // Note(unwrap): The prefix is short enough for the wildcard.
+                topic.push_str("#").unwrap();
+                self.subscribed =
+                    self.mqtt.client.subscribe(&topic, &[]).is_ok();
+            }
+        }
+    }
+
+    /// Take the oldest received command.
+    ///
+    /// # Returns
+    /// The command or `None` if no command is pending.
+    pub fn take_command(&mut self) -> Option<Command> {
+        self.commands.pop_front()
     }
 }